@@ -8,7 +8,10 @@ use serde_json;
 use sha2::{Digest, Sha256};
 use std::borrow::Cow;
 use std::cell::RefCell;
-use std::collections::HashMap;
+use unicode_normalization::UnicodeNormalization;
+use std::collections::{HashMap, HashSet};
+use std::ops::Bound::{Excluded, Unbounded};
+use std::time::Duration;
 use hmac::{Hmac, Mac};
 use base64::{Engine as _, engine::general_purpose};
 use hex;
@@ -18,12 +21,64 @@ type IdStore = StableBTreeMap<u64, u64, Memory>;
 type MessageStore = StableBTreeMap<u64, StorableMessage, Memory>;
 type ConversationStore = StableBTreeMap<String, StorableConversation, Memory>;
 type UserKeyStore = StableBTreeMap<Principal, StorableUserKey, Memory>;
-type _WebRTCSignalStore = StableBTreeMap<String, StorableWebRTCSignal, Memory>;
 type _SessionTokenStore = StableBTreeMap<String, StorableSessionToken, Memory>;
 type _KeyExchangeStore = StableBTreeMap<String, StorableKeyExchange, Memory>;
-type _RTCSessionStore = StableBTreeMap<String, StorableRTCSession, Memory>;
+type RTCSessionStore = StableBTreeMap<String, StorableRTCSession, Memory>;
 type RateLimitStore = StableBTreeMap<Principal, StorableRateLimit, Memory>;
 type NonceStore = StableBTreeMap<String, u64, Memory>;
+type RecordingConsentStore = StableBTreeMap<String, StorableRecordingConsent, Memory>;
+type RTCSessionIndexStore = StableBTreeMap<String, String, Memory>;
+type GroupRoomStore = StableBTreeMap<String, StorableGroupRoom, Memory>;
+type WebRTCSignalStore = StableBTreeMap<String, StorableWebRTCSignal, Memory>;
+type CallTelemetryStore = StableBTreeMap<String, StorableCallStatsSample, Memory>;
+type PresenceStore = StableBTreeMap<Principal, StorablePresence, Memory>;
+type ScreenShareStore = StableBTreeMap<String, StorableScreenShare, Memory>;
+type SyncEventStore = StableBTreeMap<String, StorableSyncEvent, Memory>;
+type SyncWatermarkStore = StableBTreeMap<Principal, u64, Memory>;
+type ConversationMessageIndex = StableBTreeMap<String, (), Memory>;
+type UserConversationIndex = StableBTreeMap<String, (), Memory>;
+type SettingsStore = StableBTreeMap<String, u64, Memory>;
+type MessageEditStore = StableBTreeMap<String, StorableMessageEdit, Memory>;
+type ReactionStore = StableBTreeMap<String, u64, Memory>;
+type ThreadSummaryStore = StableBTreeMap<u64, StorableThreadSummary, Memory>;
+type ThreadReplyIndex = StableBTreeMap<String, (), Memory>;
+type ReadWatermarkStore = StableBTreeMap<String, StorableReadWatermark, Memory>;
+type ConversationSettingsStore = StableBTreeMap<String, StorableConversationSettings, Memory>;
+type UnreadCountStore = StableBTreeMap<String, StorableUnreadCount, Memory>;
+type UserPreferencesStore = StableBTreeMap<Principal, StorableUserPreferences, Memory>;
+type ParticipantRoleStore = StableBTreeMap<String, StorableParticipantRole, Memory>;
+type KeyParticipantsStore = StableBTreeMap<String, StorableKeyParticipants, Memory>;
+type DirectConversationIndex = StableBTreeMap<String, String, Memory>;
+type IdempotencyStore = StableBTreeMap<String, String, Memory>;
+type InviteStore = StableBTreeMap<String, StorableConversationInvite, Memory>;
+type InviteIndex = StableBTreeMap<String, (), Memory>;
+type JoinRequestStore = StableBTreeMap<String, StorableJoinRequest, Memory>;
+type BlockStore = StableBTreeMap<String, u64, Memory>;
+type SuppressedMessageIndex = StableBTreeMap<String, (), Memory>;
+type MuteStore = StableBTreeMap<String, u64, Memory>;
+type ModeratorStore = StableBTreeMap<Principal, u64, Memory>;
+type ReportStore = StableBTreeMap<u64, StorableMessageReport, Memory>;
+type OpenReportIndex = StableBTreeMap<u64, (), Memory>;
+type ModerationAuditStore = StableBTreeMap<u64, StorableModerationAuditEntry, Memory>;
+type AccountStandingStore = StableBTreeMap<Principal, StorableAccountStanding, Memory>;
+type CrisisLexiconStore = StableBTreeMap<String, StorableCrisisTerm, Memory>;
+type EscalationStore = StableBTreeMap<u64, StorableCrisisEscalation, Memory>;
+type OpenEscalationIndex = StableBTreeMap<u64, (), Memory>;
+type CrisisFlagStore = StableBTreeMap<u64, u64, Memory>;
+type ClinicianStore = StableBTreeMap<Principal, u64, Memory>;
+type SanitizationPolicyStore = StableBTreeMap<String, StorableSanitizationPolicy, Memory>;
+type ScheduledMessageStore = StableBTreeMap<u64, StorableScheduledMessage, Memory>;
+type ScheduleQueue = StableBTreeMap<String, (), Memory>;
+type ScheduledBySenderIndex = StableBTreeMap<String, (), Memory>;
+type RetentionStore = StableBTreeMap<String, StorableRetention, Memory>;
+type MessageExpiryStore = StableBTreeMap<u64, u64, Memory>;
+type PurgeQueue = StableBTreeMap<String, String, Memory>;
+type SignalExpiryIndex = StableBTreeMap<String, (), Memory>;
+type BackfillCursorStore = StableBTreeMap<String, String, Memory>;
+type RetentionReapplyQueue = StableBTreeMap<String, (u64, u64), Memory>;
+type SuppressedByMessageIndex = StableBTreeMap<String, (), Memory>;
+type ReporterIndex = StableBTreeMap<String, u64, Memory>;
+type HiddenConversationIndex = StableBTreeMap<String, (), Memory>;
 
 // === ENCRYPTION STRUCTURES ===
 
@@ -83,9 +138,124 @@ impl Storable for StorableRateLimit {
 
 // Phase 2: Security constants
 const MAX_TEXT_LENGTH: usize = 10000;
+const MAX_CAPTION_LENGTH: u64 = 2000;
 
 const NONCE_EXPIRY_MS: u64 = 300000; // 5 minutes
 
+// Unanswered calls are reported as missed after this long (IC time is in nanoseconds)
+const RTC_RING_TIMEOUT_NS: u64 = 60 * 1_000_000_000; // 1 minute
+const MAX_CALL_HISTORY_PAGE: u64 = 50;
+
+// Group therapy rooms
+const MAX_GROUP_ROOM_PARTICIPANTS: usize = 12;
+
+// Signaling queue
+const SIGNAL_TTL_NS: u64 = 60 * 1_000_000_000; // 1 minute
+const MAX_SIGNAL_PAYLOAD_LENGTH: usize = 3072;
+const MAX_SIGNAL_EXPIRY_SWEEP: usize = 100; // expired signals dropped per new signal
+
+// Call quality telemetry: samples kept per session, and how many recent ones drive recommendations
+const TELEMETRY_BUFFER_SIZE: usize = 120;
+const TELEMETRY_RECENT_SAMPLES: usize = 10;
+
+// Presence: a user is online while heartbeats keep arriving, away once they go idle
+// or stop being active for a while, and offline when heartbeats stop
+const PRESENCE_AWAY_AFTER_NS: u64 = 2 * 60 * 1_000_000_000; // 2 minutes
+const PRESENCE_OFFLINE_AFTER_NS: u64 = 90 * 1_000_000_000; // 90 seconds without heartbeat
+const MAX_PRESENCE_BATCH: usize = 100;
+const TYPING_EXPIRY_NS: u64 = 6 * 1_000_000_000; // clients refresh while the user keeps typing
+
+// Incremental sync: events are kept for 30 days, older cursors must resync from scratch
+const SYNC_EVENT_RETENTION_NS: u64 = 30 * 24 * 60 * 60 * 1_000_000_000;
+const MAX_SYNC_BATCH: u64 = 500;
+
+const MAX_CONVERSATIONS_PAGE: u64 = 200;
+
+// Message editing: senders may edit for this long unless a controller configures otherwise
+const SETTING_EDIT_WINDOW_NS: &str = "edit_window_ns";
+const DEFAULT_EDIT_WINDOW_NS: u64 = 15 * 60 * 1_000_000_000; // 15 minutes
+
+// Reactions
+const MAX_EMOJI_LENGTH: usize = 32; // bytes; fits multi-codepoint ZWJ sequences
+const MAX_REACTIONS_PER_USER_PER_MESSAGE: usize = 20;
+
+const MAX_THREAD_PAGE: u64 = 100;
+const SETTING_THREAD_INDEX_BUILT: &str = "thread_index_built";
+const SETTING_UNREAD_COUNTS_BUILT: &str = "unread_counts_built";
+const SETTING_PARTICIPANT_ROLES_BUILT: &str = "participant_roles_built";
+const SETTING_DIRECT_INDEX_BUILT: &str = "direct_index_built";
+const SETTING_MESSAGE_INDEX_BUILT: &str = "message_index_built";
+const SETTING_USER_CONVERSATION_INDEX_BUILT: &str = "user_conversation_index_built";
+const SETTING_SUPPRESSED_BY_MESSAGE_BUILT: &str = "suppressed_by_message_built";
+const SETTING_REPORTER_INDEX_BUILT: &str = "reporter_index_built";
+const BACKFILL_BATCH_SIZE: usize = 200; // records per backfill timer tick
+const MAX_IDEMPOTENCY_KEY_LENGTH: usize = 64;
+
+// Moderation
+const MAX_REPORT_DETAILS_LENGTH: usize = 1000;
+const MAX_MODERATION_NOTE_LENGTH: usize = 1000;
+const MAX_MODERATION_PAGE: u64 = 100;
+
+// Retention
+const NANOS_PER_DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+const MAX_RETENTION_DAYS: u32 = 3650;
+const MAX_DISAPPEAR_AFTER_NS: u64 = 30 * NANOS_PER_DAY;
+const RETENTION_PURGE_INTERVAL_NS: u64 = 60 * 1_000_000_000; // 1 minute
+const MAX_PURGE_BATCH: usize = 500;
+
+// Scheduled messages
+const MAX_SCHEDULE_AHEAD_NS: u64 = 365 * 24 * 60 * 60 * 1_000_000_000;
+const MAX_SCHEDULED_PER_USER: usize = 100;
+
+// Crisis escalation
+const MAX_CRISIS_TERMS: u64 = 1000;
+const MAX_CRISIS_PHRASE_LENGTH: usize = 100;
+const MAX_MATCHED_TERMS: usize = 10;
+const MAX_ESCALATION_PAGE: u64 = 100;
+const SETTING_CRISIS_LEXICON_SEEDED: &str = "crisis_lexicon_seeded";
+const CRISIS_RESOURCES_MESSAGE: &str = "If you or someone here is in danger, please contact local emergency services now. \
+You can reach a crisis line any time: call or text 988 (US), call 116 123 (UK and Ireland), \
+or find a local helpline at https://findahelpline.com. A MentalVerse clinician has been notified.";
+
+// Seeded on first install; controllers maintain the list afterwards
+const DEFAULT_CRISIS_LEXICON: &[(&str, &str, CrisisSeverity)] = &[
+    ("en", "kill myself", CrisisSeverity::Urgent),
+    ("en", "end my life", CrisisSeverity::Urgent),
+    ("en", "want to die", CrisisSeverity::Urgent),
+    ("en", "better off dead", CrisisSeverity::Urgent),
+    ("en", "no reason to live", CrisisSeverity::Urgent),
+    ("en", "suicidal", CrisisSeverity::Urgent),
+    ("en", "suicide", CrisisSeverity::Concern),
+    ("en", "hurt myself", CrisisSeverity::Concern),
+    ("en", "self harm", CrisisSeverity::Concern),
+    ("en", "cut myself", CrisisSeverity::Concern),
+    ("en", "overdose", CrisisSeverity::Concern),
+    ("es", "quiero morir", CrisisSeverity::Urgent),
+    ("es", "quitarme la vida", CrisisSeverity::Urgent),
+    ("es", "matarme", CrisisSeverity::Urgent),
+    ("es", "suicidarme", CrisisSeverity::Urgent),
+    ("es", "hacerme daño", CrisisSeverity::Concern),
+    ("fr", "me suicider", CrisisSeverity::Urgent),
+    ("fr", "me tuer", CrisisSeverity::Urgent),
+    ("fr", "envie de mourir", CrisisSeverity::Urgent),
+    ("fr", "me faire du mal", CrisisSeverity::Concern),
+    ("pt", "quero morrer", CrisisSeverity::Urgent),
+    ("pt", "me matar", CrisisSeverity::Urgent),
+    ("pt", "suicídio", CrisisSeverity::Concern),
+    ("de", "mich umbringen", CrisisSeverity::Urgent),
+    ("de", "nicht mehr leben", CrisisSeverity::Urgent),
+    ("de", "selbstmord", CrisisSeverity::Concern),
+];
+
+// Rich text
+const MAX_RICH_TEXT_DEPTH: usize = 8;
+const MAX_RICH_TEXT_NODES: usize = 1000;
+const MAX_RICH_TEXT_LINK_LENGTH: usize = 2048;
+const MAX_RICH_TEXT_HEADING_LEVEL: u8 = 3;
+
+// Keeps a conversation record within StorableConversation's 2KB bound
+const MAX_CONVERSATION_PARTICIPANTS: usize = 32;
+
 #[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
 pub struct EncryptedData {
     pub encrypted_content: String, // Base64 encoded encrypted data
@@ -125,6 +295,145 @@ pub struct Message {
     pub is_deleted: bool,
     pub reply_to: Option<u64>,
     pub attachments: Vec<Attachment>,
+    pub edited_at: Option<u64>,
+    pub reactions: Vec<ReactionSummary>, // filled in by history queries, not stored with the message
+    pub thread: Option<ThreadSummary>, // set on thread roots by history queries
+    pub plain_text: Option<String>, // rich text only: derived text for previews and search
+}
+
+// Rich text messages carry a RichTextDocument as JSON. Only these node types parse, so
+// clients never receive raw HTML or anything else they would have to escape themselves.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum RichTextNode {
+    // Blocks
+    Paragraph { children: Vec<RichTextNode> },
+    Heading { level: u8, children: Vec<RichTextNode> },
+    BulletList { children: Vec<RichTextNode> },
+    OrderedList {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        start: Option<u32>,
+        children: Vec<RichTextNode>,
+    },
+    ListItem { children: Vec<RichTextNode> },
+    Blockquote { children: Vec<RichTextNode> },
+    CodeBlock { text: String },
+    // Inline
+    Text { text: String },
+    Bold { children: Vec<RichTextNode> },
+    Italic { children: Vec<RichTextNode> },
+    Strikethrough { children: Vec<RichTextNode> },
+    Code { text: String },
+    Link { href: String, children: Vec<RichTextNode> },
+    LineBreak,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct RichTextDocument {
+    pub blocks: Vec<RichTextNode>,
+}
+
+// Aggregated reactions of one emoji on a message
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct ReactionSummary {
+    pub emoji: String,
+    pub count: u64,
+    pub reacted_by_caller: bool,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct Reaction {
+    pub message_id: u64,
+    pub emoji: String,
+    pub user_id: Principal,
+    pub created_at: u64,
+}
+
+// Replies under a root message; replies to replies join the root's thread
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct ThreadSummary {
+    pub root_message_id: u64,
+    pub reply_count: u64,
+    pub last_reply_id: Option<u64>,
+    pub last_reply_at: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct ThreadPage {
+    pub root: Message,
+    pub replies: Vec<Message>,
+    pub summary: ThreadSummary,
+    pub next_cursor: Option<u64>, // pass as cursor to get the following replies
+}
+
+// How far a participant has received and read a conversation, as message ID watermarks
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct ReadWatermark {
+    pub user_id: Principal,
+    pub conversation_id: String,
+    pub delivered_up_to: u64,
+    pub read_up_to: Option<u64>, // None when the conversation hides read receipts
+    pub updated_at: u64,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct MessageReceipts {
+    pub message_id: u64,
+    pub delivered_to: Vec<Principal>,
+    pub read_by: Vec<Principal>,
+    pub read_receipts_enabled: bool,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct ConversationUnread {
+    pub conversation_id: String,
+    pub unread_count: u64,
+    pub first_unread_message_id: Option<u64>, // for jump-to-unread
+    pub muted_until: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct UnreadSummary {
+    pub conversations: Vec<ConversationUnread>,
+    pub total_unread: u64, // muted conversations are not included
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct BlockedUser {
+    pub user_id: Principal,
+    pub blocked_at: u64,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct ConversationMute {
+    pub conversation_id: String,
+    pub muted_until: u64, // u64::MAX until unmuted
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct ConversationSettings {
+    pub conversation_id: String,
+    pub read_receipts_enabled: bool,
+    pub end_to_end_encrypted: bool, // clients encrypt content themselves; the canister can't read it
+}
+
+// Append-only record of a message edit. Each entry keeps the ciphertext that was
+// replaced and an HMAC chained over the previous entry, so history can't be rewritten.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct MessageEdit {
+    pub message_id: u64,
+    pub editor: Principal,
+    pub edited_at: u64,
+    pub previous_content: String,
+    pub signature: String,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct MessageEditHistory {
+    pub message_id: u64,
+    pub edits: Vec<MessageEdit>, // previous_content decrypted for the caller
+    pub chain_valid: bool,
 }
 
 #[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
@@ -135,6 +444,15 @@ pub enum MessageType {
     Audio,
     Video,
     System,
+    RichText, // content is a RichTextDocument as JSON
+}
+
+// How message content of one type is cleaned and validated before encryption
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct SanitizationPolicy {
+    pub message_type: MessageType,
+    pub max_length: u64, // UTF-8 bytes after normalization
+    pub allow_newlines: bool,
 }
 
 #[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
@@ -165,6 +483,63 @@ pub enum ConversationType {
     SessionChat, // For therapy sessions
 }
 
+// Highest first: owners manage admins, admins manage members, read-only members can't post
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub enum ParticipantRole {
+    Owner,
+    Admin,
+    Member,
+    ReadOnly,
+}
+
+impl ParticipantRole {
+    fn rank(&self) -> u8 {
+        match self {
+            ParticipantRole::Owner => 3,
+            ParticipantRole::Admin => 2,
+            ParticipantRole::Member => 1,
+            ParticipantRole::ReadOnly => 0,
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct ConversationMember {
+    pub user_id: Principal,
+    pub role: ParticipantRole,
+    pub joined_at: u64,
+}
+
+// Shareable token that lets its holder join a group conversation
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct ConversationInvite {
+    pub token: String,
+    pub conversation_id: String,
+    pub created_by: Principal,
+    pub role: ParticipantRole,
+    pub created_at: u64,
+    pub expires_at: Option<u64>,
+    pub max_uses: Option<u64>,
+    pub uses: u64,
+    pub requires_approval: bool, // redeeming queues a join request for admins
+    pub revoked: bool,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct JoinRequest {
+    pub conversation_id: String,
+    pub user_id: Principal,
+    pub invite_token: String,
+    pub role: ParticipantRole,
+    pub requested_at: u64,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub enum InviteRedemption {
+    Joined(Conversation),
+    PendingApproval(JoinRequest),
+}
+
 #[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
 pub struct ConversationMetadata {
     pub title: Option<String>,
@@ -173,6 +548,13 @@ pub struct ConversationMetadata {
     pub encryption_key_id: String,
 }
 
+// Position in a user's conversation list: the updated_at and ID of the last conversation seen
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct ConversationCursor {
+    pub updated_at: u64,
+    pub conversation_id: String,
+}
+
 #[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
 pub struct UserKey {
     pub user_id: Principal,
@@ -203,6 +585,191 @@ pub struct ConversationResult {
     pub error: Option<String>,
 }
 
+// === SYNC STRUCTURES ===
+
+// Entry of a user's change log; seq increases monotonically and doubles as the sync cursor
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct SyncEvent {
+    pub seq: u64,
+    pub event_type: SyncEventType,
+    pub conversation_id: Option<String>,
+    pub message_id: Option<u64>,
+    pub session_id: Option<String>,
+    pub timestamp: u64,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub enum SyncEventType {
+    MessageCreated,
+    MessageRead,
+    MessageDeleted,
+    MessageEdited,
+    ReactionChanged,
+    ConversationUpdated,
+    SignalReceived,
+    MessageDelivered,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct SyncResponse {
+    pub events: Vec<SyncEvent>,
+    pub next_seq: u64,
+    pub has_more: bool,
+    pub reset_required: bool, // events after the cursor were pruned; refetch everything
+    pub typing: Vec<TypingIndicator>, // who is typing right now in the caller's conversations
+}
+
+// === MODERATION STRUCTURES ===
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub enum ReportReason {
+    Harassment,
+    HateSpeech,
+    Spam,
+    SelfHarm,
+    Other,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub enum ReportStatus {
+    Open,
+    Actioned,
+    Dismissed,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub enum ModerationAction {
+    Dismiss,
+    Warn,
+    RemoveMessage,
+    Suspend { duration_ns: u64 },
+    LiftSuspension,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct MessageReport {
+    pub id: u64,
+    pub message_id: u64,
+    pub conversation_id: String,
+    pub reporter: Principal,
+    pub reported_user: Principal,
+    pub reason: ReportReason,
+    pub details: Option<String>,
+    pub escrowed_content: Option<String>, // only with the reporter's consent; decrypted for moderators
+    pub status: ReportStatus,
+    pub created_at: u64,
+    pub resolved_at: Option<u64>,
+    pub resolved_by: Option<Principal>,
+}
+
+// One moderation decision; entries are never modified or removed
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct ModerationAuditEntry {
+    pub id: u64,
+    pub moderator: Principal,
+    pub action: ModerationAction,
+    pub target_user: Principal,
+    pub report_id: Option<u64>,
+    pub message_id: Option<u64>,
+    pub note: Option<String>,
+    pub created_at: u64,
+}
+
+// What a user can see about moderation of their own account
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct AccountStanding {
+    pub warning_count: u64,
+    pub last_warning_at: Option<u64>,
+    pub suspended_until: Option<u64>,
+}
+
+// === RETENTION STRUCTURES ===
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub enum RetentionPolicy {
+    KeepForever,
+    ExpireAfterDays { days: u32 },
+    DisappearAfterRead { after_ns: u64 }, // counted from when every participant has read the message
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct ConversationRetention {
+    pub conversation_id: String,
+    pub policy: RetentionPolicy,
+    pub updated_by: Option<Principal>,
+    pub updated_at: Option<u64>,
+}
+
+// === SCHEDULED MESSAGE STRUCTURES ===
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub enum ScheduledMessageStatus {
+    Pending,
+    Failed, // delivery was attempted and rejected; see failure_reason
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct ScheduledMessage {
+    pub id: u64,
+    pub conversation_id: String,
+    pub sender_id: Principal,
+    pub content: String, // encrypted at rest; decrypted for the sender when listed
+    pub message_type: MessageType,
+    pub deliver_at: u64,
+    pub created_at: u64,
+    pub status: ScheduledMessageStatus,
+    pub failure_reason: Option<String>,
+}
+
+// === CRISIS ESCALATION STRUCTURES ===
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum CrisisSeverity {
+    Concern,
+    Urgent,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct CrisisTerm {
+    pub language: String, // BCP 47 primary tag, e.g. "en", "es"
+    pub phrase: String,   // stored normalized: lowercase words separated by single spaces
+    pub severity: CrisisSeverity,
+    pub added_at: u64,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub enum EscalationSource {
+    LexiconMatch,
+    ClientReport,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub enum EscalationStatus {
+    Open,
+    Acknowledged,
+    Resolved,
+}
+
+// A message that needs a clinician's attention. Content is never copied here;
+// matched_terms is all the canister shares about what was said.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct CrisisEscalation {
+    pub id: u64,
+    pub conversation_id: String,
+    pub message_id: u64,
+    pub user_at_risk: Principal, // sender of the flagged message
+    pub reported_by: Option<Principal>, // None for lexicon matches
+    pub source: EscalationSource,
+    pub severity: CrisisSeverity,
+    pub matched_terms: Vec<String>,
+    pub status: EscalationStatus,
+    pub created_at: u64,
+    pub acknowledged_by: Option<Principal>,
+    pub acknowledged_at: Option<u64>,
+    pub resolved_at: Option<u64>,
+    pub resolution_note: Option<String>,
+}
+
 // === WEBRTC AND REAL-TIME COMMUNICATION STRUCTURES ===
 
 #[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
@@ -237,7 +804,7 @@ pub struct SessionToken {
     pub is_active: bool,
 }
 
-#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq)]
 pub enum SessionPermission {
     SendMessage,
     ReceiveMessage,
@@ -271,12 +838,16 @@ pub enum KeyExchangeStatus {
 #[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
 pub struct RTCSession {
     pub session_id: String,
+    pub conversation_id: String,
+    pub initiator_id: Principal,
     pub participants: Vec<Principal>,
     pub session_type: RTCSessionType,
     pub status: RTCSessionStatus,
     pub created_at: u64,
     pub started_at: Option<u64>,
     pub ended_at: Option<u64>,
+    pub declined_by: Vec<Principal>,
+    pub parent_session_id: Option<String>, // set for sub-sessions such as screen shares
     pub metadata: RTCSessionMetadata,
 }
 
@@ -294,6 +865,8 @@ pub enum RTCSessionStatus {
     Active,
     Ended,
     Failed,
+    Missed,
+    Declined,
 }
 
 #[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
@@ -311,6 +884,179 @@ pub struct QualitySettings {
     pub video_bitrate: u32,
 }
 
+// Summary of a past or ongoing call, as listed in a conversation's call history
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct CallSummary {
+    pub session_id: String,
+    pub session_type: RTCSessionType,
+    pub status: RTCSessionStatus,
+    pub initiator_id: Principal,
+    pub participants: Vec<Principal>,
+    pub created_at: u64,
+    pub started_at: Option<u64>,
+    pub ended_at: Option<u64>,
+    pub duration: Option<u64>, // nanoseconds between start and end
+    pub quality_settings: QualitySettings,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct CallHistoryPage {
+    pub calls: Vec<CallSummary>,
+    pub next_cursor: Option<u64>,
+}
+
+// WebRTC stats periodically reported by a client during a call
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct CallStatsReport {
+    pub rtt_ms: u32,
+    pub packet_loss_percent: f64,
+    pub jitter_ms: u32,
+    pub audio_bitrate: u32,
+    pub video_bitrate: u32,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct CallStatsSample {
+    pub session_id: String,
+    pub reporter: Principal,
+    pub timestamp: u64,
+    pub stats: CallStatsReport,
+}
+
+// Aggregated telemetry of a session for support staff
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct CallQualityStats {
+    pub session_id: String,
+    pub sample_count: u64,
+    pub first_sample_at: Option<u64>,
+    pub last_sample_at: Option<u64>,
+    pub avg_rtt_ms: f64,
+    pub max_rtt_ms: u32,
+    pub avg_packet_loss_percent: f64,
+    pub max_packet_loss_percent: f64,
+    pub avg_jitter_ms: f64,
+    pub max_jitter_ms: u32,
+    pub avg_video_bitrate: f64,
+    pub min_video_bitrate: u32,
+    pub recent_samples: Vec<CallStatsSample>,
+}
+
+// Screen share negotiated inside an active call. The share runs as its own
+// ScreenShare sub-session so renegotiation signals have a separate queue.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct ScreenShare {
+    pub share_session_id: String,
+    pub parent_session_id: String,
+    pub presenter: Principal,
+    pub status: ScreenShareStatus,
+    pub requested_at: u64,
+    pub decided_by: Option<Principal>,
+    pub decided_at: Option<u64>,
+    pub ended_at: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub enum ScreenShareStatus {
+    Requested,
+    Granted,
+    Denied,
+    Stopped,
+    Revoked,
+}
+
+// === PRESENCE STRUCTURES ===
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct Presence {
+    pub user_id: Principal,
+    pub last_heartbeat: u64,
+    pub last_active: u64,
+    pub manual_status: Option<AvailabilityStatus>,
+    pub manual_status_expires_at: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub enum PresenceState {
+    Online,
+    Away,
+    Offline,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub enum AvailabilityStatus {
+    Available,
+    InSession,
+    DoNotDisturb,
+}
+
+// Presence as seen by another user
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct UserPresence {
+    pub user_id: Principal,
+    pub state: PresenceState,
+    pub status: Option<AvailabilityStatus>,
+    pub status_expires_at: Option<u64>,
+    pub last_seen: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct ConversationPresence {
+    pub conversation_id: String,
+    pub participants: Vec<UserPresence>,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct TypingIndicator {
+    pub conversation_id: String,
+    pub user_id: Principal,
+    pub expires_at: u64,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct UserPreferences {
+    pub typing_indicators_enabled: bool,
+}
+
+// Group room mode for therapy session calls: a host runs the room and admits
+// people from the waiting room; signaling only reaches admitted members.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct GroupRoom {
+    pub session_id: String,
+    pub members: Vec<RoomMember>,
+    pub waiting_room: Vec<Principal>,
+    pub removed: Vec<Principal>,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct RoomMember {
+    pub principal: Principal,
+    pub role: RoomRole,
+    pub is_muted: bool,
+    pub joined_at: u64,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub enum RoomRole {
+    Host,
+    CoHost,
+    Attendee,
+}
+
+// Recording consent trail for RTC sessions (append-only, kept for audits)
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct RecordingConsent {
+    pub session_id: String,
+    pub participant: Principal,
+    pub action: ConsentAction,
+    pub timestamp: u64,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub enum ConsentAction {
+    Granted,
+    Withdrawn,
+}
+
 // === STORABLE IMPLEMENTATIONS ===
 
 #[derive(CandidType, Deserialize, Serialize, Clone)]
@@ -326,6 +1072,7 @@ struct StorableMessage {
     pub is_deleted: bool,
     pub reply_to: Option<u64>,
     pub attachments: Vec<Attachment>,
+    pub edited_at: Option<u64>,
 }
 
 impl From<Message> for StorableMessage {
@@ -342,6 +1089,7 @@ impl From<Message> for StorableMessage {
             is_deleted: msg.is_deleted,
             reply_to: msg.reply_to,
             attachments: msg.attachments,
+            edited_at: msg.edited_at,
         }
     }
 }
@@ -360,13 +1108,17 @@ impl From<StorableMessage> for Message {
             is_deleted: storable.is_deleted,
             reply_to: storable.reply_to,
             attachments: storable.attachments,
+            edited_at: storable.edited_at,
+            reactions: Vec::new(),
+            thread: None,
+            plain_text: None,
         }
     }
 }
 
 impl Storable for StorableMessage {
     const BOUND: Bound = Bound::Bounded {
-        max_size: 10240, // 10KB max per message
+        max_size: 32768, // 32KB: full-length ciphertext (~14KB) plus attachment entries
         is_fixed_size: false,
     };
 
@@ -672,12 +1424,16 @@ impl Storable for StorableKeyExchange {
 #[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
 pub struct StorableRTCSession {
     pub session_id: String,
+    pub conversation_id: String,
+    pub initiator_id: Principal,
     pub participants: Vec<Principal>,
     pub session_type: RTCSessionType,
     pub status: RTCSessionStatus,
     pub created_at: u64,
     pub started_at: Option<u64>,
     pub ended_at: Option<u64>,
+    pub declined_by: Vec<Principal>,
+    pub parent_session_id: Option<String>, // set for sub-sessions such as screen shares
     pub metadata: RTCSessionMetadata,
 }
 
@@ -686,12 +1442,16 @@ impl From<RTCSession> for StorableRTCSession {
     fn from(session: RTCSession) -> Self {
         Self {
             session_id: session.session_id,
+            conversation_id: session.conversation_id,
+            initiator_id: session.initiator_id,
             participants: session.participants,
             session_type: session.session_type,
             status: session.status,
             created_at: session.created_at,
             started_at: session.started_at,
             ended_at: session.ended_at,
+            declined_by: session.declined_by,
+            parent_session_id: session.parent_session_id,
             metadata: session.metadata,
         }
     }
@@ -702,12 +1462,16 @@ impl From<StorableRTCSession> for RTCSession {
     fn from(storable: StorableRTCSession) -> Self {
         Self {
             session_id: storable.session_id,
+            conversation_id: storable.conversation_id,
+            initiator_id: storable.initiator_id,
             participants: storable.participants,
             session_type: storable.session_type,
             status: storable.status,
             created_at: storable.created_at,
             started_at: storable.started_at,
             ended_at: storable.ended_at,
+            declined_by: storable.declined_by,
+            parent_session_id: storable.parent_session_id,
             metadata: storable.metadata,
         }
     }
@@ -733,1059 +1497,8332 @@ impl Storable for StorableRTCSession {
     }
 }
 
-// === GLOBAL STATE ===
-
-thread_local! {
-    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
-        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+struct StorableRecordingConsent {
+    pub session_id: String,
+    pub participant: Principal,
+    pub action: ConsentAction,
+    pub timestamp: u64,
+}
 
-    static ID_COUNTER: RefCell<IdStore> = RefCell::new(
-        IdStore::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(0)))
-        )
-    );
+impl From<RecordingConsent> for StorableRecordingConsent {
+    fn from(consent: RecordingConsent) -> Self {
+        StorableRecordingConsent {
+            session_id: consent.session_id,
+            participant: consent.participant,
+            action: consent.action,
+            timestamp: consent.timestamp,
+        }
+    }
+}
 
-    static MESSAGES: RefCell<MessageStore> = RefCell::new(
-        MessageStore::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1)))
-        )
-    );
+impl From<StorableRecordingConsent> for RecordingConsent {
+    fn from(storable: StorableRecordingConsent) -> Self {
+        RecordingConsent {
+            session_id: storable.session_id,
+            participant: storable.participant,
+            action: storable.action,
+            timestamp: storable.timestamp,
+        }
+    }
+}
 
-    static CONVERSATIONS: RefCell<ConversationStore> = RefCell::new(
-        ConversationStore::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(2)))
-        )
-    );
+impl Storable for StorableRecordingConsent {
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 512,
+        is_fixed_size: false,
+    };
 
-    static USER_KEYS: RefCell<UserKeyStore> = RefCell::new(
-        UserKeyStore::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(3)))
-        )
-    );
-    
-    // Phase 2: Security storage
-    static RATE_LIMITS: RefCell<RateLimitStore> = RefCell::new(
-        RateLimitStore::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(4)))
-        )
-    );
-    
-    static USED_NONCES: RefCell<NonceStore> = RefCell::new(
-        NonceStore::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(5)))
-        )
-    );
-}
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).unwrap())
+    }
 
-// === HELPER FUNCTIONS ===
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap()
+    }
+}
 
-fn generate_next_id() -> u64 {
-    ID_COUNTER.with(|counter| {
-        let mut counter = counter.borrow_mut();
-        let current_id = counter.get(&0).unwrap_or(0);
-        let next_id = current_id + 1;
-        counter.insert(0, next_id);
-        next_id
-    })
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+struct StorableGroupRoom {
+    pub session_id: String,
+    pub members: Vec<RoomMember>,
+    pub waiting_room: Vec<Principal>,
+    pub removed: Vec<Principal>,
 }
 
-fn generate_conversation_id(participants: &[Principal]) -> String {
-    let mut sorted_participants = participants.to_vec();
-    sorted_participants.sort_by_key(|p| p.to_text());
-    let participants_str = sorted_participants
-        .iter()
-        .map(|p| p.to_text())
-        .collect::<Vec<_>>()
-        .join("-");
-    
-    let mut hasher = Sha256::new();
-    hasher.update(participants_str.as_bytes());
-    let result = hasher.finalize();
-    format!("{:x}", result)[..16].to_string()
+impl From<GroupRoom> for StorableGroupRoom {
+    fn from(room: GroupRoom) -> Self {
+        StorableGroupRoom {
+            session_id: room.session_id,
+            members: room.members,
+            waiting_room: room.waiting_room,
+            removed: room.removed,
+        }
+    }
 }
 
-fn is_participant(conversation: &Conversation, user_id: &Principal) -> bool {
-    conversation.participants.contains(user_id)
+impl From<StorableGroupRoom> for GroupRoom {
+    fn from(storable: StorableGroupRoom) -> Self {
+        GroupRoom {
+            session_id: storable.session_id,
+            members: storable.members,
+            waiting_room: storable.waiting_room,
+            removed: storable.removed,
+        }
+    }
 }
 
-fn get_caller() -> Principal {
-    ic_cdk::caller()
+impl Storable for StorableGroupRoom {
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 8192, // 8KB covers a full room plus its waiting room
+        is_fixed_size: false,
+    };
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap()
+    }
 }
 
-fn get_time() -> u64 {
-    time()
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+struct StorableCallStatsSample {
+    pub session_id: String,
+    pub reporter: Principal,
+    pub timestamp: u64,
+    pub stats: CallStatsReport,
 }
 
-// Phase 2: Security validation functions
-fn check_rate_limit(principal: Principal, max_calls: u32, window_ms: u64) -> Result<(), String> {
-    let current_time = get_time();
-    
-    RATE_LIMITS.with(|rate_limits| {
-        let mut rate_limits = rate_limits.borrow_mut();
-        
-        match rate_limits.get(&principal) {
-            Some(rate_limit) => {
-                let rate_limit = RateLimit::from(rate_limit);
-                
-                // Check if we're still in the same time window
-                if current_time - rate_limit.window_start < window_ms {
-                    if rate_limit.call_count >= max_calls {
-                        return Err(format!("Rate limit exceeded: {} calls in {} ms", max_calls, window_ms));
-                    }
-                    
-                    // Increment call count
-                    let updated_rate_limit = RateLimit {
-                        principal,
-                        call_count: rate_limit.call_count + 1,
-                        window_start: rate_limit.window_start,
-                        window_duration: window_ms,
-                    };
-                    rate_limits.insert(principal, StorableRateLimit::from(updated_rate_limit));
-                } else {
-                    // New time window, reset counter
-                    let new_rate_limit = RateLimit {
-                        principal,
-                        call_count: 1,
-                        window_start: current_time,
-                        window_duration: window_ms,
-                    };
-                    rate_limits.insert(principal, StorableRateLimit::from(new_rate_limit));
-                }
-            }
-            None => {
-                // First call from this principal
-                let new_rate_limit = RateLimit {
-                    principal,
-                    call_count: 1,
-                    window_start: current_time,
-                    window_duration: window_ms,
-                };
-                rate_limits.insert(principal, StorableRateLimit::from(new_rate_limit));
-            }
+impl From<CallStatsSample> for StorableCallStatsSample {
+    fn from(sample: CallStatsSample) -> Self {
+        StorableCallStatsSample {
+            session_id: sample.session_id,
+            reporter: sample.reporter,
+            timestamp: sample.timestamp,
+            stats: sample.stats,
         }
-        
-        Ok(())
-    })
+    }
 }
 
-fn validate_nonce(nonce: &str, timestamp: u64) -> Result<(), String> {
-    if nonce.is_empty() {
-        return Err("Nonce cannot be empty".to_string());
+impl From<StorableCallStatsSample> for CallStatsSample {
+    fn from(storable: StorableCallStatsSample) -> Self {
+        CallStatsSample {
+            session_id: storable.session_id,
+            reporter: storable.reporter,
+            timestamp: storable.timestamp,
+            stats: storable.stats,
+        }
     }
-    
-    let current_time = get_time();
-    
-    // Check if timestamp is not too old or in the future
-    if timestamp > current_time + 60000 { // 1 minute future tolerance
-        return Err("Timestamp is too far in the future".to_string());
+}
+
+impl Storable for StorableCallStatsSample {
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 512,
+        is_fixed_size: false,
+    };
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).unwrap())
     }
-    
-    if current_time - timestamp > NONCE_EXPIRY_MS {
-        return Err("Nonce has expired".to_string());
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap()
     }
-    
-    USED_NONCES.with(|nonces| {
-        let mut nonces = nonces.borrow_mut();
-        
-        if nonces.get(&nonce.to_string()).is_some() {
-            return Err("Nonce has already been used".to_string());
-        }
-        
-        // Store the nonce with its timestamp
-        nonces.insert(nonce.to_string(), timestamp);
-        
-        // Clean up expired nonces (simple cleanup)
-        let expired_threshold = current_time - NONCE_EXPIRY_MS;
-        let expired_nonces: Vec<String> = nonces
-            .iter()
-            .filter_map(|(nonce_key, nonce_timestamp)| {
-                if nonce_timestamp < expired_threshold {
-                    Some(nonce_key)
-                } else {
-                    None
-                }
-            })
-            .collect();
-        
-        for expired_nonce in expired_nonces {
-            nonces.remove(&expired_nonce);
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+struct StorablePresence {
+    pub user_id: Principal,
+    pub last_heartbeat: u64,
+    pub last_active: u64,
+    pub manual_status: Option<AvailabilityStatus>,
+    pub manual_status_expires_at: Option<u64>,
+}
+
+impl From<Presence> for StorablePresence {
+    fn from(presence: Presence) -> Self {
+        StorablePresence {
+            user_id: presence.user_id,
+            last_heartbeat: presence.last_heartbeat,
+            last_active: presence.last_active,
+            manual_status: presence.manual_status,
+            manual_status_expires_at: presence.manual_status_expires_at,
         }
-        
-        Ok(())
-    })
+    }
 }
 
-fn validate_text_length(text: &str, max_length: usize, field_name: &str) -> Result<(), String> {
-    if text.len() > max_length {
-        return Err(format!("{} exceeds maximum length of {} characters", field_name, max_length));
+impl From<StorablePresence> for Presence {
+    fn from(storable: StorablePresence) -> Self {
+        Presence {
+            user_id: storable.user_id,
+            last_heartbeat: storable.last_heartbeat,
+            last_active: storable.last_active,
+            manual_status: storable.manual_status,
+            manual_status_expires_at: storable.manual_status_expires_at,
+        }
     }
-    Ok(())
 }
 
-fn validate_text_not_empty(text: &str, field_name: &str) -> Result<(), String> {
-    if text.trim().is_empty() {
-        return Err(format!("{} cannot be empty", field_name));
+impl Storable for StorablePresence {
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 256,
+        is_fixed_size: false,
+    };
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap()
     }
-    Ok(())
 }
 
-fn sanitize_text(text: &str) -> String {
-    // Remove potentially dangerous characters and normalize whitespace
-    let sanitized = text
-        .chars()
-        .filter(|c| c.is_alphanumeric() || c.is_whitespace() || ".,!?-_@#$%^&*()+=[]{}|;:'\"/~`<>".contains(*c))
-        .collect::<String>();
-    
-    // Normalize whitespace
-    sanitized.split_whitespace().collect::<Vec<&str>>().join(" ")
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+struct StorableScreenShare {
+    pub share_session_id: String,
+    pub parent_session_id: String,
+    pub presenter: Principal,
+    pub status: ScreenShareStatus,
+    pub requested_at: u64,
+    pub decided_by: Option<Principal>,
+    pub decided_at: Option<u64>,
+    pub ended_at: Option<u64>,
 }
 
-// === PHI ENCRYPTION FUNCTIONS ===
+impl From<ScreenShare> for StorableScreenShare {
+    fn from(share: ScreenShare) -> Self {
+        StorableScreenShare {
+            share_session_id: share.share_session_id,
+            parent_session_id: share.parent_session_id,
+            presenter: share.presenter,
+            status: share.status,
+            requested_at: share.requested_at,
+            decided_by: share.decided_by,
+            decided_at: share.decided_at,
+            ended_at: share.ended_at,
+        }
+    }
+}
 
-/// Generate a new encryption key for PHI data using IC's random source
-pub fn generate_phi_encryption_key() -> Result<Vec<u8>, String> {
-    let mut key_bytes = [0u8; 32];
-    // Use IC's time and caller as entropy source
-    let entropy = format!("{}{}", get_time(), get_caller().to_text());
-    let mut hasher = Sha256::new();
-    hasher.update(entropy.as_bytes());
-    let hash = hasher.finalize();
-    key_bytes.copy_from_slice(&hash[..32]);
-    Ok(key_bytes.to_vec())
+impl From<StorableScreenShare> for ScreenShare {
+    fn from(storable: StorableScreenShare) -> Self {
+        ScreenShare {
+            share_session_id: storable.share_session_id,
+            parent_session_id: storable.parent_session_id,
+            presenter: storable.presenter,
+            status: storable.status,
+            requested_at: storable.requested_at,
+            decided_by: storable.decided_by,
+            decided_at: storable.decided_at,
+            ended_at: storable.ended_at,
+        }
+    }
 }
 
-/// Encrypt PHI data using HMAC-based encryption
-pub fn encrypt_phi_data(data: &str, key: &[u8]) -> Result<EncryptedData, String> {
-    if key.len() != 32 {
-        return Err("Invalid key length. Requires 32 bytes".to_string());
+impl Storable for StorableScreenShare {
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 512,
+        is_fixed_size: false,
+    };
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).unwrap())
     }
 
-    // Generate nonce using time and data hash
-    let nonce_data = format!("{}{}", get_time(), data.len());
-    let mut nonce_hasher = Sha256::new();
-    nonce_hasher.update(nonce_data.as_bytes());
-    let nonce_hash = nonce_hasher.finalize();
-    let nonce_bytes = &nonce_hash[..12]; // Use first 12 bytes as nonce
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap()
+    }
+}
 
-    // Simple XOR encryption with HMAC authentication
-    let mut encrypted_bytes = Vec::new();
-    let data_bytes = data.as_bytes();
-    
-    // Create encryption key stream using HMAC
-    type HmacSha256 = Hmac<Sha256>;
-    let mut mac = HmacSha256::new_from_slice(key)
-        .map_err(|_| "Invalid key for HMAC".to_string())?;
-    mac.update(nonce_bytes);
-    let key_stream = mac.finalize().into_bytes();
-    
-    // XOR encrypt the data
-    for (i, &byte) in data_bytes.iter().enumerate() {
-        let key_byte = key_stream[i % key_stream.len()];
-        encrypted_bytes.push(byte ^ key_byte);
-    }
-
-    let encrypted_content = general_purpose::STANDARD.encode(&encrypted_bytes);
-    let nonce_b64 = general_purpose::STANDARD.encode(nonce_bytes);
-    
-    // Generate key ID based on key hash
-    let mut hasher = Sha256::new();
-    hasher.update(key);
-    let key_id = format!("phi_key_{}", hex::encode(&hasher.finalize()[..8]));
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+struct StorableSyncEvent {
+    pub seq: u64,
+    pub event_type: SyncEventType,
+    pub conversation_id: Option<String>,
+    pub message_id: Option<u64>,
+    pub session_id: Option<String>,
+    pub timestamp: u64,
+}
 
-    Ok(EncryptedData {
-        encrypted_content,
-        nonce: nonce_b64,
-        key_id,
-    })
+impl From<SyncEvent> for StorableSyncEvent {
+    fn from(event: SyncEvent) -> Self {
+        StorableSyncEvent {
+            seq: event.seq,
+            event_type: event.event_type,
+            conversation_id: event.conversation_id,
+            message_id: event.message_id,
+            session_id: event.session_id,
+            timestamp: event.timestamp,
+        }
+    }
 }
 
-/// Decrypt PHI data using HMAC-based decryption
-pub fn decrypt_phi_data(encrypted_data: &EncryptedData, key: &[u8]) -> Result<String, String> {
-    if key.len() != 32 {
-        return Err("Invalid key length. Requires 32 bytes".to_string());
+impl From<StorableSyncEvent> for SyncEvent {
+    fn from(storable: StorableSyncEvent) -> Self {
+        SyncEvent {
+            seq: storable.seq,
+            event_type: storable.event_type,
+            conversation_id: storable.conversation_id,
+            message_id: storable.message_id,
+            session_id: storable.session_id,
+            timestamp: storable.timestamp,
+        }
     }
+}
 
-    let encrypted_bytes = general_purpose::STANDARD.decode(&encrypted_data.encrypted_content)
-        .map_err(|_| "Failed to decode encrypted content".to_string())?;
-    
-    let nonce_bytes = general_purpose::STANDARD.decode(&encrypted_data.nonce)
-        .map_err(|_| "Failed to decode nonce".to_string())?;
+impl Storable for StorableSyncEvent {
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 512,
+        is_fixed_size: false,
+    };
 
-    if nonce_bytes.len() != 12 {
-        return Err("Invalid nonce length".to_string());
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).unwrap())
     }
 
-    // Create decryption key stream using HMAC (same as encryption)
-    type HmacSha256 = Hmac<Sha256>;
-    let mut mac = HmacSha256::new_from_slice(key)
-        .map_err(|_| "Invalid key for HMAC".to_string())?;
-    mac.update(&nonce_bytes);
-    let key_stream = mac.finalize().into_bytes();
-    
-    // XOR decrypt the data
-    let mut decrypted_bytes = Vec::new();
-    for (i, &byte) in encrypted_bytes.iter().enumerate() {
-        let key_byte = key_stream[i % key_stream.len()];
-        decrypted_bytes.push(byte ^ key_byte);
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap()
     }
+}
 
-    String::from_utf8(decrypted_bytes)
-        .map_err(|_| "Failed to convert decrypted data to string".to_string())
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+struct StorableMessageEdit {
+    pub message_id: u64,
+    pub editor: Principal,
+    pub edited_at: u64,
+    pub previous_content: String,
+    pub signature: String,
 }
 
-/// Derive encryption key from conversation participants for end-to-end encryption
-pub fn derive_conversation_key(participants: &[Principal]) -> Result<Vec<u8>, String> {
-    let mut sorted_participants = participants.to_vec();
-    sorted_participants.sort();
-    
-    let mut hasher = Sha256::new();
-    for participant in &sorted_participants {
-        hasher.update(participant.as_slice());
+impl From<MessageEdit> for StorableMessageEdit {
+    fn from(edit: MessageEdit) -> Self {
+        StorableMessageEdit {
+            message_id: edit.message_id,
+            editor: edit.editor,
+            edited_at: edit.edited_at,
+            previous_content: edit.previous_content,
+            signature: edit.signature,
+        }
     }
-    hasher.update(b"mentalverse_phi_encryption_v1");
-    
-    let hash = hasher.finalize();
-    Ok(hash.to_vec())
 }
 
-/// Validate that PHI data meets encryption requirements
-pub fn validate_phi_encryption(data: &str) -> Result<(), String> {
-    if data.is_empty() {
-        return Err("PHI data cannot be empty".to_string());
+impl From<StorableMessageEdit> for MessageEdit {
+    fn from(storable: StorableMessageEdit) -> Self {
+        MessageEdit {
+            message_id: storable.message_id,
+            editor: storable.editor,
+            edited_at: storable.edited_at,
+            previous_content: storable.previous_content,
+            signature: storable.signature,
+        }
     }
-    
-    if data.len() > 1_000_000 { // 1MB limit
-        return Err("PHI data exceeds maximum size limit".to_string());
+}
+
+impl Storable for StorableMessageEdit {
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 20480, // 20KB: holds a full-length message ciphertext
+        is_fixed_size: false,
+    };
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap()
     }
-    
-    Ok(())
 }
 
-/// Decrypt message content for authorized conversation participants
-pub fn decrypt_message_content(encrypted_content: &str, conversation_participants: &[Principal]) -> Result<String, String> {
-    // Derive the same conversation key used for encryption
-    let decryption_key = derive_conversation_key(conversation_participants)?;
-    
-    // Try to parse as encrypted data structure
-    match serde_json::from_str::<EncryptedData>(encrypted_content) {
-        Ok(encrypted_data) => {
-            // Decrypt using AES-256-GCM
-            decrypt_phi_data(&encrypted_data, &decryption_key)
-        },
-        Err(_) => {
-            // If not encrypted format, return as-is (backward compatibility)
-            Ok(encrypted_content.to_string())
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+struct StorableThreadSummary {
+    pub root_message_id: u64,
+    pub reply_count: u64,
+    pub last_reply_id: Option<u64>,
+    pub last_reply_at: Option<u64>,
+}
+
+impl From<ThreadSummary> for StorableThreadSummary {
+    fn from(summary: ThreadSummary) -> Self {
+        StorableThreadSummary {
+            root_message_id: summary.root_message_id,
+            reply_count: summary.reply_count,
+            last_reply_id: summary.last_reply_id,
+            last_reply_at: summary.last_reply_at,
         }
     }
 }
 
-/// Decrypt attachment data for authorized conversation participants
-pub fn decrypt_attachment_data(encrypted_data: &str, conversation_participants: &[Principal]) -> Result<String, String> {
-    if encrypted_data.is_empty() {
-        return Ok(String::new());
-    }
-    
-    // Derive the same conversation key used for encryption
-    let decryption_key = derive_conversation_key(conversation_participants)?;
-    
-    // Try to parse as encrypted data structure
-    match serde_json::from_str::<EncryptedData>(encrypted_data) {
-        Ok(encrypted_struct) => {
-            // Decrypt using AES-256-GCM
-            decrypt_phi_data(&encrypted_struct, &decryption_key)
-        },
-        Err(_) => {
-            // If not encrypted format, return as-is (backward compatibility)
-            Ok(encrypted_data.to_string())
+impl From<StorableThreadSummary> for ThreadSummary {
+    fn from(storable: StorableThreadSummary) -> Self {
+        ThreadSummary {
+            root_message_id: storable.root_message_id,
+            reply_count: storable.reply_count,
+            last_reply_id: storable.last_reply_id,
+            last_reply_at: storable.last_reply_at,
         }
     }
 }
 
-// === PRINCIPAL VALIDATION FUNCTIONS ===
+impl Storable for StorableThreadSummary {
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 128,
+        is_fixed_size: false,
+    };
 
-/// Validates that a Principal is not anonymous and has proper format
-fn validate_principal(principal: &Principal) -> Result<(), String> {
-    if principal == &Principal::anonymous() {
-        return Err("Anonymous principal not allowed".to_string());
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).unwrap())
     }
-    
-    // Check if principal is properly formatted (basic validation)
-    let principal_text = principal.to_text();
-    if principal_text.is_empty() || principal_text.len() < 5 {
-        return Err("Invalid principal format".to_string());
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap()
     }
-    
-    Ok(())
 }
 
-/// Validates multiple principals in a vector
-fn validate_principals(principals: &[Principal]) -> Result<(), String> {
-    for principal in principals {
-        validate_principal(principal)?;
-    }
-    Ok(())
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+struct StorableReadWatermark {
+    pub delivered_up_to: u64,
+    pub read_up_to: u64,
+    pub updated_at: u64,
 }
 
-/// Validates conversation ID format
-fn validate_conversation_id(conversation_id: &str) -> Result<(), String> {
-    if conversation_id.is_empty() {
-        return Err("Conversation ID cannot be empty".to_string());
+impl Storable for StorableReadWatermark {
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 64,
+        is_fixed_size: false,
+    };
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).unwrap())
     }
-    
-    if conversation_id.len() > 128 {
-        return Err("Conversation ID too long".to_string());
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap()
     }
-    
-    Ok(())
 }
 
-/// Validates session ID format for RTC sessions
-pub fn validate_session_id(session_id: &str) -> Result<(), String> {
-    if session_id.is_empty() {
-        return Err("Session ID cannot be empty".to_string());
-    }
-    
-    // Basic UUID format validation (36 characters with hyphens)
-    if session_id.len() != 36 {
-        return Err("Session ID must be a valid UUID format".to_string());
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+struct StorableConversationSettings {
+    pub read_receipts_enabled: bool,
+    pub end_to_end_encrypted: Option<bool>, // absent on settings stored before E2E existed
+}
+
+impl Storable for StorableConversationSettings {
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 512,
+        is_fixed_size: false,
+    };
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).unwrap())
     }
-    
-    // Check for basic UUID pattern (8-4-4-4-12)
-    let parts: Vec<&str> = session_id.split('-').collect();
-    if parts.len() != 5 || 
-       parts[0].len() != 8 || 
-       parts[1].len() != 4 || 
-       parts[2].len() != 4 || 
-       parts[3].len() != 4 || 
-       parts[4].len() != 12 {
-        return Err("Session ID must be a valid UUID format".to_string());
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap()
     }
-    
-    Ok(())
 }
 
-// === CANISTER LIFECYCLE ===
-
-#[init]
-fn init() {
-    // Initialize the canister
-    ic_cdk::println!("Secure Messaging Canister initialized");
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+struct StorableUnreadCount {
+    pub count: u64,
+    pub first_unread_id: Option<u64>,
 }
 
-#[pre_upgrade]
-fn pre_upgrade() {
-    // Stable memory is automatically preserved
+impl Storable for StorableUnreadCount {
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 64,
+        is_fixed_size: false,
+    };
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap()
+    }
 }
 
-#[post_upgrade]
-fn post_upgrade() {
-    // Stable memory is automatically restored
-    ic_cdk::println!("Secure Messaging Canister upgraded");
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+struct StorableUserPreferences {
+    pub typing_indicators_enabled: bool,
 }
 
-// === PHI KEY MANAGEMENT API ===
+impl Storable for StorableUserPreferences {
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 512,
+        is_fixed_size: false,
+    };
 
-/// Generate and store a new PHI encryption key for a conversation
-#[update]
-fn generate_conversation_phi_key(conversation_id: String) -> Result<String, String> {
-    let caller = get_caller();
-    
-    // Validate caller principal
-    validate_principal(&caller)?;
-    
-    // Validate conversation ID format
-    validate_conversation_id(&conversation_id)?;
-    
-    // Verify caller is participant in conversation
-    let conversation = CONVERSATIONS.with(|conversations| {
-        conversations.borrow().get(&conversation_id)
-    });
-    
-    let conversation = match conversation {
-        Some(conv) => Conversation::from(conv),
-        None => return Err("Conversation not found".to_string()),
-    };
-    
-    if !is_participant(&conversation, &caller) {
-        return Err("Unauthorized: Not a participant in this conversation".to_string());
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap()
     }
-    
-    // Generate new PHI encryption key
-    let phi_key = generate_phi_encryption_key()?;
-    let key_id = format!("phi_conv_{}_{}", conversation_id, get_time());
-    
-    let _phi_encryption_key = PHIEncryptionKey {
-        key_id: key_id.clone(),
-        key_data: phi_key,
-        created_at: get_time(),
-        is_active: true,
-        purpose: EncryptionPurpose::MessageContent,
-    };
-    
-    // Store the key (in a real implementation, this would be stored securely)
-    // For now, we return the key_id as confirmation
-    Ok(key_id)
 }
 
-/// Rotate PHI encryption key for a conversation (enhanced security)
-#[update]
-fn rotate_conversation_phi_key(conversation_id: String, _old_key_id: String) -> Result<String, String> {
-    let caller = get_caller();
-    
-    // Validate caller principal
-    validate_principal(&caller)?;
-    
-    // Validate conversation ID format
-    validate_conversation_id(&conversation_id)?;
-    
-    // Verify caller is participant in conversation
-    let conversation = CONVERSATIONS.with(|conversations| {
-        conversations.borrow().get(&conversation_id)
-    });
-    
-    let conversation = match conversation {
-        Some(conv) => Conversation::from(conv),
-        None => return Err("Conversation not found".to_string()),
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+struct StorableParticipantRole {
+    pub role: ParticipantRole,
+    pub joined_at: u64,
+}
+
+impl Storable for StorableParticipantRole {
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 64,
+        is_fixed_size: false,
     };
-    
-    if !is_participant(&conversation, &caller) {
-        return Err("Unauthorized: Not a participant in this conversation".to_string());
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap()
     }
-    
-    // Generate new PHI encryption key
-    let _new_phi_key = generate_phi_encryption_key()?;
-    let new_key_id = format!("phi_conv_{}_{}", conversation_id, get_time());
-    
-    // In a real implementation, you would:
-    // 1. Mark old key as inactive
-    // 2. Store new key securely
-    // 3. Re-encrypt recent messages with new key (optional)
-    
-    Ok(new_key_id)
 }
 
-// === PUBLIC API ===
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+struct StorableKeyParticipants {
+    pub participants: Vec<Principal>,
+}
 
-// Register user's public key for encryption
-#[update]
-fn register_user_key(public_key: String, key_type: KeyType) -> Result<UserKey, String> {
-    let caller = get_caller();
-    let now = get_time();
-    
-    // Validate caller principal
-    validate_principal(&caller)?;
-    
-    if public_key.is_empty() {
-        return Err("Public key cannot be empty".to_string());
-    }
-    
-    let user_key = UserKey {
-        user_id: caller,
-        public_key,
-        key_type,
-        created_at: now,
-        is_active: true,
+impl Storable for StorableKeyParticipants {
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 2048,
+        is_fixed_size: false,
     };
-    
-    USER_KEYS.with(|keys| {
-        keys.borrow_mut().insert(caller, StorableUserKey::from(user_key.clone()));
-    });
-    
-    Ok(user_key)
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap()
+    }
 }
 
-// Get user's public key
-#[query]
-fn get_user_key(user_id: Principal) -> Option<UserKey> {
-    // Validate user_id principal
-    if validate_principal(&user_id).is_err() {
-        return None; // Return None for invalid principals
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+struct StorableConversationInvite {
+    pub token: String,
+    pub conversation_id: String,
+    pub created_by: Principal,
+    pub role: ParticipantRole,
+    pub created_at: u64,
+    pub expires_at: Option<u64>,
+    pub max_uses: Option<u64>,
+    pub uses: u64,
+    pub requires_approval: bool,
+    pub revoked: bool,
+}
+
+impl From<ConversationInvite> for StorableConversationInvite {
+    fn from(invite: ConversationInvite) -> Self {
+        StorableConversationInvite {
+            token: invite.token,
+            conversation_id: invite.conversation_id,
+            created_by: invite.created_by,
+            role: invite.role,
+            created_at: invite.created_at,
+            expires_at: invite.expires_at,
+            max_uses: invite.max_uses,
+            uses: invite.uses,
+            requires_approval: invite.requires_approval,
+            revoked: invite.revoked,
+        }
     }
-    
-    USER_KEYS.with(|keys| {
-        keys.borrow().get(&user_id).map(|storable| UserKey::from(storable))
-    })
 }
 
-// Create a new conversation
-#[update]
-fn create_conversation(
-    participants: Vec<Principal>,
-    conversation_type: ConversationType,
-    metadata: ConversationMetadata,
-) -> ConversationResult {
-    let caller = get_caller();
-    let now = get_time();
-    
-    // Validate caller principal
-    if let Err(e) = validate_principal(&caller) {
-        return ConversationResult {
-            success: false,
-            conversation: None,
-            error: Some(format!("Invalid caller: {}", e)),
-        };
+impl From<StorableConversationInvite> for ConversationInvite {
+    fn from(storable: StorableConversationInvite) -> Self {
+        ConversationInvite {
+            token: storable.token,
+            conversation_id: storable.conversation_id,
+            created_by: storable.created_by,
+            role: storable.role,
+            created_at: storable.created_at,
+            expires_at: storable.expires_at,
+            max_uses: storable.max_uses,
+            uses: storable.uses,
+            requires_approval: storable.requires_approval,
+            revoked: storable.revoked,
+        }
     }
-    
-    // Validate all participant principals
-    if let Err(e) = validate_principals(&participants) {
-        return ConversationResult {
-            success: false,
-            conversation: None,
-            error: Some(format!("Invalid participants: {}", e)),
-        };
+}
+
+impl Storable for StorableConversationInvite {
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 512,
+        is_fixed_size: false,
+    };
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).unwrap())
     }
-    
-    // Validate that caller is in participants
-    if !participants.contains(&caller) {
-        return ConversationResult {
-            success: false,
-            conversation: None,
-            error: Some("Caller must be a participant".to_string()),
-        };
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap()
     }
-    
-    // Validate participants count
-    if participants.len() < 2 {
-        return ConversationResult {
-            success: false,
-            conversation: None,
-            error: Some("Conversation must have at least 2 participants".to_string()),
-        };
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+struct StorableJoinRequest {
+    pub conversation_id: String,
+    pub user_id: Principal,
+    pub invite_token: String,
+    pub role: ParticipantRole,
+    pub requested_at: u64,
+}
+
+impl From<JoinRequest> for StorableJoinRequest {
+    fn from(request: JoinRequest) -> Self {
+        StorableJoinRequest {
+            conversation_id: request.conversation_id,
+            user_id: request.user_id,
+            invite_token: request.invite_token,
+            role: request.role,
+            requested_at: request.requested_at,
+        }
     }
-    
-    let conversation_id = generate_conversation_id(&participants);
-    
-    // Check if conversation already exists
-    let existing_conversation = CONVERSATIONS.with(|conversations| {
-        conversations.borrow().get(&conversation_id)
-    });
-    
-    if existing_conversation.is_some() {
-        return ConversationResult {
-            success: false,
-            conversation: None,
-            error: Some("Conversation already exists".to_string()),
-        };
+}
+
+impl From<StorableJoinRequest> for JoinRequest {
+    fn from(storable: StorableJoinRequest) -> Self {
+        JoinRequest {
+            conversation_id: storable.conversation_id,
+            user_id: storable.user_id,
+            invite_token: storable.invite_token,
+            role: storable.role,
+            requested_at: storable.requested_at,
+        }
     }
-    
-    let conversation = Conversation {
-        id: conversation_id.clone(),
-        participants,
-        conversation_type,
-        created_at: now,
-        updated_at: now,
-        last_message_id: None,
-        is_archived: false,
-        metadata,
+}
+
+impl Storable for StorableJoinRequest {
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 512,
+        is_fixed_size: false,
     };
-    
-    CONVERSATIONS.with(|conversations| {
-        conversations.borrow_mut().insert(
-            conversation_id,
-            StorableConversation::from(conversation.clone()),
-        );
-    });
-    
-    ConversationResult {
-        success: true,
-        conversation: Some(conversation),
-        error: None,
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap()
     }
 }
 
-// Send a message with comprehensive PHI encryption
-#[update]
-fn send_message(
-    conversation_id: String,
-    recipient_id: Principal,
-    content: String,
-    message_type: MessageType,
-    reply_to: Option<u64>,
-    attachments: Vec<Attachment>,
-    nonce: String,
-    timestamp: u64,
-) -> MessageResult {
-    let caller = get_caller();
-    let now = get_time();
-    
-    // Phase 2: Rate limiting (max 50 messages per minute)
-    if let Err(e) = check_rate_limit(caller, 50, 60000) {
-        return MessageResult {
-            success: false,
-            message: None,
-            error: Some(e),
-        };
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+struct StorableMessageReport {
+    pub id: u64,
+    pub message_id: u64,
+    pub conversation_id: String,
+    pub reporter: Principal,
+    pub reported_user: Principal,
+    pub reason: ReportReason,
+    pub details: Option<String>,
+    pub escrowed_content: Option<String>, // encrypted with the moderation escrow key
+    pub status: ReportStatus,
+    pub created_at: u64,
+    pub resolved_at: Option<u64>,
+    pub resolved_by: Option<Principal>,
+}
+
+impl From<MessageReport> for StorableMessageReport {
+    fn from(report: MessageReport) -> Self {
+        StorableMessageReport {
+            id: report.id,
+            message_id: report.message_id,
+            conversation_id: report.conversation_id,
+            reporter: report.reporter,
+            reported_user: report.reported_user,
+            reason: report.reason,
+            details: report.details,
+            escrowed_content: report.escrowed_content,
+            status: report.status,
+            created_at: report.created_at,
+            resolved_at: report.resolved_at,
+            resolved_by: report.resolved_by,
+        }
     }
-    
-    // Phase 2: Replay attack protection
-    if let Err(e) = validate_nonce(&nonce, timestamp) {
-        return MessageResult {
-            success: false,
-            message: None,
-            error: Some(e),
-        };
+}
+
+impl From<StorableMessageReport> for MessageReport {
+    fn from(storable: StorableMessageReport) -> Self {
+        MessageReport {
+            id: storable.id,
+            message_id: storable.message_id,
+            conversation_id: storable.conversation_id,
+            reporter: storable.reporter,
+            reported_user: storable.reported_user,
+            reason: storable.reason,
+            details: storable.details,
+            escrowed_content: storable.escrowed_content,
+            status: storable.status,
+            created_at: storable.created_at,
+            resolved_at: storable.resolved_at,
+            resolved_by: storable.resolved_by,
+        }
     }
-    
-    // Phase 2: Input validation and sanitization
-    if let Err(e) = validate_text_not_empty(&content, "Message content") {
-        return MessageResult {
-            success: false,
-            message: None,
-            error: Some(e),
-        };
+}
+
+impl Storable for StorableMessageReport {
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 24576, // 24KB: escrowed full-length message plus report details
+        is_fixed_size: false,
+    };
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).unwrap())
     }
-    
-    if let Err(e) = validate_text_length(&content, MAX_TEXT_LENGTH, "Message content") {
-        return MessageResult {
-            success: false,
-            message: None,
-            error: Some(e),
-        };
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap()
     }
-    
-    let sanitized_content = sanitize_text(&content);
-    
-    // Validate caller principal
-    if let Err(e) = validate_principal(&caller) {
-        return MessageResult {
-            success: false,
-            message: None,
-            error: Some(format!("Invalid caller: {}", e)),
-        };
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+struct StorableModerationAuditEntry {
+    pub id: u64,
+    pub moderator: Principal,
+    pub action: ModerationAction,
+    pub target_user: Principal,
+    pub report_id: Option<u64>,
+    pub message_id: Option<u64>,
+    pub note: Option<String>,
+    pub created_at: u64,
+}
+
+impl From<ModerationAuditEntry> for StorableModerationAuditEntry {
+    fn from(entry: ModerationAuditEntry) -> Self {
+        StorableModerationAuditEntry {
+            id: entry.id,
+            moderator: entry.moderator,
+            action: entry.action,
+            target_user: entry.target_user,
+            report_id: entry.report_id,
+            message_id: entry.message_id,
+            note: entry.note,
+            created_at: entry.created_at,
+        }
     }
-    
-    // Validate recipient principal
-    if let Err(e) = validate_principal(&recipient_id) {
-        return MessageResult {
-            success: false,
-            message: None,
-            error: Some(format!("Invalid recipient: {}", e)),
-        };
+}
+
+impl From<StorableModerationAuditEntry> for ModerationAuditEntry {
+    fn from(storable: StorableModerationAuditEntry) -> Self {
+        ModerationAuditEntry {
+            id: storable.id,
+            moderator: storable.moderator,
+            action: storable.action,
+            target_user: storable.target_user,
+            report_id: storable.report_id,
+            message_id: storable.message_id,
+            note: storable.note,
+            created_at: storable.created_at,
+        }
     }
-    
-    // Validate conversation ID format
-    if let Err(e) = validate_conversation_id(&conversation_id) {
-        return MessageResult {
-            success: false,
-            message: None,
-            error: Some(format!("Invalid conversation ID: {}", e)),
-        };
+}
+
+impl Storable for StorableModerationAuditEntry {
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 2048,
+        is_fixed_size: false,
+    };
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).unwrap())
     }
-    
-    // Validate PHI content requirements
-    if let Err(e) = validate_phi_encryption(&content) {
-        return MessageResult {
-            success: false,
-            message: None,
-            error: Some(format!("PHI validation failed: {}", e)),
-        };
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap()
     }
-    
-    // Validate conversation exists and caller is participant
-    let conversation = CONVERSATIONS.with(|conversations| {
-        conversations.borrow().get(&conversation_id)
-    });
-    
-    let conversation = match conversation {
-        Some(conv) => Conversation::from(conv),
-        None => {
-            return MessageResult {
-                success: false,
-                message: None,
-                error: Some("Conversation not found".to_string()),
-            };
-        }
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+struct StorableAccountStanding {
+    pub warning_count: u64,
+    pub last_warning_at: Option<u64>,
+    pub suspended_until: Option<u64>,
+}
+
+impl Storable for StorableAccountStanding {
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 128,
+        is_fixed_size: false,
     };
-    
-    if !is_participant(&conversation, &caller) {
-        return MessageResult {
-            success: false,
-            message: None,
-            error: Some("Unauthorized: Not a participant in this conversation".to_string()),
-        };
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).unwrap())
     }
-    
-    // Validate recipient is also a participant
-    if !is_participant(&conversation, &recipient_id) {
-        return MessageResult {
-            success: false,
-            message: None,
-            error: Some("Recipient is not a participant in this conversation".to_string()),
-        };
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap()
     }
-    
-    // Generate conversation-specific encryption key for PHI data
-    let encryption_key = match derive_conversation_key(&conversation.participants) {
-        Ok(key) => key,
-        Err(e) => {
-            return MessageResult {
-                success: false,
-                message: None,
-                error: Some(format!("Failed to derive encryption key: {}", e)),
-            };
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+struct StorableCrisisTerm {
+    pub language: String,
+    pub phrase: String,
+    pub severity: CrisisSeverity,
+    pub added_at: u64,
+}
+
+impl From<CrisisTerm> for StorableCrisisTerm {
+    fn from(term: CrisisTerm) -> Self {
+        StorableCrisisTerm {
+            language: term.language,
+            phrase: term.phrase,
+            severity: term.severity,
+            added_at: term.added_at,
         }
-    };
-    
-    // Encrypt message content using AES-256-GCM (use sanitized content)
-    let encrypted_content = match encrypt_phi_data(&sanitized_content, &encryption_key) {
-        Ok(encrypted) => {
-            // Store as JSON string containing encrypted data structure
-            serde_json::to_string(&encrypted).unwrap_or_else(|_| sanitized_content.clone())
-        },
-        Err(e) => {
-            return MessageResult {
-                success: false,
-                message: None,
-                error: Some(format!("Failed to encrypt message content: {}", e)),
-            };
+    }
+}
+
+impl From<StorableCrisisTerm> for CrisisTerm {
+    fn from(storable: StorableCrisisTerm) -> Self {
+        CrisisTerm {
+            language: storable.language,
+            phrase: storable.phrase,
+            severity: storable.severity,
+            added_at: storable.added_at,
         }
+    }
+}
+
+impl Storable for StorableCrisisTerm {
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 512,
+        is_fixed_size: false,
     };
-    
-    // Encrypt attachments if present
-    let encrypted_attachments: Vec<Attachment> = attachments.into_iter().map(|mut attachment| {
-        if !attachment.encrypted_data.is_empty() {
-            // Decrypt first if already encrypted, then re-encrypt with conversation key
-            match encrypt_phi_data(&attachment.encrypted_data, &encryption_key) {
-                Ok(encrypted) => {
-                    attachment.encrypted_data = serde_json::to_string(&encrypted)
-                        .unwrap_or_else(|_| attachment.encrypted_data.clone());
-                },
-                Err(_) => {
-                    // Keep original if encryption fails
-                }
-            }
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap()
+    }
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+struct StorableCrisisEscalation {
+    pub id: u64,
+    pub conversation_id: String,
+    pub message_id: u64,
+    pub user_at_risk: Principal,
+    pub reported_by: Option<Principal>,
+    pub source: EscalationSource,
+    pub severity: CrisisSeverity,
+    pub matched_terms: Vec<String>,
+    pub status: EscalationStatus,
+    pub created_at: u64,
+    pub acknowledged_by: Option<Principal>,
+    pub acknowledged_at: Option<u64>,
+    pub resolved_at: Option<u64>,
+    pub resolution_note: Option<String>,
+}
+
+impl From<CrisisEscalation> for StorableCrisisEscalation {
+    fn from(escalation: CrisisEscalation) -> Self {
+        StorableCrisisEscalation {
+            id: escalation.id,
+            conversation_id: escalation.conversation_id,
+            message_id: escalation.message_id,
+            user_at_risk: escalation.user_at_risk,
+            reported_by: escalation.reported_by,
+            source: escalation.source,
+            severity: escalation.severity,
+            matched_terms: escalation.matched_terms,
+            status: escalation.status,
+            created_at: escalation.created_at,
+            acknowledged_by: escalation.acknowledged_by,
+            acknowledged_at: escalation.acknowledged_at,
+            resolved_at: escalation.resolved_at,
+            resolution_note: escalation.resolution_note,
         }
-        attachment
-    }).collect();
-    
-    let message_id = generate_next_id();
-    
-    let message = Message {
-        id: message_id,
-        conversation_id: conversation_id.clone(),
-        sender_id: caller,
-        recipient_id,
-        content: encrypted_content, // Store encrypted content
-        message_type,
-        timestamp: now,
-        is_read: false,
-        is_deleted: false,
-        reply_to,
-        attachments: encrypted_attachments, // Store encrypted attachments
-    };
-    
-    // Store the message
-    MESSAGES.with(|messages| {
-        messages.borrow_mut().insert(message_id, StorableMessage::from(message.clone()));
-    });
-    
-    // Update conversation's last message
-    let updated_conversation = Conversation {
-        last_message_id: Some(message_id),
-        updated_at: now,
-        ..conversation
+    }
+}
+
+impl From<StorableCrisisEscalation> for CrisisEscalation {
+    fn from(storable: StorableCrisisEscalation) -> Self {
+        CrisisEscalation {
+            id: storable.id,
+            conversation_id: storable.conversation_id,
+            message_id: storable.message_id,
+            user_at_risk: storable.user_at_risk,
+            reported_by: storable.reported_by,
+            source: storable.source,
+            severity: storable.severity,
+            matched_terms: storable.matched_terms,
+            status: storable.status,
+            created_at: storable.created_at,
+            acknowledged_by: storable.acknowledged_by,
+            acknowledged_at: storable.acknowledged_at,
+            resolved_at: storable.resolved_at,
+            resolution_note: storable.resolution_note,
+        }
+    }
+}
+
+impl Storable for StorableCrisisEscalation {
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 4096,
+        is_fixed_size: false,
     };
-    
-    CONVERSATIONS.with(|conversations| {
-        conversations.borrow_mut().insert(
-            conversation_id,
-            StorableConversation::from(updated_conversation),
-        );
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap()
+    }
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+struct StorableRetention {
+    pub policy: RetentionPolicy,
+    pub updated_by: Principal,
+    pub updated_at: u64,
+    pub read_expiry_through: u64, // disappear-after-read: highest message ID already given an expiry
+}
+
+impl Storable for StorableRetention {
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 256,
+        is_fixed_size: false,
+    };
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap()
+    }
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+struct StorableScheduledMessage {
+    pub id: u64,
+    pub conversation_id: String,
+    pub sender_id: Principal,
+    pub content: String,
+    pub message_type: MessageType,
+    pub deliver_at: u64,
+    pub created_at: u64,
+    pub status: ScheduledMessageStatus,
+    pub failure_reason: Option<String>,
+}
+
+impl From<ScheduledMessage> for StorableScheduledMessage {
+    fn from(scheduled: ScheduledMessage) -> Self {
+        StorableScheduledMessage {
+            id: scheduled.id,
+            conversation_id: scheduled.conversation_id,
+            sender_id: scheduled.sender_id,
+            content: scheduled.content,
+            message_type: scheduled.message_type,
+            deliver_at: scheduled.deliver_at,
+            created_at: scheduled.created_at,
+            status: scheduled.status,
+            failure_reason: scheduled.failure_reason,
+        }
+    }
+}
+
+impl From<StorableScheduledMessage> for ScheduledMessage {
+    fn from(storable: StorableScheduledMessage) -> Self {
+        ScheduledMessage {
+            id: storable.id,
+            conversation_id: storable.conversation_id,
+            sender_id: storable.sender_id,
+            content: storable.content,
+            message_type: storable.message_type,
+            deliver_at: storable.deliver_at,
+            created_at: storable.created_at,
+            status: storable.status,
+            failure_reason: storable.failure_reason,
+        }
+    }
+}
+
+impl Storable for StorableScheduledMessage {
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 20480, // 20KB: encrypted full-length content plus failure reason
+        is_fixed_size: false,
+    };
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap()
+    }
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+struct StorableSanitizationPolicy {
+    pub max_length: u64,
+    pub allow_newlines: bool,
+}
+
+impl Storable for StorableSanitizationPolicy {
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 128,
+        is_fixed_size: false,
+    };
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        candid::decode_one(&bytes).unwrap()
+    }
+}
+
+// === GLOBAL STATE ===
+
+thread_local! {
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
+        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+
+    static ID_COUNTER: RefCell<IdStore> = RefCell::new(
+        IdStore::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(0)))
+        )
+    );
+
+    static MESSAGES: RefCell<MessageStore> = RefCell::new(
+        MessageStore::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1)))
+        )
+    );
+
+    static CONVERSATIONS: RefCell<ConversationStore> = RefCell::new(
+        ConversationStore::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(2)))
+        )
+    );
+
+    static USER_KEYS: RefCell<UserKeyStore> = RefCell::new(
+        UserKeyStore::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(3)))
+        )
+    );
+    
+    // Phase 2: Security storage
+    static RATE_LIMITS: RefCell<RateLimitStore> = RefCell::new(
+        RateLimitStore::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(4)))
+        )
+    );
+    
+    static USED_NONCES: RefCell<NonceStore> = RefCell::new(
+        NonceStore::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(5)))
+        )
+    );
+
+    // Real-time communication storage
+    static RTC_SESSIONS: RefCell<RTCSessionStore> = RefCell::new(
+        RTCSessionStore::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(6)))
+        )
+    );
+
+    // Keyed by "<session_id>:<zero-padded id>" so a session's trail is a contiguous range
+    static RECORDING_CONSENTS: RefCell<RecordingConsentStore> = RefCell::new(
+        RecordingConsentStore::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(7)))
+        )
+    );
+
+    // "<conversation_id>:<zero-padded sequence>" -> session_id, in creation order
+    static RTC_SESSION_INDEX: RefCell<RTCSessionIndexStore> = RefCell::new(
+        RTCSessionIndexStore::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(8)))
+        )
+    );
+
+    static GROUP_ROOMS: RefCell<GroupRoomStore> = RefCell::new(
+        GroupRoomStore::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(9)))
+        )
+    );
+
+    // Keyed by "<session_id>:<recipient>:<zero-padded id>" so each recipient drains its own queue
+    static WEBRTC_SIGNALS: RefCell<WebRTCSignalStore> = RefCell::new(
+        WebRTCSignalStore::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(10)))
+        )
+    );
+
+    // Bounded per-session telemetry buffer keyed by "<session_id>:<zero-padded id>"
+    static CALL_TELEMETRY: RefCell<CallTelemetryStore> = RefCell::new(
+        CallTelemetryStore::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(11)))
+        )
+    );
+
+    static PRESENCE: RefCell<PresenceStore> = RefCell::new(
+        PresenceStore::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(12)))
+        )
+    );
+
+    // Keyed by "<parent_session_id>:<share_session_id>" so a call's shares are a contiguous range
+    static SCREEN_SHARES: RefCell<ScreenShareStore> = RefCell::new(
+        ScreenShareStore::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(13)))
+        )
+    );
+
+    // Per-user change log keyed by "<principal>:<zero-padded seq>"
+    static SYNC_EVENTS: RefCell<SyncEventStore> = RefCell::new(
+        SyncEventStore::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(14)))
+        )
+    );
+
+    // Highest seq pruned from each user's change log
+    static SYNC_WATERMARKS: RefCell<SyncWatermarkStore> = RefCell::new(
+        SyncWatermarkStore::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(15)))
+        )
+    );
+
+    // "<conversation_id>:<zero-padded message_id>" so history queries only touch one conversation
+    static CONVERSATION_MESSAGES: RefCell<ConversationMessageIndex> = RefCell::new(
+        ConversationMessageIndex::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(16)))
+        )
+    );
+
+    // "<principal>:<zero-padded updated_at>:<conversation_id>" so a user's conversations come out sorted
+    static USER_CONVERSATIONS: RefCell<UserConversationIndex> = RefCell::new(
+        UserConversationIndex::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(17)))
+        )
+    );
+
+    // Controller-tunable numeric settings
+    static SETTINGS: RefCell<SettingsStore> = RefCell::new(
+        SettingsStore::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(18)))
+        )
+    );
+
+    // Keyed by "<zero-padded message_id>:<zero-padded id>", oldest edit first
+    static MESSAGE_EDITS: RefCell<MessageEditStore> = RefCell::new(
+        MessageEditStore::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(19)))
+        )
+    );
+
+    // "<zero-padded message_id>:<emoji>:<principal>" -> reacted at; one entry per user and emoji
+    static REACTIONS: RefCell<ReactionStore> = RefCell::new(
+        ReactionStore::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(20)))
+        )
+    );
+
+    static THREAD_SUMMARIES: RefCell<ThreadSummaryStore> = RefCell::new(
+        ThreadSummaryStore::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(21)))
+        )
+    );
+
+    // Keyed by "<zero-padded root_id>:<zero-padded reply_id>"
+    static THREAD_REPLIES: RefCell<ThreadReplyIndex> = RefCell::new(
+        ThreadReplyIndex::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(22)))
+        )
+    );
+
+    // Keyed by "<conversation_id>:<principal>"
+    static READ_WATERMARKS: RefCell<ReadWatermarkStore> = RefCell::new(
+        ReadWatermarkStore::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(23)))
+        )
+    );
+
+    // Only conversations that changed a default have an entry
+    static CONVERSATION_SETTINGS: RefCell<ConversationSettingsStore> = RefCell::new(
+        ConversationSettingsStore::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(24)))
+        )
+    );
+
+    // Keyed by "<principal>:<conversation_id>"; only conversations with unread messages have an entry
+    static UNREAD_COUNTS: RefCell<UnreadCountStore> = RefCell::new(
+        UnreadCountStore::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(25)))
+        )
+    );
+
+    static USER_PREFERENCES: RefCell<UserPreferencesStore> = RefCell::new(
+        UserPreferencesStore::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(26)))
+        )
+    );
+
+    // Keyed by "<conversation_id>:<principal>"
+    static PARTICIPANT_ROLES: RefCell<ParticipantRoleStore> = RefCell::new(
+        ParticipantRoleStore::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(27)))
+        )
+    );
+
+    // Participants the conversation key was derived from, pinned on the first membership change
+    static KEY_PARTICIPANTS: RefCell<KeyParticipantsStore> = RefCell::new(
+        KeyParticipantsStore::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(28)))
+        )
+    );
+
+    // "<principal>:<principal>" (sorted) -> ID of their direct conversation
+    static DIRECT_CONVERSATIONS: RefCell<DirectConversationIndex> = RefCell::new(
+        DirectConversationIndex::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(29)))
+        )
+    );
+
+    // "<creator>:<idempotency key>" -> conversation ID created with that key
+    static CONVERSATION_IDEMPOTENCY: RefCell<IdempotencyStore> = RefCell::new(
+        IdempotencyStore::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(30)))
+        )
+    );
+
+    static INVITES: RefCell<InviteStore> = RefCell::new(
+        InviteStore::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(31)))
+        )
+    );
+
+    // "<conversation_id>:<token>" for listing a conversation's invites
+    static CONVERSATION_INVITES: RefCell<InviteIndex> = RefCell::new(
+        InviteIndex::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(32)))
+        )
+    );
+
+    // Keyed by "<conversation_id>:<principal>"; one pending request per user
+    static JOIN_REQUESTS: RefCell<JoinRequestStore> = RefCell::new(
+        JoinRequestStore::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(33)))
+        )
+    );
+
+    // "<blocker>:<blocked>" -> blocked at
+    static BLOCKS: RefCell<BlockStore> = RefCell::new(
+        BlockStore::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(34)))
+        )
+    );
+
+    // "<principal>:<zero-padded message_id>" for messages sent while the principal had blocked the sender
+    static SUPPRESSED_MESSAGES: RefCell<SuppressedMessageIndex> = RefCell::new(
+        SuppressedMessageIndex::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(35)))
+        )
+    );
+
+    // "<principal>:<conversation_id>" -> muted until
+    static MUTES: RefCell<MuteStore> = RefCell::new(
+        MuteStore::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(36)))
+        )
+    );
+
+    // Principal -> appointed at; appointed by controllers
+    static MODERATORS: RefCell<ModeratorStore> = RefCell::new(
+        ModeratorStore::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(37)))
+        )
+    );
+
+    static REPORTS: RefCell<ReportStore> = RefCell::new(
+        ReportStore::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(38)))
+        )
+    );
+
+    // IDs of reports awaiting review, oldest first
+    static OPEN_REPORTS: RefCell<OpenReportIndex> = RefCell::new(
+        OpenReportIndex::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(39)))
+        )
+    );
+
+    static MODERATION_AUDIT: RefCell<ModerationAuditStore> = RefCell::new(
+        ModerationAuditStore::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(40)))
+        )
+    );
+
+    static ACCOUNT_STANDING: RefCell<AccountStandingStore> = RefCell::new(
+        AccountStandingStore::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(41)))
+        )
+    );
+
+    // "language:phrase" -> term
+    static CRISIS_LEXICON: RefCell<CrisisLexiconStore> = RefCell::new(
+        CrisisLexiconStore::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(42)))
+        )
+    );
+
+    static CRISIS_ESCALATIONS: RefCell<EscalationStore> = RefCell::new(
+        EscalationStore::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(43)))
+        )
+    );
+
+    // IDs of escalations not yet resolved, oldest first
+    static OPEN_ESCALATIONS: RefCell<OpenEscalationIndex> = RefCell::new(
+        OpenEscalationIndex::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(44)))
+        )
+    );
+
+    // Flagged message ID -> escalation ID
+    static CRISIS_FLAGS: RefCell<CrisisFlagStore> = RefCell::new(
+        CrisisFlagStore::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(45)))
+        )
+    );
+
+    // Principal -> added at; clinicians who work the escalation queue
+    static ON_CALL_CLINICIANS: RefCell<ClinicianStore> = RefCell::new(
+        ClinicianStore::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(46)))
+        )
+    );
+
+    // Message type name -> policy; types without an entry use the built-in default
+    static SANITIZATION_POLICIES: RefCell<SanitizationPolicyStore> = RefCell::new(
+        SanitizationPolicyStore::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(47)))
+        )
+    );
+
+    static SCHEDULED_MESSAGES: RefCell<ScheduledMessageStore> = RefCell::new(
+        ScheduledMessageStore::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(48)))
+        )
+    );
+
+    // "deliver_at:id" for pending scheduled messages, so they range-scan in delivery order
+    static SCHEDULE_QUEUE: RefCell<ScheduleQueue> = RefCell::new(
+        ScheduleQueue::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(49)))
+        )
+    );
+
+    // "principal:id" for every scheduled message a sender still has
+    static SCHEDULED_BY_SENDER: RefCell<ScheduledBySenderIndex> = RefCell::new(
+        ScheduledBySenderIndex::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(50)))
+        )
+    );
+
+    // Conversations without an entry keep messages forever
+    static RETENTION_POLICIES: RefCell<RetentionStore> = RefCell::new(
+        RetentionStore::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(51)))
+        )
+    );
+
+    // Message ID -> when it gets purged
+    static MESSAGE_EXPIRY: RefCell<MessageExpiryStore> = RefCell::new(
+        MessageExpiryStore::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(52)))
+        )
+    );
+
+    // "expire_at:message_id" -> conversation ID, scanned in expiry order by the purge timer
+    static PURGE_QUEUE: RefCell<PurgeQueue> = RefCell::new(
+        PurgeQueue::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(53)))
+        )
+    );
+
+    // "expires_at:signal key" for every queued signal, so signals nobody fetches age out
+    static SIGNAL_EXPIRY: RefCell<SignalExpiryIndex> = RefCell::new(
+        SignalExpiryIndex::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(54)))
+        )
+    );
+
+    // Backfill setting name -> last key it processed, so a backfill resumes after upgrades
+    static BACKFILL_CURSORS: RefCell<BackfillCursorStore> = RefCell::new(
+        BackfillCursorStore::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(55)))
+        )
+    );
+
+    // Conversation ID -> (last message ID re-derived, newest message ID when the policy
+    // changed). The purge timer works through these after a retention policy change.
+    static RETENTION_REAPPLY: RefCell<RetentionReapplyQueue> = RefCell::new(
+        RetentionReapplyQueue::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(56)))
+        )
+    );
+
+    // "<zero-padded message_id>:<principal>", the reverse of SUPPRESSED_MESSAGES, so a
+    // purged message's entries can be found even for people who have since left
+    static SUPPRESSED_BY_MESSAGE: RefCell<SuppressedByMessageIndex> = RefCell::new(
+        SuppressedByMessageIndex::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(57)))
+        )
+    );
+
+    // "<message_id>:<reporter>" -> report ID, so each person reports a message once
+    static REPORTS_BY_REPORTER: RefCell<ReporterIndex> = RefCell::new(
+        ReporterIndex::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(58)))
+        )
+    );
+
+    // "<viewer>:<conversation_id>" for conversations created by someone the viewer had
+    // already blocked; they stay members, so the creator sees nothing different
+    static HIDDEN_CONVERSATIONS: RefCell<HiddenConversationIndex> = RefCell::new(
+        HiddenConversationIndex::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(59)))
+        )
+    );
+
+    // Typing state is ephemeral: heap only, lost on upgrade by design.
+    // conversation_id -> (user -> expires_at)
+    static TYPING: RefCell<HashMap<String, HashMap<Principal, u64>>> = RefCell::new(HashMap::new());
+}
+
+// === HELPER FUNCTIONS ===
+
+fn generate_next_id() -> u64 {
+    ID_COUNTER.with(|counter| {
+        let mut counter = counter.borrow_mut();
+        let current_id = counter.get(&0).unwrap_or(0);
+        let next_id = current_id + 1;
+        counter.insert(0, next_id);
+        next_id
+    })
+}
+
+// Unique per call, independent of who takes part
+fn generate_conversation_id(creator: &Principal) -> String {
+    loop {
+        let mut hasher = Sha256::new();
+        hasher.update(creator.as_slice());
+        hasher.update(get_time().to_be_bytes());
+        hasher.update(generate_next_id().to_be_bytes());
+        let conversation_id = hex::encode(&hasher.finalize()[..8]);
+
+        if !CONVERSATIONS.with(|conversations| conversations.borrow().contains_key(&conversation_id)) {
+            return conversation_id;
+        }
+    }
+}
+
+// Index key for the one direct conversation between two users
+fn direct_conversation_key(a: &Principal, b: &Principal) -> String {
+    let (a, b) = (a.to_text(), b.to_text());
+    if a <= b {
+        format!("{}:{}", a, b)
+    } else {
+        format!("{}:{}", b, a)
+    }
+}
+
+fn idempotency_store_key(caller: &Principal, key: &str) -> String {
+    format!("{}:{}", caller.to_text(), key)
+}
+
+// Index direct conversations created before the lookup existed
+fn backfill_direct_conversation_index(cursor: Option<String>) -> Option<String> {
+    let batch = conversations_after(cursor);
+
+    DIRECT_CONVERSATIONS.with(|index| {
+        let mut index = index.borrow_mut();
+        for conversation in &batch {
+            if let (ConversationType::DirectMessage, [a, b]) =
+                (&conversation.conversation_type, conversation.participants.as_slice())
+            {
+                index.insert(direct_conversation_key(a, b), conversation.id.clone());
+            }
+        }
+    });
+
+    next_conversation_cursor(&batch)
+}
+
+fn is_participant(conversation: &Conversation, user_id: &Principal) -> bool {
+    conversation.participants.contains(user_id)
+}
+
+fn get_conversation_for_participant(conversation_id: &str, caller: &Principal) -> Result<Conversation, String> {
+    let conversation = CONVERSATIONS
+        .with(|conversations| conversations.borrow().get(&conversation_id.to_string()))
+        .map(Conversation::from)
+        .ok_or_else(|| "Conversation not found".to_string())?;
+
+    if !is_participant(&conversation, caller) {
+        return Err("Unauthorized: Not a participant in this conversation".to_string());
+    }
+
+    Ok(conversation)
+}
+
+fn get_caller() -> Principal {
+    ic_cdk::caller()
+}
+
+fn get_time() -> u64 {
+    time()
+}
+
+fn require_controller(principal: &Principal) -> Result<(), String> {
+    if !ic_cdk::api::is_controller(principal) {
+        return Err("Unauthorized: Caller is not a canister controller".to_string());
+    }
+    Ok(())
+}
+
+fn get_setting(name: &str, default: u64) -> u64 {
+    SETTINGS
+        .with(|settings| settings.borrow().get(&name.to_string()))
+        .unwrap_or(default)
+}
+
+fn set_setting(name: &str, value: u64) {
+    SETTINGS.with(|settings| {
+        settings.borrow_mut().insert(name.to_string(), value);
+    });
+}
+
+fn user_conversation_key(user_id: &Principal, updated_at: u64, conversation_id: &str) -> String {
+    format!("{}:{:020}:{}", user_id.to_text(), updated_at, conversation_id)
+}
+
+fn conversation_id_from_index_key(key: &str) -> Option<String> {
+    key.splitn(3, ':').nth(2).map(|id| id.to_string())
+}
+
+// Write a conversation and move its participants' index entries to the new updated_at
+fn save_conversation(conversation: &Conversation, previous_updated_at: Option<u64>) {
+    CONVERSATIONS.with(|conversations| {
+        conversations.borrow_mut().insert(
+            conversation.id.clone(),
+            StorableConversation::from(conversation.clone()),
+        );
+    });
+
+    USER_CONVERSATIONS.with(|index| {
+        let mut index = index.borrow_mut();
+        for participant in &conversation.participants {
+            if let Some(previous_updated_at) = previous_updated_at {
+                index.remove(&user_conversation_key(participant, previous_updated_at, &conversation.id));
+            }
+            index.insert(user_conversation_key(participant, conversation.updated_at, &conversation.id), ());
+        }
+    });
+}
+
+// Conversation IDs of a user, most recently updated first
+fn user_conversation_ids(user_id: &Principal) -> Vec<String> {
+    let scope = user_id.to_text();
+    let start = format!("{}:", scope);
+    let end = format!("{};", scope); // ';' sorts right after ':'
+
+    USER_CONVERSATIONS.with(|index| {
+        index
+            .borrow()
+            .range(start..end)
+            .rev()
+            .filter_map(|(key, _)| conversation_id_from_index_key(&key))
+            .collect()
+    })
+}
+
+// Index conversations created before the per-user index existed
+fn backfill_user_conversation_index(cursor: Option<String>) -> Option<String> {
+    let batch = conversations_after(cursor);
+
+    for conversation in &batch {
+        save_conversation(conversation, None);
+    }
+
+    next_conversation_cursor(&batch)
+}
+
+// Persist a new message and add it to its conversation's index
+fn store_message(message: &Message) {
+    MESSAGES.with(|messages| {
+        messages.borrow_mut().insert(message.id, StorableMessage::from(message.clone()));
+    });
+
+    CONVERSATION_MESSAGES.with(|index| {
+        index.borrow_mut().insert(scoped_key(&message.conversation_id, message.id), ());
+    });
+
+    if let Some(RetentionPolicy::ExpireAfterDays { days }) = get_retention(&message.conversation_id).map(|r| r.policy) {
+        set_message_expiry(&message.conversation_id, message.id, message.timestamp.saturating_add(days as u64 * NANOS_PER_DAY));
+    }
+}
+
+// Soft-delete a message and take it out of its thread and everyone's unread count
+fn soft_delete_message(mut message: Message) {
+    let was_deleted = message.is_deleted;
+    message.is_deleted = true;
+    MESSAGES.with(|messages| {
+        messages.borrow_mut().insert(message.id, StorableMessage::from(message.clone()));
+    });
+
+    if !was_deleted {
+        if message.reply_to.is_some() {
+            unindex_thread_reply(&message);
+        }
+
+        if let Some(conversation) = CONVERSATIONS.with(|conversations| conversations.borrow().get(&message.conversation_id)) {
+            let counted = Message { is_deleted: false, ..message.clone() };
+            uncount_unread_message(&counted, &conversation.participants);
+        }
+    }
+
+    notify_conversation(&message.conversation_id, SyncEventType::MessageDeleted, Some(message.id));
+}
+
+// Index messages stored before the per-conversation index existed
+fn backfill_conversation_message_index(cursor: Option<String>) -> Option<String> {
+    let batch = messages_after(cursor);
+
+    CONVERSATION_MESSAGES.with(|index| {
+        let mut index = index.borrow_mut();
+        for message in &batch {
+            index.insert(scoped_key(&message.conversation_id, message.id), ());
+        }
+    });
+
+    next_message_cursor(&batch)
+}
+
+// One page of a conversation's stored messages, newest first unless paging forward
+// from after_id. Only this conversation's slice of the index is range-scanned.
+fn conversation_page(
+    conversation_id: &str,
+    before_id: Option<u64>,
+    after_id: Option<u64>,
+    offset: usize,
+    limit: usize,
+    visible: impl Fn(&Message) -> bool,
+) -> Vec<Message> {
+    let start = scoped_key(conversation_id, after_id.map(|id| id.saturating_add(1)).unwrap_or(0));
+    let end = scoped_key(conversation_id, before_id.unwrap_or(u64::MAX));
+    if start >= end {
+        return vec![];
+    }
+
+    CONVERSATION_MESSAGES.with(|index| {
+        MESSAGES.with(|msg_store| {
+            let index_ref = index.borrow();
+            let messages_ref = msg_store.borrow();
+
+            let keys = index_ref.range(start..end).map(|(key, _)| key);
+            let keys: Box<dyn Iterator<Item = String>> = if after_id.is_some() {
+                Box::new(keys)
+            } else {
+                Box::new(keys.rev())
+            };
+
+            keys.filter_map(|key| parse_scoped_id(&key))
+                .filter_map(|message_id| messages_ref.get(&message_id))
+                .map(Message::from)
+                .filter(|message| visible(message))
+                .skip(offset)
+                .take(limit)
+                .collect()
+        })
+    })
+}
+
+// Post a System message into a conversation on behalf of the canister
+fn post_system_message(conversation_id: &str, text: &str) -> Result<Message, String> {
+    let conversation = CONVERSATIONS.with(|conversations| {
+        conversations.borrow().get(&conversation_id.to_string())
+    });
+
+    let conversation = match conversation {
+        Some(conv) => Conversation::from(conv),
+        None => return Err("Conversation not found".to_string()),
+    };
+
+    // System notices are encrypted with the conversation key like any other message
+    let encryption_key = conversation_key(&conversation)?;
+    let encrypted = encrypt_phi_data(text, &encryption_key)?;
+    let encrypted_content = serde_json::to_string(&encrypted)
+        .map_err(|_| "Failed to serialize encrypted content".to_string())?;
+
+    let now = get_time();
+    let message_id = generate_next_id();
+    let canister_id = ic_cdk::id();
+
+    let message = Message {
+        id: message_id,
+        conversation_id: conversation.id.clone(),
+        sender_id: canister_id,
+        recipient_id: canister_id,
+        content: encrypted_content,
+        message_type: MessageType::System,
+        timestamp: now,
+        is_read: false,
+        is_deleted: false,
+        reply_to: None,
+        attachments: Vec::new(),
+        edited_at: None,
+        reactions: Vec::new(),
+        thread: None,
+        plain_text: None,
+    };
+
+    store_message(&message);
+    count_unread_message(&message, &conversation.participants);
+
+    let previous_updated_at = conversation.updated_at;
+    let updated_conversation = Conversation {
+        last_message_id: Some(message_id),
+        updated_at: now,
+        ..conversation
+    };
+
+    record_sync_event(
+        &updated_conversation.participants,
+        SyncEventType::MessageCreated,
+        Some(&updated_conversation.id),
+        Some(message_id),
+        None,
+    );
+
+    save_conversation(&updated_conversation, Some(previous_updated_at));
+
+    Ok(message)
+}
+
+// === MESSAGE EDIT HELPERS ===
+
+fn message_edit_scope(message_id: u64) -> String {
+    format!("{:020}", message_id)
+}
+
+fn get_message_edits(message_id: u64) -> Vec<MessageEdit> {
+    let prefix = format!("{}:", message_edit_scope(message_id));
+
+    MESSAGE_EDITS.with(|edits| {
+        edits
+            .borrow()
+            .range(prefix.clone()..)
+            .take_while(|(key, _)| key.starts_with(&prefix))
+            .map(|(_, storable)| MessageEdit::from(storable))
+            .collect()
+    })
+}
+
+// HMAC over the previous entry's signature and this edit, keyed with the conversation key
+fn sign_message_edit(
+    key: &[u8],
+    previous_signature: &str,
+    message_id: u64,
+    editor: &Principal,
+    edited_at: u64,
+    previous_content: &str,
+) -> Result<String, String> {
+    type HmacSha256 = Hmac<Sha256>;
+    let mut mac = HmacSha256::new_from_slice(key)
+        .map_err(|_| "Invalid key for HMAC".to_string())?;
+    mac.update(previous_signature.as_bytes());
+    mac.update(&message_id.to_be_bytes());
+    mac.update(editor.as_slice());
+    mac.update(&edited_at.to_be_bytes());
+    mac.update(previous_content.as_bytes());
+    Ok(hex::encode(mac.finalize().into_bytes()))
+}
+
+fn verify_message_edit_chain(key: &[u8], edits: &[MessageEdit]) -> bool {
+    let mut previous_signature = String::new();
+
+    for edit in edits {
+        match sign_message_edit(
+            key,
+            &previous_signature,
+            edit.message_id,
+            &edit.editor,
+            edit.edited_at,
+            &edit.previous_content,
+        ) {
+            Ok(signature) if signature == edit.signature => previous_signature = signature,
+            _ => return false,
+        }
+    }
+
+    true
+}
+
+// === REACTION HELPERS ===
+
+fn validate_emoji(emoji: &str) -> Result<(), String> {
+    if emoji.is_empty() || emoji.len() > MAX_EMOJI_LENGTH {
+        return Err("Invalid emoji".to_string());
+    }
+
+    // Emoji are non-ASCII; ASCII is only allowed for keycap sequences like 1️⃣
+    let has_emoji = !emoji.is_ascii();
+    let valid_chars = emoji
+        .chars()
+        .all(|c| !c.is_ascii() || c.is_ascii_digit() || c == '#' || c == '*');
+    if !has_emoji || !valid_chars || emoji.chars().any(|c| c.is_control() || c.is_whitespace()) {
+        return Err("Invalid emoji".to_string());
+    }
+
+    Ok(())
+}
+
+fn reaction_key(message_id: u64, emoji: &str, user_id: &Principal) -> String {
+    format!("{:020}:{}:{}", message_id, emoji, user_id.to_text())
+}
+
+fn get_message_reactions_list(message_id: u64) -> Vec<Reaction> {
+    let prefix = format!("{:020}:", message_id);
+
+    REACTIONS.with(|reactions| {
+        reactions
+            .borrow()
+            .range(prefix.clone()..)
+            .take_while(|(key, _)| key.starts_with(&prefix))
+            .filter_map(|(key, created_at)| {
+                // The principal is the last segment; the emoji sits between the separators
+                let rest = &key[prefix.len()..];
+                let (emoji, user) = rest.rsplit_once(':')?;
+                Some(Reaction {
+                    message_id,
+                    emoji: emoji.to_string(),
+                    user_id: Principal::from_text(user).ok()?,
+                    created_at,
+                })
+            })
+            .collect()
+    })
+}
+
+// Per-emoji counts for a message, in the order emoji were first used
+fn summarize_reactions(message_id: u64, caller: &Principal) -> Vec<ReactionSummary> {
+    let mut reactions = get_message_reactions_list(message_id);
+    reactions.sort_by_key(|reaction| reaction.created_at);
+
+    let mut summaries: Vec<ReactionSummary> = Vec::new();
+    for reaction in reactions {
+        let reacted_by_caller = &reaction.user_id == caller;
+        match summaries.iter_mut().find(|summary| summary.emoji == reaction.emoji) {
+            Some(summary) => {
+                summary.count += 1;
+                summary.reacted_by_caller |= reacted_by_caller;
+            }
+            None => summaries.push(ReactionSummary {
+                emoji: reaction.emoji,
+                count: 1,
+                reacted_by_caller,
+            }),
+        }
+    }
+
+    summaries
+}
+
+// Load a live message and its conversation, checking the caller participates
+fn get_message_for_participant(message_id: u64, caller: &Principal) -> Result<(Message, Conversation), String> {
+    let message = MESSAGES
+        .with(|messages| messages.borrow().get(&message_id))
+        .map(Message::from)
+        .filter(|message| !message.is_deleted)
+        .ok_or_else(|| "Message not found".to_string())?;
+
+    let conversation = CONVERSATIONS
+        .with(|conversations| conversations.borrow().get(&message.conversation_id))
+        .map(Conversation::from)
+        .ok_or_else(|| "Conversation not found".to_string())?;
+
+    if !is_participant(&conversation, caller) {
+        return Err("Unauthorized: Not a participant in this conversation".to_string());
+    }
+
+    Ok((message, conversation))
+}
+
+// === THREAD HELPERS ===
+
+fn thread_scope(root_message_id: u64) -> String {
+    format!("{:020}", root_message_id)
+}
+
+// Follow reply_to links up to the message that started the thread
+fn thread_root_id(message: &Message) -> u64 {
+    follow_reply_chain(message.id, message.reply_to, |parent_id| {
+        MESSAGES
+            .with(|messages| messages.borrow().get(&parent_id))
+            .and_then(|parent_message| parent_message.reply_to)
+    })
+}
+
+// reply_to was not validated before threads existed, so stored chains may loop back on
+// themselves. A message caught in a cycle is treated as its own root.
+fn follow_reply_chain(message_id: u64, reply_to: Option<u64>, parent_of: impl Fn(u64) -> Option<u64>) -> u64 {
+    let mut visited = HashSet::from([message_id]);
+    let mut root_id = message_id;
+    let mut parent = reply_to;
+
+    while let Some(parent_id) = parent {
+        if !visited.insert(parent_id) {
+            return message_id;
+        }
+        root_id = parent_id;
+        parent = parent_of(parent_id);
+    }
+
+    root_id
+}
+
+// A reply must target a live message in the same conversation
+fn validate_reply_to(reply_to: u64, conversation_id: &str) -> Result<(), String> {
+    let parent = MESSAGES
+        .with(|messages| messages.borrow().get(&reply_to))
+        .map(Message::from);
+
+    match parent {
+        Some(parent) if parent.conversation_id != conversation_id => {
+            Err("Replied-to message is not in this conversation".to_string())
+        }
+        Some(parent) if !parent.is_deleted => Ok(()),
+        _ => Err("Replied-to message not found".to_string()),
+    }
+}
+
+fn get_thread_summary(root_message_id: u64) -> Option<ThreadSummary> {
+    THREAD_SUMMARIES
+        .with(|summaries| summaries.borrow().get(&root_message_id))
+        .map(ThreadSummary::from)
+}
+
+fn empty_thread_summary(root_message_id: u64) -> ThreadSummary {
+    ThreadSummary {
+        root_message_id,
+        reply_count: 0,
+        last_reply_id: None,
+        last_reply_at: None,
+    }
+}
+
+fn index_thread_reply(reply: &Message) {
+    let root_message_id = thread_root_id(reply);
+
+    THREAD_REPLIES.with(|index| {
+        index.borrow_mut().insert(scoped_key(&thread_scope(root_message_id), reply.id), ());
+    });
+
+    let mut summary = get_thread_summary(root_message_id).unwrap_or_else(|| empty_thread_summary(root_message_id));
+    summary.reply_count += 1;
+    summary.last_reply_id = Some(reply.id);
+    summary.last_reply_at = Some(reply.timestamp);
+
+    THREAD_SUMMARIES.with(|summaries| {
+        summaries.borrow_mut().insert(root_message_id, StorableThreadSummary::from(summary));
+    });
+}
+
+// Drop a deleted reply from its thread and recompute the last reply
+fn unindex_thread_reply(reply: &Message) {
+    let root_message_id = thread_root_id(reply);
+    let scope = thread_scope(root_message_id);
+
+    THREAD_REPLIES.with(|index| {
+        index.borrow_mut().remove(&scoped_key(&scope, reply.id));
+    });
+
+    let Some(mut summary) = get_thread_summary(root_message_id) else {
+        return;
+    };
+    summary.reply_count = summary.reply_count.saturating_sub(1);
+
+    if summary.last_reply_id == Some(reply.id) {
+        let last_reply = THREAD_REPLIES.with(|index| {
+            index
+                .borrow()
+                .range(scoped_key(&scope, 0)..scoped_key(&scope, u64::MAX))
+                .next_back()
+                .and_then(|(key, _)| parse_scoped_id(&key))
+        });
+        summary.last_reply_id = last_reply;
+        summary.last_reply_at = last_reply
+            .and_then(|reply_id| MESSAGES.with(|messages| messages.borrow().get(&reply_id)))
+            .map(|message| message.timestamp);
+    }
+
+    THREAD_SUMMARIES.with(|summaries| {
+        let mut summaries = summaries.borrow_mut();
+        if summary.reply_count == 0 {
+            summaries.remove(&root_message_id);
+        } else {
+            summaries.insert(root_message_id, StorableThreadSummary::from(summary));
+        }
+    });
+}
+
+// Index replies stored before threads were tracked
+fn backfill_thread_index(cursor: Option<String>) -> Option<String> {
+    let batch = messages_after(cursor);
+
+    for reply in batch.iter().filter(|message| message.reply_to.is_some() && !message.is_deleted) {
+        index_thread_reply(reply);
+    }
+
+    next_message_cursor(&batch)
+}
+
+// Decrypt a stored message and attach the caller's view of reactions and thread state
+fn present_message(message: Message, conversation: &Conversation, caller: &Principal) -> Message {
+    let mut message = decrypt_message_for_participants(message, &conversation_key_participants(conversation));
+    message.reactions = summarize_reactions(message.id, caller);
+    message.thread = get_thread_summary(message.id);
+    message.plain_text = rich_text_plain_text(&message.message_type, &message.content);
+    message
+}
+
+// === MEMBERSHIP HELPERS ===
+
+fn role_key(conversation_id: &str, user_id: &Principal) -> String {
+    format!("{}:{}", conversation_id, user_id.to_text())
+}
+
+fn get_participant_role(conversation_id: &str, user_id: &Principal) -> ParticipantRole {
+    PARTICIPANT_ROLES
+        .with(|roles| roles.borrow().get(&role_key(conversation_id, user_id)))
+        .map(|entry| entry.role)
+        .unwrap_or(ParticipantRole::Member)
+}
+
+fn get_joined_at(conversation: &Conversation, user_id: &Principal) -> u64 {
+    PARTICIPANT_ROLES
+        .with(|roles| roles.borrow().get(&role_key(&conversation.id, user_id)))
+        .map(|entry| entry.joined_at)
+        .unwrap_or(conversation.created_at)
+}
+
+fn set_role_entry(conversation_id: &str, user_id: &Principal, role: ParticipantRole, joined_at: u64) {
+    PARTICIPANT_ROLES.with(|roles| {
+        roles.borrow_mut().insert(
+            role_key(conversation_id, user_id),
+            StorableParticipantRole { role, joined_at },
+        );
+    });
+}
+
+// Check the user takes part in the conversation with at least the given role
+fn require_role(conversation: &Conversation, user_id: &Principal, minimum: ParticipantRole) -> Result<ParticipantRole, String> {
+    if !is_participant(conversation, user_id) {
+        return Err("Unauthorized: Not a participant in this conversation".to_string());
+    }
+
+    let role = get_participant_role(&conversation.id, user_id);
+    if role.rank() < minimum.rank() {
+        return Err(format!("Unauthorized: Requires {:?} role in this conversation", minimum));
+    }
+
+    Ok(role)
+}
+
+// Group admins may remove other people's messages. Roles in a direct conversation only
+// reflect who created it, so neither side can delete the other's messages there.
+fn can_delete_others_messages(conversation_id: &str, user_id: &Principal) -> bool {
+    CONVERSATIONS
+        .with(|conversations| conversations.borrow().get(&conversation_id.to_string()))
+        .map(Conversation::from)
+        .is_some_and(|conversation| {
+            !matches!(conversation.conversation_type, ConversationType::DirectMessage)
+                && require_role(&conversation, user_id, ParticipantRole::Admin).is_ok()
+        })
+}
+
+// Role needed to change privacy settings. DM roles can never change after creation,
+// so both people in a direct conversation may change them.
+fn settings_role(conversation: &Conversation) -> ParticipantRole {
+    match conversation.conversation_type {
+        ConversationType::DirectMessage => ParticipantRole::Member,
+        _ => ParticipantRole::Admin,
+    }
+}
+
+fn conversation_members(conversation: &Conversation) -> Vec<ConversationMember> {
+    conversation
+        .participants
+        .iter()
+        .map(|participant| ConversationMember {
+            user_id: *participant,
+            role: get_participant_role(&conversation.id, participant),
+            joined_at: get_joined_at(conversation, participant),
+        })
+        .collect()
+}
+
+// The key is derived from the founding participants, so history stays readable after membership changes
+fn conversation_key_participants(conversation: &Conversation) -> Vec<Principal> {
+    KEY_PARTICIPANTS
+        .with(|store| store.borrow().get(&conversation.id))
+        .map(|pinned| pinned.participants)
+        .unwrap_or_else(|| conversation.participants.clone())
+}
+
+fn conversation_key(conversation: &Conversation) -> Result<Vec<u8>, String> {
+    derive_conversation_key(&conversation_key_participants(conversation))
+}
+
+fn pin_key_participants(conversation: &Conversation) {
+    KEY_PARTICIPANTS.with(|store| {
+        let mut store = store.borrow_mut();
+        if !store.contains_key(&conversation.id) {
+            store.insert(
+                conversation.id.clone(),
+                StorableKeyParticipants {
+                    participants: conversation.participants.clone(),
+                },
+            );
+        }
+    });
+}
+
+fn require_mutable_membership(conversation: &Conversation) -> Result<(), String> {
+    if matches!(conversation.conversation_type, ConversationType::DirectMessage) {
+        return Err("Direct messages have fixed membership".to_string());
+    }
+    Ok(())
+}
+
+// A conversation with members left always keeps someone who can manage it.
+// remaining is the conversation without the leaver.
+fn require_manager_remains(remaining: &Conversation, leaver: &Principal) -> Result<(), String> {
+    let is_manager = |role: ParticipantRole| role.rank() >= ParticipantRole::Admin.rank();
+    if remaining.participants.is_empty() || !is_manager(get_participant_role(&remaining.id, leaver)) {
+        return Ok(());
+    }
+    if conversation_members(remaining).into_iter().any(|member| is_manager(member.role)) {
+        return Ok(());
+    }
+    Err("The last owner or admin must make someone else an admin before leaving".to_string())
+}
+
+// Persist new membership and bring per-user state in line.
+// previous is the conversation as stored before the change.
+fn save_membership_change(conversation: &Conversation, previous: &Conversation) {
+    let removed: Vec<Principal> = previous
+        .participants
+        .iter()
+        .filter(|participant| !conversation.participants.contains(participant))
+        .copied()
+        .collect();
+    let added: Vec<Principal> = conversation
+        .participants
+        .iter()
+        .filter(|participant| !previous.participants.contains(participant))
+        .copied()
+        .collect();
+
+    USER_CONVERSATIONS.with(|index| {
+        let mut index = index.borrow_mut();
+        for user_id in &removed {
+            index.remove(&user_conversation_key(user_id, previous.updated_at, &conversation.id));
+        }
+    });
+    save_conversation(conversation, Some(previous.updated_at));
+
+    for user_id in &removed {
+        PARTICIPANT_ROLES.with(|roles| roles.borrow_mut().remove(&role_key(&conversation.id, user_id)));
+        UNREAD_COUNTS.with(|counts| counts.borrow_mut().remove(&unread_key(user_id, &conversation.id)));
+        clear_typing(&conversation.id, user_id);
+    }
+
+    // New members can read the history but start with nothing unread
+    if let Some(last_message_id) = conversation.last_message_id {
+        for user_id in &added {
+            advance_watermark(&conversation.id, user_id, last_message_id, last_message_id);
+        }
+    }
+
+    let mut recipients = conversation.participants.clone();
+    recipients.extend(removed);
+    record_sync_event(
+        &recipients,
+        SyncEventType::ConversationUpdated,
+        Some(&conversation.id),
+        None,
+        None,
+    );
+}
+
+// Add a user with the given role and announce it in the conversation
+fn add_member(previous: &Conversation, user_id: &Principal, role: ParticipantRole, announcement: &str) -> Result<Conversation, String> {
+    if is_participant(previous, user_id) {
+        return Err("User is already a participant".to_string());
+    }
+    if previous.participants.len() >= MAX_CONVERSATION_PARTICIPANTS {
+        return Err(format!("Conversation cannot have more than {} participants", MAX_CONVERSATION_PARTICIPANTS));
+    }
+
+    pin_key_participants(previous);
+
+    let now = get_time();
+    let mut conversation = previous.clone();
+    conversation.participants.push(*user_id);
+    conversation.updated_at = now;
+    set_role_entry(&conversation.id, user_id, role, now);
+    save_membership_change(&conversation, previous);
+
+    let message = post_system_message(&conversation.id, announcement)?;
+    conversation.last_message_id = Some(message.id);
+    conversation.updated_at = message.timestamp;
+    Ok(conversation)
+}
+
+// === INVITE HELPERS ===
+
+// Invite tokens are bearer credentials, so they come from the management canister's
+// randomness rather than anything a caller could reconstruct
+async fn generate_invite_token() -> Result<String, String> {
+    let (random_bytes,) = ic_cdk::api::management_canister::main::raw_rand()
+        .await
+        .map_err(|(code, message)| format!("Failed to generate invite token: {:?} {}", code, message))?;
+
+    if random_bytes.len() < 16 {
+        return Err("Failed to generate invite token: not enough randomness".to_string());
+    }
+    Ok(hex::encode(&random_bytes[..16]))
+}
+
+// Who may create an invite granting role; checked again after the randomness call
+// because membership can change while it is in flight
+fn validate_invite_creation(conversation_id: &str, caller: &Principal, role: &ParticipantRole) -> Result<(), String> {
+    let conversation = get_conversation_for_participant(conversation_id, caller)?;
+    require_mutable_membership(&conversation)?;
+    let caller_role = require_role(&conversation, caller, ParticipantRole::Admin)?;
+
+    if *role == ParticipantRole::Owner {
+        return Err("Invites cannot grant ownership".to_string());
+    }
+    if *role == ParticipantRole::Admin && caller_role != ParticipantRole::Owner {
+        return Err("Unauthorized: Only the owner can invite admins".to_string());
+    }
+    Ok(())
+}
+
+fn get_invite(token: &str) -> Option<ConversationInvite> {
+    INVITES
+        .with(|invites| invites.borrow().get(&token.to_string()))
+        .map(ConversationInvite::from)
+}
+
+fn save_invite(invite: &ConversationInvite) {
+    INVITES.with(|invites| {
+        invites.borrow_mut().insert(invite.token.clone(), StorableConversationInvite::from(invite.clone()));
+    });
+}
+
+// Whether the invite can still be redeemed; revoked and used-up invites stay listed
+fn validate_invite_usable(invite: &ConversationInvite, now: u64) -> Result<(), String> {
+    if invite.revoked {
+        return Err("Invite has been revoked".to_string());
+    }
+    if invite.expires_at.is_some_and(|expires_at| expires_at <= now) {
+        return Err("Invite has expired".to_string());
+    }
+    if invite.max_uses.is_some_and(|max_uses| invite.uses >= max_uses) {
+        return Err("Invite has no uses left".to_string());
+    }
+    Ok(())
+}
+
+// Uses are counted when someone is admitted, not when a join request is queued
+fn count_invite_use(mut invite: ConversationInvite) {
+    invite.uses += 1;
+    save_invite(&invite);
+}
+
+fn join_request_key(conversation_id: &str, user_id: &Principal) -> String {
+    format!("{}:{}", conversation_id, user_id.to_text())
+}
+
+// Admins and the owner of a conversation
+fn conversation_admins(conversation: &Conversation) -> Vec<Principal> {
+    conversation_members(conversation)
+        .into_iter()
+        .filter(|member| member.role.rank() >= ParticipantRole::Admin.rank())
+        .map(|member| member.user_id)
+        .collect()
+}
+
+// Give conversations created before roles existed a role for everyone.
+// Their creator is unknown, so every existing participant keeps full control as an admin.
+fn backfill_participant_roles(cursor: Option<String>) -> Option<String> {
+    let batch = conversations_after(cursor);
+
+    for conversation in &batch {
+        for participant in &conversation.participants {
+            let key = role_key(&conversation.id, participant);
+            if !PARTICIPANT_ROLES.with(|roles| roles.borrow().contains_key(&key)) {
+                set_role_entry(&conversation.id, participant, ParticipantRole::Admin, conversation.created_at);
+            }
+        }
+    }
+
+    next_conversation_cursor(&batch)
+}
+
+// === READ RECEIPT HELPERS ===
+
+fn watermark_key(conversation_id: &str, user_id: &Principal) -> String {
+    format!("{}:{}", conversation_id, user_id.to_text())
+}
+
+fn get_stored_watermark(conversation_id: &str, user_id: &Principal) -> StorableReadWatermark {
+    READ_WATERMARKS
+        .with(|watermarks| watermarks.borrow().get(&watermark_key(conversation_id, user_id)))
+        .unwrap_or(StorableReadWatermark {
+            delivered_up_to: 0,
+            read_up_to: 0,
+            updated_at: 0,
+        })
+}
+
+// Move a participant's watermarks forward; they never go back. Reading implies delivery.
+// Returns whether anything changed.
+fn advance_watermark(conversation_id: &str, user_id: &Principal, delivered_up_to: u64, read_up_to: u64) -> bool {
+    let mut watermark = get_stored_watermark(conversation_id, user_id);
+    let delivered_up_to = delivered_up_to.max(read_up_to);
+
+    if delivered_up_to <= watermark.delivered_up_to && read_up_to <= watermark.read_up_to {
+        return false;
+    }
+
+    let read_advanced = read_up_to > watermark.read_up_to;
+    watermark.delivered_up_to = watermark.delivered_up_to.max(delivered_up_to);
+    watermark.read_up_to = watermark.read_up_to.max(read_up_to);
+    watermark.updated_at = get_time();
+
+    READ_WATERMARKS.with(|watermarks| {
+        watermarks.borrow_mut().insert(watermark_key(conversation_id, user_id), watermark);
+    });
+
+    if read_advanced {
+        refresh_unread_count(conversation_id, user_id);
+        expire_read_messages(conversation_id);
+    }
+    true
+}
+
+fn get_conversation_settings_or_default(conversation_id: &str) -> ConversationSettings {
+    let stored = CONVERSATION_SETTINGS
+        .with(|settings| settings.borrow().get(&conversation_id.to_string()));
+
+    ConversationSettings {
+        conversation_id: conversation_id.to_string(),
+        read_receipts_enabled: stored.as_ref().map(|settings| settings.read_receipts_enabled).unwrap_or(true),
+        end_to_end_encrypted: stored.and_then(|settings| settings.end_to_end_encrypted).unwrap_or(false),
+    }
+}
+
+fn save_conversation_settings(settings: &ConversationSettings) {
+    CONVERSATION_SETTINGS.with(|store| {
+        store.borrow_mut().insert(
+            settings.conversation_id.clone(),
+            StorableConversationSettings {
+                read_receipts_enabled: settings.read_receipts_enabled,
+                end_to_end_encrypted: Some(settings.end_to_end_encrypted),
+            },
+        );
+    });
+}
+
+// Tell the others about a read only when the conversation shares read receipts;
+// the reader's own devices are always told
+fn notify_read(conversation: &Conversation, reader: &Principal, message_id: u64) {
+    let recipients = if get_conversation_settings_or_default(&conversation.id).read_receipts_enabled {
+        conversation.participants.clone()
+    } else {
+        vec![*reader]
+    };
+
+    record_sync_event(
+        &recipients,
+        SyncEventType::MessageRead,
+        Some(&conversation.id),
+        Some(message_id),
+        None,
+    );
+}
+
+// A participant's watermark as seen by viewer; read progress of others is hidden when receipts are off
+fn get_read_watermark_view(conversation_id: &str, user_id: &Principal, viewer: &Principal) -> ReadWatermark {
+    let stored = get_stored_watermark(conversation_id, user_id);
+    let show_read = user_id == viewer
+        || get_conversation_settings_or_default(conversation_id).read_receipts_enabled;
+
+    ReadWatermark {
+        user_id: *user_id,
+        conversation_id: conversation_id.to_string(),
+        delivered_up_to: stored.delivered_up_to,
+        read_up_to: if show_read { Some(stored.read_up_to) } else { None },
+        updated_at: stored.updated_at,
+    }
+}
+
+fn conversation_has_message(conversation_id: &str, message_id: u64) -> bool {
+    CONVERSATION_MESSAGES.with(|index| index.borrow().contains_key(&scoped_key(conversation_id, message_id)))
+}
+
+// === BLOCK AND MUTE HELPERS ===
+// Blocking is one-sided and silent: the blocked user's calls succeed as usual,
+// the blocker simply never sees what they send.
+
+fn block_key(blocker: &Principal, blocked: &Principal) -> String {
+    format!("{}:{}", blocker.to_text(), blocked.to_text())
+}
+
+fn is_blocked(blocker: &Principal, blocked: &Principal) -> bool {
+    BLOCKS.with(|blocks| blocks.borrow().contains_key(&block_key(blocker, blocked)))
+}
+
+fn blocked_either_way(a: &Principal, b: &Principal) -> bool {
+    is_blocked(a, b) || is_blocked(b, a)
+}
+
+fn suppressed_by_message_key(message_id: u64, viewer: &Principal) -> String {
+    format!("{:020}:{}", message_id, viewer.to_text())
+}
+
+fn suppress_message_for(viewer: &Principal, message_id: u64) {
+    SUPPRESSED_MESSAGES.with(|index| {
+        index.borrow_mut().insert(scoped_key(&viewer.to_text(), message_id), ());
+    });
+    SUPPRESSED_BY_MESSAGE.with(|index| {
+        index.borrow_mut().insert(suppressed_by_message_key(message_id, viewer), ());
+    });
+}
+
+// Drop every suppression of a message, whoever it was hidden from
+fn clear_message_suppressions(message_id: u64) {
+    let prefix = format!("{:020}:", message_id);
+    let viewers: Vec<String> = SUPPRESSED_BY_MESSAGE.with(|index| {
+        index
+            .borrow()
+            .range(prefix.clone()..)
+            .take_while(|(key, _)| key.starts_with(&prefix))
+            .filter_map(|(key, _)| key.split_once(':').map(|(_, viewer)| viewer.to_string()))
+            .collect()
+    });
+
+    SUPPRESSED_MESSAGES.with(|index| {
+        let mut index = index.borrow_mut();
+        for viewer in &viewers {
+            index.remove(&scoped_key(viewer, message_id));
+        }
+    });
+    SUPPRESSED_BY_MESSAGE.with(|index| remove_keys_with_prefix(&mut index.borrow_mut(), &prefix));
+}
+
+// Build the reverse index for suppressions recorded before it existed
+fn backfill_suppressed_by_message(cursor: Option<String>) -> Option<String> {
+    let start = cursor.map_or(Unbounded, Excluded);
+    let batch: Vec<String> = SUPPRESSED_MESSAGES.with(|index| {
+        index
+            .borrow()
+            .range((start, Unbounded))
+            .take(BACKFILL_BATCH_SIZE)
+            .map(|(key, _)| key)
+            .collect()
+    });
+
+    SUPPRESSED_BY_MESSAGE.with(|index| {
+        let mut index = index.borrow_mut();
+        for key in &batch {
+            if let (Some((viewer, _)), Some(message_id)) = (key.rsplit_once(':'), parse_scoped_id(key)) {
+                index.insert(format!("{:020}:{}", message_id, viewer), ());
+            }
+        }
+    });
+
+    if batch.len() < BACKFILL_BATCH_SIZE {
+        return None;
+    }
+    batch.last().cloned()
+}
+
+fn is_suppressed_for(viewer: &Principal, message_id: u64) -> bool {
+    SUPPRESSED_MESSAGES.with(|index| index.borrow().contains_key(&scoped_key(&viewer.to_text(), message_id)))
+}
+
+// Messages from blocked senders are hidden, and so is anything they sent during a block
+fn is_message_hidden_from(sender_id: &Principal, message_id: u64, viewer: &Principal) -> bool {
+    is_blocked(viewer, sender_id) || is_suppressed_for(viewer, message_id)
+}
+
+fn hidden_conversation_key(viewer: &Principal, conversation_id: &str) -> String {
+    format!("{}:{}", viewer.to_text(), conversation_id)
+}
+
+// Anyone pulled into a new conversation by a user they blocked never sees it
+fn hide_from_blockers(conversation: &Conversation, creator: &Principal) {
+    HIDDEN_CONVERSATIONS.with(|index| {
+        let mut index = index.borrow_mut();
+        for participant in &conversation.participants {
+            if participant != creator && is_blocked(participant, creator) {
+                index.insert(hidden_conversation_key(participant, &conversation.id), ());
+            }
+        }
+    });
+}
+
+fn is_hidden_by_creator_block(viewer: &Principal, conversation_id: &str) -> bool {
+    HIDDEN_CONVERSATIONS.with(|index| index.borrow().contains_key(&hidden_conversation_key(viewer, conversation_id)))
+}
+
+// Direct conversations with a blocked user disappear from the blocker's list, as do
+// conversations a blocked user created with them in it
+fn is_conversation_hidden_from(conversation: &Conversation, viewer: &Principal) -> bool {
+    if is_hidden_by_creator_block(viewer, &conversation.id) {
+        return true;
+    }
+    matches!(conversation.conversation_type, ConversationType::DirectMessage)
+        && conversation
+            .participants
+            .iter()
+            .any(|participant| participant != viewer && is_blocked(viewer, participant))
+}
+
+fn mute_key(user_id: &Principal, conversation_id: &str) -> String {
+    format!("{}:{}", user_id.to_text(), conversation_id)
+}
+
+// Mute expiry if the conversation is muted right now
+fn active_mute(user_id: &Principal, conversation_id: &str, now: u64) -> Option<u64> {
+    MUTES
+        .with(|mutes| mutes.borrow().get(&mute_key(user_id, conversation_id)))
+        .filter(|muted_until| *muted_until > now)
+}
+
+// Blocking hides the sender's unread messages from the user, unblocking shows the ones
+// not sent during the block again. Only the sender's messages past the user's read
+// watermark are visited. Call after the block has been added or removed.
+fn adjust_unread_counts_for_block(user_id: &Principal, sender: &Principal, blocked: bool) {
+    for conversation_id in user_conversation_ids(user_id) {
+        let shared = CONVERSATIONS
+            .with(|conversations| conversations.borrow().get(&conversation_id))
+            .is_some_and(|conversation| conversation.participants.contains(sender));
+        if !shared {
+            continue;
+        }
+
+        let read_up_to = get_stored_watermark(&conversation_id, user_id).read_up_to;
+        let affected = unread_message_ids(&conversation_id, read_up_to, |message| {
+            message.sender_id == *sender
+                && is_unread_candidate(message, user_id, read_up_to)
+                && !is_suppressed_for(user_id, message.id)
+        });
+        let Some(first_affected) = affected.first().copied() else {
+            continue;
+        };
+
+        let key = unread_key(user_id, &conversation_id);
+        let mut unread = UNREAD_COUNTS.with(|counts| counts.borrow().get(&key)).unwrap_or(StorableUnreadCount {
+            count: 0,
+            first_unread_id: None,
+        });
+        if blocked {
+            unread.count = unread.count.saturating_sub(affected.len() as u64);
+            if unread.first_unread_id.is_some_and(|id| affected.contains(&id)) {
+                unread.first_unread_id = first_unread_after(&conversation_id, user_id, first_affected);
+            }
+        } else {
+            unread.count += affected.len() as u64;
+            unread.first_unread_id = Some(unread.first_unread_id.map_or(first_affected, |id| id.min(first_affected)));
+        }
+        save_unread_count(key, unread);
+    }
+}
+
+// === UNREAD COUNT HELPERS ===
+
+fn unread_key(user_id: &Principal, conversation_id: &str) -> String {
+    format!("{}:{}", user_id.to_text(), conversation_id)
+}
+
+// A new message is unread for everyone but its sender
+fn count_unread_message(message: &Message, participants: &[Principal]) {
+    UNREAD_COUNTS.with(|counts| {
+        let mut counts = counts.borrow_mut();
+        for participant in participants.iter().filter(|p| **p != message.sender_id) {
+            if is_message_hidden_from(&message.sender_id, message.id, participant) {
+                continue;
+            }
+            let key = unread_key(participant, &message.conversation_id);
+            let mut unread = counts.get(&key).unwrap_or(StorableUnreadCount {
+                count: 0,
+                first_unread_id: None,
+            });
+            unread.count += 1;
+            unread.first_unread_id.get_or_insert(message.id);
+            counts.insert(key, unread);
+        }
+    });
+}
+
+// Everything about a message that makes it unread for the user, except whether it is hidden
+fn is_unread_candidate(message: &StorableMessage, user_id: &Principal, read_up_to: u64) -> bool {
+    let read_directly = message.is_read && message.recipient_id == *user_id;
+    message.id > read_up_to && !message.is_deleted && message.sender_id != *user_id && !read_directly
+}
+
+fn counts_as_unread(message: &StorableMessage, user_id: &Principal, read_up_to: u64) -> bool {
+    is_unread_candidate(message, user_id, read_up_to)
+        && !is_message_hidden_from(&message.sender_id, message.id, user_id)
+}
+
+// IDs of the conversation's messages after `after_id` that match, oldest first
+fn unread_message_ids(conversation_id: &str, after_id: u64, matches: impl Fn(&StorableMessage) -> bool) -> Vec<u64> {
+    let start = scoped_key(conversation_id, after_id.saturating_add(1));
+    let end = format!("{};", conversation_id); // ';' sorts right after ':'
+
+    CONVERSATION_MESSAGES.with(|index| {
+        MESSAGES.with(|messages| {
+            let messages = messages.borrow();
+            index
+                .borrow()
+                .range(start..end)
+                .filter_map(|(key, _)| parse_scoped_id(&key))
+                .filter(|message_id| messages.get(message_id).is_some_and(|message| matches(&message)))
+                .collect()
+        })
+    })
+}
+
+// The user's first unread message after `after_id`; the walk stops at the first match
+fn first_unread_after(conversation_id: &str, user_id: &Principal, after_id: u64) -> Option<u64> {
+    let read_up_to = get_stored_watermark(conversation_id, user_id).read_up_to;
+    let start = scoped_key(conversation_id, after_id.max(read_up_to).saturating_add(1));
+    let end = format!("{};", conversation_id);
+
+    CONVERSATION_MESSAGES.with(|index| {
+        MESSAGES.with(|messages| {
+            let messages = messages.borrow();
+            index
+                .borrow()
+                .range(start..end)
+                .filter_map(|(key, _)| parse_scoped_id(&key))
+                .find(|message_id| {
+                    messages
+                        .get(message_id)
+                        .is_some_and(|message| counts_as_unread(&message, user_id, read_up_to))
+                })
+        })
+    })
+}
+
+fn save_unread_count(key: String, unread: StorableUnreadCount) {
+    UNREAD_COUNTS.with(|counts| {
+        let mut counts = counts.borrow_mut();
+        if unread.count == 0 {
+            counts.remove(&key);
+        } else {
+            counts.insert(key, unread);
+        }
+    });
+}
+
+// Take a message that is going away out of the counts of everyone it was unread for.
+// Pass the message as it was while still visible.
+fn uncount_unread_message(message: &Message, participants: &[Principal]) {
+    let counted = StorableMessage::from(message.clone());
+    for participant in participants {
+        let read_up_to = get_stored_watermark(&message.conversation_id, participant).read_up_to;
+        if !counts_as_unread(&counted, participant, read_up_to) {
+            continue;
+        }
+
+        let key = unread_key(participant, &message.conversation_id);
+        let Some(mut unread) = UNREAD_COUNTS.with(|counts| counts.borrow().get(&key)) else {
+            continue;
+        };
+        unread.count = unread.count.saturating_sub(1);
+        if unread.first_unread_id == Some(message.id) {
+            unread.first_unread_id = first_unread_after(&message.conversation_id, participant, message.id);
+        }
+        save_unread_count(key, unread);
+    }
+}
+
+// Recount what a user has not read past their read watermark.
+// Called when the watermark moves, so the scan only covers what is still unread.
+fn refresh_unread_count(conversation_id: &str, user_id: &Principal) {
+    let read_up_to = get_stored_watermark(conversation_id, user_id).read_up_to;
+    let unread_ids = unread_message_ids(conversation_id, read_up_to, |message| {
+        counts_as_unread(message, user_id, read_up_to)
+    });
+
+    save_unread_count(
+        unread_key(user_id, conversation_id),
+        StorableUnreadCount {
+            count: unread_ids.len() as u64,
+            first_unread_id: unread_ids.first().copied(),
+        },
+    );
+}
+
+// Build counters for messages stored before unread counts were tracked
+fn backfill_unread_counts(cursor: Option<String>) -> Option<String> {
+    let batch = conversations_after(cursor);
+
+    for conversation in &batch {
+        for participant in &conversation.participants {
+            refresh_unread_count(&conversation.id, participant);
+        }
+    }
+
+    next_conversation_cursor(&batch)
+}
+
+// === RETENTION HELPERS ===
+
+fn get_retention(conversation_id: &str) -> Option<StorableRetention> {
+    RETENTION_POLICIES.with(|policies| policies.borrow().get(&conversation_id.to_string()))
+}
+
+fn purge_queue_key(expire_at: u64, message_id: u64) -> String {
+    format!("{:020}:{:020}", expire_at, message_id)
+}
+
+fn clear_message_expiry(message_id: u64) {
+    if let Some(expire_at) = MESSAGE_EXPIRY.with(|expiry| expiry.borrow_mut().remove(&message_id)) {
+        PURGE_QUEUE.with(|queue| queue.borrow_mut().remove(&purge_queue_key(expire_at, message_id)));
+    }
+}
+
+fn set_message_expiry(conversation_id: &str, message_id: u64, expire_at: u64) {
+    clear_message_expiry(message_id);
+    MESSAGE_EXPIRY.with(|expiry| expiry.borrow_mut().insert(message_id, expire_at));
+    PURGE_QUEUE.with(|queue| {
+        queue.borrow_mut().insert(purge_queue_key(expire_at, message_id), conversation_id.to_string())
+    });
+}
+
+fn conversation_message_ids(conversation_id: &str, after_id: u64, up_to: u64) -> Vec<u64> {
+    let start = scoped_key(conversation_id, after_id.saturating_add(1));
+    let end = scoped_key(conversation_id, up_to);
+
+    CONVERSATION_MESSAGES.with(|index| {
+        index
+            .borrow()
+            .range(start..=end)
+            .filter_map(|(key, _)| parse_scoped_id(&key))
+            .collect()
+    })
+}
+
+// Newest message every current participant has read
+fn read_by_all(conversation_id: &str) -> u64 {
+    CONVERSATIONS
+        .with(|conversations| conversations.borrow().get(&conversation_id.to_string()))
+        .and_then(|conversation| {
+            conversation
+                .participants
+                .iter()
+                .map(|participant| get_stored_watermark(conversation_id, participant).read_up_to)
+                .min()
+        })
+        .unwrap_or(0)
+}
+
+// Disappear-after-read: once everyone has read up to a message, its countdown starts.
+// Senders' watermarks move past their own messages when they send, so the lowest
+// watermark is the newest message everyone has read.
+fn expire_read_messages(conversation_id: &str) {
+    let Some(mut retention) = get_retention(conversation_id) else {
+        return;
+    };
+    let RetentionPolicy::DisappearAfterRead { after_ns } = retention.policy else {
+        return;
+    };
+    let read_by_all = read_by_all(conversation_id);
+    if read_by_all <= retention.read_expiry_through {
+        return;
+    }
+
+    let expire_at = get_time().saturating_add(after_ns);
+    for message_id in conversation_message_ids(conversation_id, retention.read_expiry_through, read_by_all) {
+        set_message_expiry(conversation_id, message_id, expire_at);
+    }
+
+    retention.read_expiry_through = read_by_all;
+    RETENTION_POLICIES.with(|policies| {
+        policies.borrow_mut().insert(conversation_id.to_string(), retention);
+    });
+}
+
+// Queue a policy change so the purge timer re-derives the expiry of every message
+// already in the conversation. Newer messages get theirs when sent or read.
+fn apply_retention_policy(conversation_id: &str) {
+    let newest = conversation_message_ids(conversation_id, 0, u64::MAX).last().copied();
+    match newest {
+        Some(newest) => RETENTION_REAPPLY.with(|queue| queue.borrow_mut().insert(conversation_id.to_string(), (0, newest))),
+        None => RETENTION_REAPPLY.with(|queue| queue.borrow_mut().remove(&conversation_id.to_string())),
+    };
+}
+
+// Re-derive the expiry of up to `budget` queued messages; returns how many were handled
+fn reapply_retention_batch(budget: usize) -> usize {
+    let Some((conversation_id, (done_through, up_to))) =
+        RETENTION_REAPPLY.with(|queue| queue.borrow().iter().next())
+    else {
+        return 0;
+    };
+
+    let start = scoped_key(&conversation_id, done_through.saturating_add(1));
+    let end = scoped_key(&conversation_id, up_to);
+    let batch: Vec<u64> = CONVERSATION_MESSAGES.with(|index| {
+        index
+            .borrow()
+            .range(start..=end)
+            .take(budget)
+            .filter_map(|(key, _)| parse_scoped_id(&key))
+            .collect()
+    });
+
+    let retention = get_retention(&conversation_id);
+    let now = get_time();
+    for message_id in &batch {
+        clear_message_expiry(*message_id);
+        match retention.as_ref().map(|retention| &retention.policy) {
+            None | Some(RetentionPolicy::KeepForever) => {}
+            Some(RetentionPolicy::ExpireAfterDays { days }) => {
+                if let Some(message) = MESSAGES.with(|messages| messages.borrow().get(message_id)) {
+                    let expire_at = message.timestamp.saturating_add(*days as u64 * NANOS_PER_DAY);
+                    set_message_expiry(&conversation_id, *message_id, expire_at);
+                }
+            }
+            Some(RetentionPolicy::DisappearAfterRead { after_ns }) => {
+                let read_through = retention.as_ref().map_or(0, |retention| retention.read_expiry_through);
+                if *message_id <= read_through {
+                    set_message_expiry(&conversation_id, *message_id, now.saturating_add(*after_ns));
+                }
+            }
+        }
+    }
+
+    RETENTION_REAPPLY.with(|queue| {
+        let mut queue = queue.borrow_mut();
+        match batch.last() {
+            Some(last) if batch.len() == budget && *last < up_to => {
+                queue.insert(conversation_id, (*last, up_to));
+            }
+            _ => {
+                queue.remove(&conversation_id);
+            }
+        }
+    });
+
+    batch.len()
+}
+
+fn describe_retention_delay(duration_ns: u64) -> String {
+    let seconds = duration_ns / 1_000_000_000;
+    let (amount, unit) = if seconds >= 86400 && seconds.is_multiple_of(86400) {
+        (seconds / 86400, "day")
+    } else if seconds >= 3600 && seconds.is_multiple_of(3600) {
+        (seconds / 3600, "hour")
+    } else if seconds >= 60 && seconds.is_multiple_of(60) {
+        (seconds / 60, "minute")
+    } else {
+        (seconds, "second")
+    };
+
+    format!("{} {}{}", amount, unit, if amount == 1 { "" } else { "s" })
+}
+
+fn retention_notice(policy: &RetentionPolicy) -> String {
+    match policy {
+        RetentionPolicy::KeepForever => "Messages in this conversation are now kept until they are deleted.".to_string(),
+        RetentionPolicy::ExpireAfterDays { days } => format!(
+            "Messages in this conversation now disappear {} after they are sent.",
+            describe_retention_delay(*days as u64 * NANOS_PER_DAY)
+        ),
+        RetentionPolicy::DisappearAfterRead { after_ns } => format!(
+            "Messages in this conversation now disappear {} after everyone has read them.",
+            describe_retention_delay(*after_ns)
+        ),
+    }
+}
+
+fn remove_keys_with_prefix<V: Storable>(store: &mut StableBTreeMap<String, V, Memory>, prefix: &str) {
+    let keys: Vec<String> = store
+        .range(prefix.to_string()..)
+        .take_while(|(key, _)| key.starts_with(prefix))
+        .map(|(key, _)| key)
+        .collect();
+    for key in keys {
+        store.remove(&key);
+    }
+}
+
+// Physically remove a message with its attachments, edit history, reactions and
+// every index entry pointing at it
+fn purge_message(message_id: u64) -> Option<Message> {
+    clear_message_expiry(message_id);
+
+    let message = MESSAGES
+        .with(|messages| messages.borrow_mut().remove(&message_id))
+        .map(Message::from)?;
+
+    CONVERSATION_MESSAGES.with(|index| {
+        index.borrow_mut().remove(&scoped_key(&message.conversation_id, message_id))
+    });
+
+    if message.reply_to.is_some() && !message.is_deleted {
+        unindex_thread_reply(&message);
+    }
+    THREAD_SUMMARIES.with(|summaries| summaries.borrow_mut().remove(&message_id));
+    THREAD_REPLIES.with(|index| {
+        remove_keys_with_prefix(&mut index.borrow_mut(), &format!("{}:", thread_scope(message_id)))
+    });
+
+    let message_prefix = format!("{:020}:", message_id);
+    MESSAGE_EDITS.with(|edits| remove_keys_with_prefix(&mut edits.borrow_mut(), &message_prefix));
+    REACTIONS.with(|reactions| remove_keys_with_prefix(&mut reactions.borrow_mut(), &message_prefix));
+
+    if !message.is_deleted {
+        if let Some(conversation) = CONVERSATIONS.with(|conversations| conversations.borrow().get(&message.conversation_id)) {
+            uncount_unread_message(&message, &conversation.participants);
+        }
+    }
+    clear_message_suppressions(message_id);
+    CRISIS_FLAGS.with(|flags| flags.borrow_mut().remove(&message_id));
+
+    Some(message)
+}
+
+// Purge timer: finish re-deriving expiries after policy changes, then remove every
+// message whose retention has run out, a batch at a time
+fn purge_expired_messages() {
+    let mut budget = MAX_PURGE_BATCH;
+    while budget > 0 && RETENTION_REAPPLY.with(|queue| !queue.borrow().is_empty()) {
+        // An entry with nothing left to re-derive still costs one unit, so the loop ends
+        budget = budget.saturating_sub(reapply_retention_batch(budget).max(1));
+    }
+
+    let due: Vec<u64> = PURGE_QUEUE.with(|queue| {
+        queue
+            .borrow()
+            .range(..format!("{:020};", get_time()))
+            .take(MAX_PURGE_BATCH)
+            .filter_map(|(key, _)| parse_scoped_id(&key))
+            .collect()
+    });
+
+    let mut purged_by_conversation: HashMap<String, Vec<u64>> = HashMap::new();
+    for message_id in due {
+        match purge_message(message_id) {
+            Some(message) => purged_by_conversation
+                .entry(message.conversation_id)
+                .or_default()
+                .push(message_id),
+            None => clear_message_expiry(message_id),
+        }
+    }
+
+    for (conversation_id, purged) in purged_by_conversation {
+        let Some(mut conversation) = CONVERSATIONS
+            .with(|conversations| conversations.borrow().get(&conversation_id))
+            .map(Conversation::from)
+        else {
+            continue;
+        };
+
+        if conversation.last_message_id.is_some_and(|id| purged.contains(&id)) {
+            conversation.last_message_id = conversation_message_ids(&conversation_id, 0, u64::MAX).last().copied();
+            save_conversation(&conversation, Some(conversation.updated_at));
+        }
+
+        for message_id in purged {
+            notify_conversation(&conversation_id, SyncEventType::MessageDeleted, Some(message_id));
+        }
+    }
+}
+
+fn start_retention_purge_timer() {
+    ic_cdk_timers::set_timer_interval(Duration::from_nanos(RETENTION_PURGE_INTERVAL_NS), purge_expired_messages);
+}
+
+// === SCHEDULED MESSAGE HELPERS ===
+
+fn schedule_queue_key(deliver_at: u64, id: u64) -> String {
+    format!("{:020}:{:020}", deliver_at, id)
+}
+
+fn scheduled_sender_key(sender: &Principal, id: u64) -> String {
+    scoped_key(&sender.to_text(), id)
+}
+
+fn get_scheduled_message(id: u64) -> Option<ScheduledMessage> {
+    SCHEDULED_MESSAGES
+        .with(|scheduled| scheduled.borrow().get(&id))
+        .map(ScheduledMessage::from)
+}
+
+fn save_scheduled_message(scheduled: &ScheduledMessage) {
+    SCHEDULED_MESSAGES.with(|store| {
+        store.borrow_mut().insert(scheduled.id, StorableScheduledMessage::from(scheduled.clone()));
+    });
+    SCHEDULED_BY_SENDER.with(|index| {
+        index.borrow_mut().insert(scheduled_sender_key(&scheduled.sender_id, scheduled.id), ());
+    });
+    SCHEDULE_QUEUE.with(|queue| {
+        let key = schedule_queue_key(scheduled.deliver_at, scheduled.id);
+        if scheduled.status == ScheduledMessageStatus::Pending {
+            queue.borrow_mut().insert(key, ());
+        } else {
+            queue.borrow_mut().remove(&key);
+        }
+    });
+}
+
+fn remove_scheduled_message(scheduled: &ScheduledMessage) {
+    SCHEDULED_MESSAGES.with(|store| store.borrow_mut().remove(&scheduled.id));
+    SCHEDULED_BY_SENDER.with(|index| {
+        index.borrow_mut().remove(&scheduled_sender_key(&scheduled.sender_id, scheduled.id))
+    });
+    SCHEDULE_QUEUE.with(|queue| {
+        queue.borrow_mut().remove(&schedule_queue_key(scheduled.deliver_at, scheduled.id))
+    });
+}
+
+fn scheduled_message_ids_for(sender: &Principal) -> Vec<u64> {
+    let scope = sender.to_text();
+    let start = format!("{}:", scope);
+    let end = format!("{};", scope);
+
+    SCHEDULED_BY_SENDER.with(|index| {
+        index
+            .borrow()
+            .range(start..end)
+            .filter_map(|(key, _)| key.rsplit(':').next().and_then(|id| id.parse().ok()))
+            .collect()
+    })
+}
+
+// Scheduled messages go to whoever a plain send would address: the peer in a DM,
+// and the first other participant in groups, where recipient_id is informational
+fn default_recipient(conversation: &Conversation, sender: &Principal) -> Principal {
+    conversation
+        .participants
+        .iter()
+        .find(|participant| *participant != sender)
+        .copied()
+        .unwrap_or(*sender)
+}
+
+// Deliver a due scheduled message through the same path as send_message. Rejections
+// are kept on the record so the sender can see why nothing was sent.
+fn deliver_scheduled_message(id: u64) {
+    let mut scheduled = match get_scheduled_message(id) {
+        Some(scheduled) if scheduled.status == ScheduledMessageStatus::Pending => scheduled,
+        _ => return, // cancelled or already handled
+    };
+
+    let result = CONVERSATIONS
+        .with(|conversations| conversations.borrow().get(&scheduled.conversation_id))
+        .map(Conversation::from)
+        .ok_or_else(|| "Conversation not found".to_string())
+        .and_then(|conversation| {
+            let content = decrypt_message_content(&scheduled.content, &conversation_key_participants(&conversation))?;
+            deliver_message(
+                scheduled.sender_id,
+                scheduled.conversation_id.clone(),
+                default_recipient(&conversation, &scheduled.sender_id),
+                content,
+                scheduled.message_type.clone(),
+                None,
+                Vec::new(),
+            )
+        });
+
+    match result {
+        Ok(_) => remove_scheduled_message(&scheduled),
+        Err(e) => {
+            scheduled.status = ScheduledMessageStatus::Failed;
+            scheduled.failure_reason = Some(e);
+            save_scheduled_message(&scheduled);
+        }
+    }
+}
+
+fn schedule_delivery_timer(id: u64, delay_ns: u64) {
+    ic_cdk_timers::set_timer(Duration::from_nanos(delay_ns), move || {
+        deliver_scheduled_message(id);
+    });
+}
+
+// Timers do not survive upgrades, so re-arm one for every pending scheduled message;
+// anything that fell due during the upgrade is delivered right away
+fn restore_scheduled_message_timers() {
+    let now = get_time();
+    let pending: Vec<(u64, u64)> = SCHEDULE_QUEUE.with(|queue| {
+        queue
+            .borrow()
+            .iter()
+            .filter_map(|(key, _)| {
+                let (deliver_at, id) = key.split_once(':')?;
+                Some((deliver_at.parse().ok()?, id.parse().ok()?))
+            })
+            .collect()
+    });
+
+    for (deliver_at, id) in pending {
+        schedule_delivery_timer(id, deliver_at.saturating_sub(now));
+    }
+}
+
+// === MODERATION HELPERS ===
+
+// Moderators appointed by controllers; controllers can always moderate
+fn require_moderator(principal: &Principal) -> Result<(), String> {
+    let appointed = MODERATORS.with(|moderators| moderators.borrow().contains_key(principal));
+    if !appointed && !ic_cdk::api::is_controller(principal) {
+        return Err("Unauthorized: Caller is not a moderator".to_string());
+    }
+    Ok(())
+}
+
+// Escrowed report content is kept encrypted under a canister-wide key, not the conversation's.
+// Like conversation keys, it is derived from public data only (the canister ID): it keeps
+// escrowed copies apart from conversation ciphertext but is not a secret by itself.
+fn moderation_escrow_key() -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(ic_cdk::id().as_slice());
+    hasher.update(b"mentalverse_moderation_escrow_v1");
+    hasher.finalize().to_vec()
+}
+
+fn get_report_record(report_id: u64) -> Option<MessageReport> {
+    REPORTS
+        .with(|reports| reports.borrow().get(&report_id))
+        .map(MessageReport::from)
+}
+
+fn reporter_key(message_id: u64, reporter: &Principal) -> String {
+    format!("{}:{}", message_id, reporter.to_text())
+}
+
+fn save_report_record(report: &MessageReport) {
+    REPORTS.with(|reports| {
+        reports.borrow_mut().insert(report.id, StorableMessageReport::from(report.clone()));
+    });
+    REPORTS_BY_REPORTER.with(|index| {
+        index.borrow_mut().insert(reporter_key(report.message_id, &report.reporter), report.id);
+    });
+
+    OPEN_REPORTS.with(|open| {
+        let mut open = open.borrow_mut();
+        if report.status == ReportStatus::Open {
+            open.insert(report.id, ());
+        } else {
+            open.remove(&report.id);
+        }
+    });
+}
+
+// Index reports filed before duplicates were looked up by reporter
+fn backfill_reporter_index(cursor: Option<String>) -> Option<String> {
+    let start = match cursor.and_then(|cursor| cursor.parse::<u64>().ok()) {
+        Some(report_id) => Excluded(report_id),
+        None => Unbounded,
+    };
+    let batch: Vec<MessageReport> = REPORTS.with(|reports| {
+        reports
+            .borrow()
+            .range((start, Unbounded))
+            .take(BACKFILL_BATCH_SIZE)
+            .map(|(_, storable)| MessageReport::from(storable))
+            .collect()
+    });
+
+    REPORTS_BY_REPORTER.with(|index| {
+        let mut index = index.borrow_mut();
+        for report in &batch {
+            index.insert(reporter_key(report.message_id, &report.reporter), report.id);
+        }
+    });
+
+    if batch.len() < BACKFILL_BATCH_SIZE {
+        return None;
+    }
+    batch.last().map(|report| report.id.to_string())
+}
+
+// A report as moderators see it, with the escrowed content decrypted
+fn report_for_moderator(mut report: MessageReport) -> MessageReport {
+    report.escrowed_content = report.escrowed_content.and_then(|escrowed| {
+        serde_json::from_str::<EncryptedData>(&escrowed)
+            .ok()
+            .and_then(|encrypted| decrypt_phi_data(&encrypted, &moderation_escrow_key()).ok())
+    });
+    report
+}
+
+fn get_account_standing(user_id: &Principal) -> AccountStanding {
+    let stored = ACCOUNT_STANDING.with(|standing| standing.borrow().get(user_id));
+
+    AccountStanding {
+        warning_count: stored.as_ref().map(|s| s.warning_count).unwrap_or(0),
+        last_warning_at: stored.as_ref().and_then(|s| s.last_warning_at),
+        suspended_until: stored.and_then(|s| s.suspended_until),
+    }
+}
+
+fn save_account_standing(user_id: &Principal, standing: &AccountStanding) {
+    ACCOUNT_STANDING.with(|store| {
+        store.borrow_mut().insert(
+            *user_id,
+            StorableAccountStanding {
+                warning_count: standing.warning_count,
+                last_warning_at: standing.last_warning_at,
+                suspended_until: standing.suspended_until,
+            },
+        );
+    });
+}
+
+// Suspended principals can still read but not post
+fn require_not_suspended(user_id: &Principal) -> Result<(), String> {
+    match get_account_standing(user_id).suspended_until {
+        Some(suspended_until) if suspended_until > get_time() => Err(format!(
+            "Account is suspended from messaging until {}",
+            suspended_until
+        )),
+        _ => Ok(()),
+    }
+}
+
+fn record_moderation_decision(
+    moderator: Principal,
+    action: ModerationAction,
+    target_user: Principal,
+    report_id: Option<u64>,
+    message_id: Option<u64>,
+    note: Option<String>,
+) -> ModerationAuditEntry {
+    let entry = ModerationAuditEntry {
+        id: generate_next_id(),
+        moderator,
+        action,
+        target_user,
+        report_id,
+        message_id,
+        note,
+        created_at: get_time(),
+    };
+
+    MODERATION_AUDIT.with(|audit| {
+        audit.borrow_mut().insert(entry.id, StorableModerationAuditEntry::from(entry.clone()));
+    });
+    entry
+}
+
+// Apply a moderation action to a user and, for message removal, the message
+fn apply_moderation_action(action: &ModerationAction, target_user: &Principal, message_id: Option<u64>) -> Result<(), String> {
+    let now = get_time();
+
+    match action {
+        ModerationAction::Dismiss => {}
+        ModerationAction::Warn => {
+            let mut standing = get_account_standing(target_user);
+            standing.warning_count += 1;
+            standing.last_warning_at = Some(now);
+            save_account_standing(target_user, &standing);
+        }
+        ModerationAction::RemoveMessage => {
+            let message = message_id
+                .and_then(|message_id| MESSAGES.with(|messages| messages.borrow().get(&message_id)))
+                .map(Message::from)
+                .ok_or_else(|| "Message not found".to_string())?;
+            soft_delete_message(message);
+        }
+        ModerationAction::Suspend { duration_ns } => {
+            if *duration_ns == 0 {
+                return Err("Suspension duration must be greater than zero".to_string());
+            }
+            let mut standing = get_account_standing(target_user);
+            standing.suspended_until = Some(now.saturating_add(*duration_ns));
+            save_account_standing(target_user, &standing);
+        }
+        ModerationAction::LiftSuspension => {
+            let mut standing = get_account_standing(target_user);
+            standing.suspended_until = None;
+            save_account_standing(target_user, &standing);
+        }
+    }
+
+    Ok(())
+}
+
+// === CRISIS ESCALATION HELPERS ===
+
+fn require_on_call_clinician(principal: &Principal) -> Result<(), String> {
+    let on_call = ON_CALL_CLINICIANS.with(|clinicians| clinicians.borrow().contains_key(principal));
+    if !on_call && !ic_cdk::api::is_controller(principal) {
+        return Err("Unauthorized: Caller is not an on-call clinician".to_string());
+    }
+    Ok(())
+}
+
+// Lowercase words separated by single spaces, so matching ignores case and punctuation
+// in any script that separates words with spaces or punctuation
+fn normalize_crisis_text(text: &str) -> String {
+    let folded: String = text
+        .nfc()
+        .flat_map(char::to_lowercase)
+        .map(|c| if c.is_alphanumeric() || is_unspaced_script(c) { c } else { ' ' })
+        .collect();
+    folded.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+// Scripts written without spaces between words (Han, kana, Thai, Lao, Khmer, Myanmar)
+fn is_unspaced_script(c: char) -> bool {
+    matches!(c,
+        '\u{3040}'..='\u{30FF}'     // Hiragana, Katakana
+        | '\u{3400}'..='\u{4DBF}'   // CJK Extension A
+        | '\u{4E00}'..='\u{9FFF}'   // CJK Unified Ideographs
+        | '\u{F900}'..='\u{FAFF}'   // CJK Compatibility Ideographs
+        | '\u{FF66}'..='\u{FF9F}'   // Halfwidth Katakana
+        | '\u{0E00}'..='\u{0EFF}'   // Thai, Lao
+        | '\u{1000}'..='\u{109F}'   // Myanmar
+        | '\u{1780}'..='\u{17FF}'   // Khmer
+        | '\u{20000}'..='\u{2FA1F}' // CJK Extensions B and up
+    )
+}
+
+// Phrases in scripts without word spaces match anywhere in the text; everything else
+// must match whole words. `haystack` is normalized text padded with a space on each side.
+fn crisis_phrase_matches(haystack: &str, phrase: &str) -> bool {
+    if phrase.chars().any(is_unspaced_script) {
+        haystack.contains(phrase)
+    } else {
+        haystack.contains(&format!(" {} ", phrase))
+    }
+}
+
+fn crisis_term_key(language: &str, phrase: &str) -> String {
+    format!("{}:{}", language, phrase)
+}
+
+fn validate_crisis_language(language: &str) -> Result<(), String> {
+    if language.is_empty()
+        || language.len() > 8
+        || !language.chars().all(|c| c.is_ascii_lowercase() || c == '-')
+    {
+        return Err("Language must be a lowercase language tag such as \"en\"".to_string());
+    }
+    Ok(())
+}
+
+fn seed_crisis_lexicon() {
+    if get_setting(SETTING_CRISIS_LEXICON_SEEDED, 0) == 1 {
+        return;
+    }
+
+    let now = get_time();
+    CRISIS_LEXICON.with(|lexicon| {
+        let mut lexicon = lexicon.borrow_mut();
+        for (language, phrase, severity) in DEFAULT_CRISIS_LEXICON {
+            let phrase = normalize_crisis_text(phrase);
+            lexicon.insert(
+                crisis_term_key(language, &phrase),
+                StorableCrisisTerm {
+                    language: language.to_string(),
+                    phrase,
+                    severity: *severity,
+                    added_at: now,
+                },
+            );
+        }
+    });
+
+    set_setting(SETTING_CRISIS_LEXICON_SEEDED, 1);
+}
+
+// Terms found in the text, with the highest severity among them. Every language is
+// checked because people often switch languages mid-conversation.
+fn match_crisis_lexicon(text: &str) -> Option<(CrisisSeverity, Vec<String>)> {
+    let haystack = format!(" {} ", normalize_crisis_text(text));
+    let mut severity = None;
+    let mut matched = Vec::new();
+
+    CRISIS_LEXICON.with(|lexicon| {
+        for (_, term) in lexicon.borrow().iter() {
+            if crisis_phrase_matches(&haystack, &term.phrase) {
+                if Some(term.severity) > severity {
+                    severity = Some(term.severity);
+                }
+                if matched.len() < MAX_MATCHED_TERMS {
+                    matched.push(term.phrase);
+                }
+            }
+        }
+    });
+
+    severity.map(|severity| (severity, matched))
+}
+
+fn save_escalation(escalation: &CrisisEscalation) {
+    CRISIS_ESCALATIONS.with(|escalations| {
+        escalations
+            .borrow_mut()
+            .insert(escalation.id, StorableCrisisEscalation::from(escalation.clone()));
+    });
+
+    OPEN_ESCALATIONS.with(|open| {
+        let mut open = open.borrow_mut();
+        if escalation.status == EscalationStatus::Resolved {
+            open.remove(&escalation.id);
+        } else {
+            open.insert(escalation.id, ());
+        }
+    });
+}
+
+fn get_escalation(escalation_id: u64) -> Option<CrisisEscalation> {
+    CRISIS_ESCALATIONS
+        .with(|escalations| escalations.borrow().get(&escalation_id))
+        .map(CrisisEscalation::from)
+}
+
+// Flag a message, queue it for on-call clinicians and point the conversation at crisis
+// resources. A message is escalated once; later flags raise the severity if needed.
+fn escalate_crisis(
+    message: &Message,
+    source: EscalationSource,
+    reported_by: Option<Principal>,
+    severity: CrisisSeverity,
+    matched_terms: Vec<String>,
+) -> CrisisEscalation {
+    let existing = CRISIS_FLAGS
+        .with(|flags| flags.borrow().get(&message.id))
+        .and_then(get_escalation);
+
+    if let Some(mut escalation) = existing {
+        if severity > escalation.severity && escalation.status != EscalationStatus::Resolved {
+            escalation.severity = severity;
+            save_escalation(&escalation);
+        }
+        return escalation;
+    }
+
+    let escalation = CrisisEscalation {
+        id: generate_next_id(),
+        conversation_id: message.conversation_id.clone(),
+        message_id: message.id,
+        user_at_risk: message.sender_id,
+        reported_by,
+        source,
+        severity,
+        matched_terms,
+        status: EscalationStatus::Open,
+        created_at: get_time(),
+        acknowledged_by: None,
+        acknowledged_at: None,
+        resolved_at: None,
+        resolution_note: None,
+    };
+    save_escalation(&escalation);
+    CRISIS_FLAGS.with(|flags| {
+        flags.borrow_mut().insert(message.id, escalation.id);
+    });
+
+    if let Err(e) = post_system_message(&message.conversation_id, CRISIS_RESOURCES_MESSAGE) {
+        ic_cdk::println!("Failed to post crisis resources to {}: {}", message.conversation_id, e);
+    }
+
+    escalation
+}
+
+// Server-readable conversations are screened for crisis language when a message is
+// sent or edited; E2E clients screen locally and call flag_crisis_message.
+// `content` is the sanitized plaintext of the message.
+fn screen_for_crisis(message: &Message, content: &str) {
+    let screened = !matches!(message.message_type, MessageType::System)
+        && !get_conversation_settings_or_default(&message.conversation_id).end_to_end_encrypted;
+    if !screened {
+        return;
+    }
+
+    let screened_text = rich_text_plain_text(&message.message_type, content).unwrap_or_else(|| content.to_string());
+    if let Some((severity, matched_terms)) = match_crisis_lexicon(&screened_text) {
+        escalate_crisis(message, EscalationSource::LexiconMatch, None, severity, matched_terms);
+    }
+}
+
+// === SYNC HELPERS ===
+
+// Append an event to each recipient's change log, pruning entries past retention
+fn record_sync_event(
+    recipients: &[Principal],
+    event_type: SyncEventType,
+    conversation_id: Option<&str>,
+    message_id: Option<u64>,
+    session_id: Option<&str>,
+) {
+    let now = get_time();
+    let seq = generate_next_id();
+    let expired_before = now.saturating_sub(SYNC_EVENT_RETENTION_NS);
+
+    for recipient in recipients {
+        let event = SyncEvent {
+            seq,
+            event_type: event_type.clone(),
+            conversation_id: conversation_id.map(|id| id.to_string()),
+            message_id,
+            session_id: session_id.map(|id| id.to_string()),
+            timestamp: now,
+        };
+
+        let scope = recipient.to_text();
+        let prefix = format!("{}:", scope);
+
+        SYNC_EVENTS.with(|events| {
+            let mut events = events.borrow_mut();
+            events.insert(scoped_key(&scope, seq), StorableSyncEvent::from(event));
+
+            // Oldest entries come first, so stop at the first one still retained
+            let expired: Vec<(String, u64)> = events
+                .range(prefix.clone()..)
+                .take_while(|(key, event)| key.starts_with(&prefix) && event.timestamp < expired_before)
+                .map(|(key, event)| (key, event.seq))
+                .collect();
+
+            if let Some((_, last_pruned)) = expired.last() {
+                SYNC_WATERMARKS.with(|watermarks| {
+                    watermarks.borrow_mut().insert(*recipient, *last_pruned);
+                });
+            }
+
+            for (key, _) in expired {
+                events.remove(&key);
+            }
+        });
+    }
+}
+
+// Record an event for every participant of a conversation
+fn notify_conversation(conversation_id: &str, event_type: SyncEventType, message_id: Option<u64>) {
+    let conversation = CONVERSATIONS.with(|conversations| {
+        conversations.borrow().get(&conversation_id.to_string())
+    });
+
+    if let Some(conversation) = conversation {
+        record_sync_event(
+            &conversation.participants,
+            event_type,
+            Some(conversation_id),
+            message_id,
+            None,
+        );
+    }
+}
+
+// === RTC SESSION HELPERS ===
+
+// Generate a UUID-formatted session ID (8-4-4-4-12) accepted by validate_session_id
+fn generate_session_id() -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(get_caller().as_slice());
+    hasher.update(get_time().to_be_bytes());
+    hasher.update(generate_next_id().to_be_bytes());
+    let hash = hex::encode(&hasher.finalize()[..16]);
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hash[0..8],
+        &hash[8..12],
+        &hash[12..16],
+        &hash[16..20],
+        &hash[20..32]
+    )
+}
+
+fn get_rtc_session_record(session_id: &str) -> Result<RTCSession, String> {
+    validate_session_id(session_id)?;
+
+    RTC_SESSIONS
+        .with(|sessions| sessions.borrow().get(&session_id.to_string()))
+        .map(RTCSession::from)
+        .ok_or_else(|| "RTC session not found".to_string())
+}
+
+// Load a session and verify the caller takes part in it
+fn get_rtc_session_for_participant(session_id: &str, caller: &Principal) -> Result<RTCSession, String> {
+    let session = get_rtc_session_record(session_id)?;
+
+    if !session.participants.contains(caller) {
+        return Err("Unauthorized: Not a participant in this RTC session".to_string());
+    }
+
+    Ok(session)
+}
+
+fn save_rtc_session(session: &RTCSession) {
+    RTC_SESSIONS.with(|sessions| {
+        sessions.borrow_mut().insert(
+            session.session_id.clone(),
+            StorableRTCSession::from(session.clone()),
+        );
+    });
+}
+
+fn is_session_open(session: &RTCSession) -> bool {
+    matches!(session.status, RTCSessionStatus::Pending | RTCSessionStatus::Active)
+}
+
+// What a participant may do in a call, derived from their role in the linked
+// conversation: read-only members only listen in, recording follows the same
+// rule as privacy settings, and group room hosts and co-hosts may also record.
+fn session_permissions(session: &RTCSession, user_id: &Principal) -> Vec<SessionPermission> {
+    let conversation = match CONVERSATIONS
+        .with(|conversations| conversations.borrow().get(&session.conversation_id))
+        .map(Conversation::from)
+    {
+        Some(conversation) if is_participant(&conversation, user_id) => conversation,
+        _ => return Vec::new(),
+    };
+
+    let mut permissions = vec![SessionPermission::ReceiveMessage, SessionPermission::ReceiveCall];
+
+    let role = get_participant_role(&conversation.id, user_id);
+    if role.rank() < ParticipantRole::Member.rank() {
+        return permissions;
+    }
+
+    permissions.extend([
+        SessionPermission::SendMessage,
+        SessionPermission::InitiateCall,
+        SessionPermission::ShareScreen,
+    ]);
+
+    let room_moderator = get_group_room(&session.session_id)
+        .and_then(|room| room_role(&room, user_id))
+        .is_some_and(|role| role != RoomRole::Attendee);
+    if room_moderator || role.rank() >= settings_role(&conversation).rank() {
+        permissions.push(SessionPermission::RecordSession);
+    }
+
+    permissions
+}
+
+fn require_session_permission(session: &RTCSession, user_id: &Principal, permission: SessionPermission) -> Result<(), String> {
+    if !session_permissions(session, user_id).contains(&permission) {
+        return Err(format!("Unauthorized: Requires the {:?} permission in this session", permission));
+    }
+
+    Ok(())
+}
+
+// Ordered "<scope>:<zero-padded id>" key used for per-scope ranges in stable maps
+fn scoped_key(scope: &str, id: u64) -> String {
+    format!("{}:{:020}", scope, id)
+}
+
+fn parse_scoped_id(key: &str) -> Option<u64> {
+    key.rsplit(':').next().and_then(|id| id.parse().ok())
+}
+
+// All consent records of a session, oldest first
+fn get_consent_trail(session_id: &str) -> Vec<RecordingConsent> {
+    let prefix = format!("{}:", session_id);
+
+    RECORDING_CONSENTS.with(|consents| {
+        consents
+            .borrow()
+            .range(prefix.clone()..)
+            .take_while(|(key, _)| key.starts_with(&prefix))
+            .map(|(_, storable)| RecordingConsent::from(storable))
+            .collect()
+    })
+}
+
+// A participant has consented when their most recent consent record is a grant
+fn has_recording_consent(trail: &[RecordingConsent], participant: &Principal) -> bool {
+    trail
+        .iter()
+        .rev()
+        .find(|consent| &consent.participant == participant)
+        .map(|consent| consent.action == ConsentAction::Granted)
+        .unwrap_or(false)
+}
+
+fn record_consent(session_id: &str, participant: Principal, action: ConsentAction) -> RecordingConsent {
+    let consent = RecordingConsent {
+        session_id: session_id.to_string(),
+        participant,
+        action,
+        timestamp: get_time(),
+    };
+
+    RECORDING_CONSENTS.with(|consents| {
+        consents.borrow_mut().insert(
+            scoped_key(session_id, generate_next_id()),
+            StorableRecordingConsent::from(consent.clone()),
+        );
+    });
+
+    consent
+}
+
+fn session_type_label(session_type: &RTCSessionType) -> &'static str {
+    match session_type {
+        RTCSessionType::AudioCall => "Audio call",
+        RTCSessionType::VideoCall => "Video call",
+        RTCSessionType::ScreenShare => "Screen share",
+        RTCSessionType::TherapySession => "Therapy session call",
+    }
+}
+
+// Format a nanosecond duration as h:mm:ss or m:ss
+fn format_call_duration(duration_ns: u64) -> String {
+    let total_seconds = duration_ns / 1_000_000_000;
+    let hours = total_seconds / 3600;
+    let minutes = (total_seconds % 3600) / 60;
+    let seconds = total_seconds % 60;
+
+    if hours > 0 {
+        format!("{}:{:02}:{:02}", hours, minutes, seconds)
+    } else {
+        format!("{}:{:02}", minutes, seconds)
+    }
+}
+
+fn call_duration(session: &RTCSession) -> Option<u64> {
+    match (session.started_at, session.ended_at) {
+        (Some(started_at), Some(ended_at)) => Some(ended_at.saturating_sub(started_at)),
+        _ => None,
+    }
+}
+
+// Post the call history notice matching a session's current status
+fn post_call_notice(session: &RTCSession) {
+    let label = session_type_label(&session.session_type);
+
+    let notice = match session.status {
+        RTCSessionStatus::Active => format!("{} started", label),
+        RTCSessionStatus::Ended => match call_duration(session) {
+            Some(duration) => format!("{} ended (duration {})", label, format_call_duration(duration)),
+            None => format!("{} ended", label),
+        },
+        RTCSessionStatus::Missed => format!("Missed {} from {}", label.to_lowercase(), session.initiator_id.to_text()),
+        RTCSessionStatus::Declined => format!("{} declined", label),
+        RTCSessionStatus::Pending | RTCSessionStatus::Failed => return,
+    };
+
+    if let Err(e) = post_system_message(&session.conversation_id, &notice) {
+        ic_cdk::println!("Failed to post call notice for {}: {}", session.session_id, e);
+    }
+}
+
+// Mark a still-pending session as missed once its ring timeout has elapsed
+fn expire_unanswered_session(session_id: &str) {
+    let mut session = match get_rtc_session_record(session_id) {
+        Ok(session) => session,
+        Err(_) => return,
+    };
+
+    if !matches!(session.status, RTCSessionStatus::Pending) {
+        return;
+    }
+
+    session.status = RTCSessionStatus::Missed;
+    session.ended_at = Some(get_time());
+    save_rtc_session(&session);
+    post_call_notice(&session);
+}
+
+fn schedule_ring_timeout(session_id: String, delay_ns: u64) {
+    ic_cdk_timers::set_timer(Duration::from_nanos(delay_ns), move || {
+        expire_unanswered_session(&session_id);
+    });
+}
+
+// Timers do not survive upgrades, so re-arm ring timeouts for calls still ringing
+fn restore_ring_timeouts() {
+    let now = get_time();
+    let pending: Vec<(String, u64)> = RTC_SESSIONS.with(|sessions| {
+        sessions
+            .borrow()
+            .iter()
+            .filter(|(_, session)| {
+                matches!(session.status, RTCSessionStatus::Pending) && session.parent_session_id.is_none()
+            })
+            .map(|(session_id, session)| (session_id, session.created_at))
+            .collect()
+    });
+
+    for (session_id, created_at) in pending {
+        let deadline = created_at.saturating_add(RTC_RING_TIMEOUT_NS);
+        schedule_ring_timeout(session_id, deadline.saturating_sub(now));
+    }
+}
+
+fn to_call_summary(session: RTCSession) -> CallSummary {
+    CallSummary {
+        duration: call_duration(&session),
+        session_id: session.session_id,
+        session_type: session.session_type,
+        status: session.status,
+        initiator_id: session.initiator_id,
+        participants: session.participants,
+        created_at: session.created_at,
+        started_at: session.started_at,
+        ended_at: session.ended_at,
+        quality_settings: session.metadata.quality_settings,
+    }
+}
+
+// Phase 2: Security validation functions
+fn check_rate_limit(principal: Principal, max_calls: u32, window_ms: u64) -> Result<(), String> {
+    let current_time = get_time();
+    
+    RATE_LIMITS.with(|rate_limits| {
+        let mut rate_limits = rate_limits.borrow_mut();
+        
+        match rate_limits.get(&principal) {
+            Some(rate_limit) => {
+                let rate_limit = RateLimit::from(rate_limit);
+                
+                // Check if we're still in the same time window
+                if current_time - rate_limit.window_start < window_ms {
+                    if rate_limit.call_count >= max_calls {
+                        return Err(format!("Rate limit exceeded: {} calls in {} ms", max_calls, window_ms));
+                    }
+                    
+                    // Increment call count
+                    let updated_rate_limit = RateLimit {
+                        principal,
+                        call_count: rate_limit.call_count + 1,
+                        window_start: rate_limit.window_start,
+                        window_duration: window_ms,
+                    };
+                    rate_limits.insert(principal, StorableRateLimit::from(updated_rate_limit));
+                } else {
+                    // New time window, reset counter
+                    let new_rate_limit = RateLimit {
+                        principal,
+                        call_count: 1,
+                        window_start: current_time,
+                        window_duration: window_ms,
+                    };
+                    rate_limits.insert(principal, StorableRateLimit::from(new_rate_limit));
+                }
+            }
+            None => {
+                // First call from this principal
+                let new_rate_limit = RateLimit {
+                    principal,
+                    call_count: 1,
+                    window_start: current_time,
+                    window_duration: window_ms,
+                };
+                rate_limits.insert(principal, StorableRateLimit::from(new_rate_limit));
+            }
+        }
+        
+        Ok(())
+    })
+}
+
+fn validate_nonce(nonce: &str, timestamp: u64) -> Result<(), String> {
+    if nonce.is_empty() {
+        return Err("Nonce cannot be empty".to_string());
+    }
+    
+    let current_time = get_time();
+    
+    // Check if timestamp is not too old or in the future
+    if timestamp > current_time + 60000 { // 1 minute future tolerance
+        return Err("Timestamp is too far in the future".to_string());
+    }
+    
+    if current_time - timestamp > NONCE_EXPIRY_MS {
+        return Err("Nonce has expired".to_string());
+    }
+    
+    USED_NONCES.with(|nonces| {
+        let mut nonces = nonces.borrow_mut();
+        
+        if nonces.get(&nonce.to_string()).is_some() {
+            return Err("Nonce has already been used".to_string());
+        }
+        
+        // Store the nonce with its timestamp
+        nonces.insert(nonce.to_string(), timestamp);
+        
+        // Clean up expired nonces (simple cleanup)
+        let expired_threshold = current_time - NONCE_EXPIRY_MS;
+        let expired_nonces: Vec<String> = nonces
+            .iter()
+            .filter_map(|(nonce_key, nonce_timestamp)| {
+                if nonce_timestamp < expired_threshold {
+                    Some(nonce_key)
+                } else {
+                    None
+                }
+            })
+            .collect();
+        
+        for expired_nonce in expired_nonces {
+            nonces.remove(&expired_nonce);
+        }
+        
+        Ok(())
+    })
+}
+
+fn validate_text_length(text: &str, max_length: usize, field_name: &str) -> Result<(), String> {
+    if text.len() > max_length {
+        return Err(format!("{} exceeds maximum length of {} characters", field_name, max_length));
+    }
+    Ok(())
+}
+
+// Embedding, override and isolate controls can make text display in a different order
+// than it was written. Marks (LRM, RLM, ALM) only affect neutral characters and are kept.
+fn is_bidi_control(c: char) -> bool {
+    matches!(c, '\u{202A}'..='\u{202E}' | '\u{2066}'..='\u{2069}')
+}
+
+// U+FDD0..U+FDEF and the last two code points of every plane are never valid in
+// interchange, so their presence means the client sent something other than text
+fn is_noncharacter(c: char) -> bool {
+    let code = c as u32;
+    (0xFDD0..=0xFDEF).contains(&code) || code & 0xFFFE == 0xFFFE
+}
+
+// NFC-normalize, unify line endings and drop control and bidi-override characters.
+// Tabs and newlines are the only controls kept; where line breaks are not allowed,
+// callers reject them first with reject_line_breaks rather than dropping them here.
+fn strip_unsafe_chars(text: &str) -> String {
+    text.replace("\r\n", "\n")
+        .replace('\r', "\n")
+        .nfc()
+        .filter(|c| !is_bidi_control(*c))
+        .filter(|c| !c.is_control() || *c == '\t' || *c == '\n')
+        .collect()
+}
+
+fn clean_text(text: &str) -> String {
+    strip_unsafe_chars(text).trim().to_string()
+}
+
+fn reject_line_breaks(text: &str, error: impl FnOnce() -> String) -> Result<(), String> {
+    if text.contains(['\n', '\r']) {
+        return Err(error());
+    }
+    Ok(())
+}
+
+fn reject_invalid_chars(text: &str) -> Result<(), String> {
+    match text.chars().find(|c| *c == '\0' || is_noncharacter(*c)) {
+        Some(c) => Err(format!("Message content contains an invalid character (U+{:04X})", c as u32)),
+        None => Ok(()),
+    }
+}
+
+// Free-text fields outside messages, such as report details and moderator notes
+fn sanitize_text(text: &str) -> String {
+    clean_text(text)
+}
+
+fn message_type_name(message_type: &MessageType) -> String {
+    format!("{:?}", message_type)
+}
+
+fn default_sanitization_policy(message_type: &MessageType) -> SanitizationPolicy {
+    let max_length = match message_type {
+        MessageType::Text | MessageType::System | MessageType::RichText => MAX_TEXT_LENGTH as u64,
+        // Media content is a caption; the payload travels in attachments
+        MessageType::Image | MessageType::File | MessageType::Audio | MessageType::Video => MAX_CAPTION_LENGTH,
+    };
+
+    SanitizationPolicy {
+        message_type: message_type.clone(),
+        max_length,
+        allow_newlines: true,
+    }
+}
+
+fn get_sanitization_policy(message_type: &MessageType) -> SanitizationPolicy {
+    SANITIZATION_POLICIES
+        .with(|policies| policies.borrow().get(&message_type_name(message_type)))
+        .map(|stored| SanitizationPolicy {
+            message_type: message_type.clone(),
+            max_length: stored.max_length,
+            allow_newlines: stored.allow_newlines,
+        })
+        .unwrap_or_else(|| default_sanitization_policy(message_type))
+}
+
+// Clean message content under its type's policy. Content that can't be made valid by
+// dropping invisible controls is rejected rather than altered.
+fn sanitize_message_content(content: &str, message_type: &MessageType) -> Result<String, String> {
+    let policy = get_sanitization_policy(message_type);
+    let type_name = message_type_name(message_type);
+
+    reject_invalid_chars(content)?;
+
+    if !policy.allow_newlines {
+        reject_line_breaks(content, || format!("Line breaks are not allowed in {} messages", type_name))?;
+    }
+
+    let mut cleaned = clean_text(content);
+    if matches!(message_type, MessageType::RichText) {
+        cleaned = normalize_rich_text(&cleaned)?;
+    }
+    if cleaned.is_empty() {
+        return Err("Message content cannot be empty".to_string());
+    }
+    if cleaned.len() as u64 > policy.max_length {
+        return Err(format!(
+            "Message content exceeds the {} byte limit for {} messages",
+            policy.max_length, type_name
+        ));
+    }
+
+    Ok(cleaned)
+}
+
+// === RICH TEXT HELPERS ===
+
+fn is_block_node(node: &RichTextNode) -> bool {
+    matches!(
+        node,
+        RichTextNode::Paragraph { .. }
+            | RichTextNode::Heading { .. }
+            | RichTextNode::BulletList { .. }
+            | RichTextNode::OrderedList { .. }
+            | RichTextNode::Blockquote { .. }
+            | RichTextNode::CodeBlock { .. }
+    )
+}
+
+fn validate_link_href(href: &str) -> Result<(), String> {
+    let lower = href.to_ascii_lowercase();
+    let allowed_scheme = ["https://", "mailto:"].iter().any(|scheme| lower.starts_with(scheme));
+
+    if !allowed_scheme || href.len() > MAX_RICH_TEXT_LINK_LENGTH {
+        return Err("Links must be https: or mailto: URLs of at most 2048 bytes".to_string());
+    }
+    if href.chars().any(|c| c.is_whitespace() || c.is_control() || is_bidi_control(c)) {
+        return Err("Links cannot contain whitespace or control characters".to_string());
+    }
+    Ok(())
+}
+
+// Where a node sits decides which node types may appear there
+#[derive(Clone, Copy, PartialEq)]
+enum RichTextContext {
+    Blocks,   // document root, blockquote
+    Inline,   // paragraph, heading, formatting, link
+    List,     // bullet and ordered lists
+    ListItem, // inline content, paragraphs and nested lists
+}
+
+// Check placement, depth and size, and clean every text leaf the way plain messages are cleaned
+fn validate_rich_text_nodes(
+    nodes: &mut [RichTextNode],
+    context: RichTextContext,
+    depth: usize,
+    in_link: bool,
+    node_count: &mut usize,
+) -> Result<(), String> {
+    if depth > MAX_RICH_TEXT_DEPTH {
+        return Err(format!("Rich text is nested more than {} levels deep", MAX_RICH_TEXT_DEPTH));
+    }
+
+    for node in nodes.iter_mut() {
+        *node_count += 1;
+        if *node_count > MAX_RICH_TEXT_NODES {
+            return Err(format!("Rich text has more than {} nodes", MAX_RICH_TEXT_NODES));
+        }
+
+        let allowed = match context {
+            RichTextContext::Blocks => is_block_node(node),
+            RichTextContext::Inline => !is_block_node(node) && !matches!(node, RichTextNode::ListItem { .. }),
+            RichTextContext::List => matches!(node, RichTextNode::ListItem { .. }),
+            RichTextContext::ListItem => {
+                !matches!(node, RichTextNode::ListItem { .. } | RichTextNode::Heading { .. } | RichTextNode::Blockquote { .. })
+            }
+        };
+        if !allowed {
+            return Err("Rich text contains a node in a position where it is not allowed".to_string());
+        }
+
+        match node {
+            RichTextNode::Paragraph { children }
+            | RichTextNode::Bold { children }
+            | RichTextNode::Italic { children }
+            | RichTextNode::Strikethrough { children } => {
+                validate_rich_text_nodes(children, RichTextContext::Inline, depth + 1, in_link, node_count)?;
+            }
+            RichTextNode::Heading { level, children } => {
+                if *level == 0 || *level > MAX_RICH_TEXT_HEADING_LEVEL {
+                    return Err(format!("Heading level must be between 1 and {}", MAX_RICH_TEXT_HEADING_LEVEL));
+                }
+                validate_rich_text_nodes(children, RichTextContext::Inline, depth + 1, in_link, node_count)?;
+            }
+            RichTextNode::BulletList { children } | RichTextNode::OrderedList { children, .. } => {
+                validate_rich_text_nodes(children, RichTextContext::List, depth + 1, in_link, node_count)?;
+            }
+            RichTextNode::ListItem { children } => {
+                validate_rich_text_nodes(children, RichTextContext::ListItem, depth + 1, in_link, node_count)?;
+            }
+            RichTextNode::Blockquote { children } => {
+                validate_rich_text_nodes(children, RichTextContext::Blocks, depth + 1, in_link, node_count)?;
+            }
+            RichTextNode::Link { href, children } => {
+                if in_link {
+                    return Err("Links cannot contain other links".to_string());
+                }
+                validate_link_href(href)?;
+                validate_rich_text_nodes(children, RichTextContext::Inline, depth + 1, true, node_count)?;
+            }
+            RichTextNode::CodeBlock { text } => {
+                reject_invalid_chars(text)?;
+                *text = strip_unsafe_chars(text);
+            }
+            RichTextNode::Text { text } | RichTextNode::Code { text } => {
+                reject_invalid_chars(text)?;
+                reject_line_breaks(text, || {
+                    "Text and code nodes cannot contain line breaks; use a line_break node or a code_block".to_string()
+                })?;
+                *text = strip_unsafe_chars(text);
+            }
+            RichTextNode::LineBreak => {}
+        }
+    }
+
+    Ok(())
+}
+
+// Parse, validate and re-serialize a rich text document, so only the canonical
+// allow-listed form is ever stored
+fn normalize_rich_text(content: &str) -> Result<String, String> {
+    let mut document: RichTextDocument = serde_json::from_str(content)
+        .map_err(|e| format!("Invalid rich text document: {}", e))?;
+
+    let mut node_count = 0;
+    validate_rich_text_nodes(&mut document.blocks, RichTextContext::Blocks, 1, false, &mut node_count)?;
+
+    if plain_text_of_blocks(&document.blocks).trim().is_empty() {
+        return Err("Rich text message has no text".to_string());
+    }
+
+    serde_json::to_string(&document).map_err(|_| "Failed to serialize rich text document".to_string())
+}
+
+fn plain_text_of_inline(nodes: &[RichTextNode], out: &mut String) {
+    for node in nodes {
+        match node {
+            RichTextNode::Text { text } | RichTextNode::Code { text } | RichTextNode::CodeBlock { text } => out.push_str(text),
+            RichTextNode::LineBreak => out.push('\n'),
+            RichTextNode::Bold { children }
+            | RichTextNode::Italic { children }
+            | RichTextNode::Strikethrough { children }
+            | RichTextNode::Link { children, .. } => plain_text_of_inline(children, out),
+            RichTextNode::ListItem { .. } => out.push_str(&plain_text_of_list_item(node)),
+            block => out.push_str(&plain_text_of_blocks(std::slice::from_ref(block))),
+        }
+    }
+}
+
+// A list item's inline text, followed by any nested lists or paragraphs on their own lines
+fn plain_text_of_list_item(item: &RichTextNode) -> String {
+    let mut text = String::new();
+    if let RichTextNode::ListItem { children } = item {
+        let (nested, inline): (Vec<RichTextNode>, Vec<RichTextNode>) =
+            children.iter().cloned().partition(is_block_node);
+        plain_text_of_inline(&inline, &mut text);
+
+        let nested_text = plain_text_of_blocks(&nested);
+        if !nested_text.is_empty() {
+            if !text.is_empty() {
+                text.push('\n');
+            }
+            text.push_str(&nested_text);
+        }
+    }
+    text
+}
+
+// One line per paragraph, heading and list item; formatting is dropped
+fn plain_text_of_blocks(blocks: &[RichTextNode]) -> String {
+    let mut lines = Vec::new();
+
+    for block in blocks {
+        let mut line = String::new();
+        match block {
+            RichTextNode::Paragraph { children } | RichTextNode::Heading { children, .. } => {
+                plain_text_of_inline(children, &mut line);
+            }
+            RichTextNode::BulletList { children } | RichTextNode::OrderedList { children, .. } => {
+                let start = match block {
+                    RichTextNode::OrderedList { start, .. } => Some(start.unwrap_or(1) as u64),
+                    _ => None,
+                };
+                let items: Vec<String> = children
+                    .iter()
+                    .enumerate()
+                    .map(|(index, item)| {
+                        let marker = match start {
+                            Some(start) => format!("{}. ", start + index as u64),
+                            None => "- ".to_string(),
+                        };
+                        format!("{}{}", marker, plain_text_of_list_item(item))
+                    })
+                    .collect();
+                line = items.join("\n");
+            }
+            RichTextNode::Blockquote { children } => line = plain_text_of_blocks(children),
+            RichTextNode::CodeBlock { text } => line.push_str(text),
+            inline => plain_text_of_inline(std::slice::from_ref(inline), &mut line),
+        }
+        if !line.is_empty() {
+            lines.push(line);
+        }
+    }
+
+    lines.join("\n")
+}
+
+// Plain text of decrypted rich text content; None for other message types
+fn rich_text_plain_text(message_type: &MessageType, content: &str) -> Option<String> {
+    if !matches!(message_type, MessageType::RichText) {
+        return None;
+    }
+
+    serde_json::from_str::<RichTextDocument>(content)
+        .ok()
+        .map(|document| plain_text_of_blocks(&document.blocks))
+}
+
+// === GROUP ROOM HELPERS ===
+
+fn get_group_room(session_id: &str) -> Option<GroupRoom> {
+    GROUP_ROOMS
+        .with(|rooms| rooms.borrow().get(&session_id.to_string()))
+        .map(GroupRoom::from)
+}
+
+fn save_group_room(room: &GroupRoom) {
+    GROUP_ROOMS.with(|rooms| {
+        rooms.borrow_mut().insert(
+            room.session_id.clone(),
+            StorableGroupRoom::from(room.clone()),
+        );
+    });
+}
+
+fn room_role(room: &GroupRoom, principal: &Principal) -> Option<RoomRole> {
+    room.members
+        .iter()
+        .find(|member| &member.principal == principal)
+        .map(|member| member.role.clone())
+}
+
+// Load an open room together with its session and check the caller may moderate it
+fn get_room_for_moderator(session_id: &str, caller: &Principal) -> Result<(RTCSession, GroupRoom), String> {
+    let session = get_rtc_session_for_participant(session_id, caller)?;
+    let room = get_group_room(session_id).ok_or_else(|| "Not a group room".to_string())?;
+
+    if !is_session_open(&session) {
+        return Err("RTC session has already ended".to_string());
+    }
+
+    match room_role(&room, caller) {
+        Some(RoomRole::Host) | Some(RoomRole::CoHost) => Ok((session, room)),
+        _ => Err("Unauthorized: Only the host or a co-host can manage this room".to_string()),
+    }
+}
+
+// === SIGNALING HELPERS ===
+
+fn signal_queue_scope(session_id: &str, recipient: &Principal) -> String {
+    format!("{}:{}", session_id, recipient.to_text())
+}
+
+// Principals allowed to exchange signals in a session: admitted members for
+// group rooms, every participant otherwise
+fn signaling_members(session: &RTCSession) -> Vec<Principal> {
+    match get_group_room(&session.session_id) {
+        Some(room) => room.members.iter().map(|member| member.principal).collect(),
+        None => session.participants.clone(),
+    }
+}
+
+fn signal_expiry_key(expires_at: u64, signal_key: &str) -> String {
+    format!("{:020}:{}", expires_at, signal_key)
+}
+
+// Drop signals whose TTL has passed, oldest first, a bounded number per call
+fn expire_stale_signals(now: u64) {
+    let stale: Vec<String> = SIGNAL_EXPIRY.with(|expiry| {
+        expiry
+            .borrow()
+            .range(..format!("{:020};", now))
+            .take(MAX_SIGNAL_EXPIRY_SWEEP)
+            .map(|(key, _)| key)
+            .collect()
+    });
+
+    for expiry_key in stale {
+        SIGNAL_EXPIRY.with(|expiry| expiry.borrow_mut().remove(&expiry_key));
+        if let Some((_, signal_key)) = expiry_key.split_once(':') {
+            WEBRTC_SIGNALS.with(|signals| signals.borrow_mut().remove(&signal_key.to_string()));
+        }
+    }
+}
+
+fn enqueue_signal(
+    session_id: &str,
+    sender_id: Principal,
+    recipient_id: Principal,
+    signal_type: SignalType,
+    payload: &str,
+) -> WebRTCSignal {
+    let now = get_time();
+    expire_stale_signals(now);
+
+    let key = scoped_key(&signal_queue_scope(session_id, &recipient_id), generate_next_id());
+
+    let signal = WebRTCSignal {
+        id: key.clone(),
+        session_id: session_id.to_string(),
+        sender_id,
+        recipient_id,
+        signal_type,
+        payload: payload.to_string(),
+        timestamp: now,
+        expires_at: now.saturating_add(SIGNAL_TTL_NS),
+    };
+
+    WEBRTC_SIGNALS.with(|signals| {
+        signals.borrow_mut().insert(key.clone(), StorableWebRTCSignal::from(signal.clone()));
+    });
+    SIGNAL_EXPIRY.with(|expiry| expiry.borrow_mut().insert(signal_expiry_key(signal.expires_at, &key), ()));
+
+    record_sync_event(&[recipient_id], SyncEventType::SignalReceived, None, None, Some(session_id));
+
+    signal
+}
+
+// === CALL TELEMETRY HELPERS ===
+
+// Buffered samples of a session, oldest first
+fn get_telemetry_samples(session_id: &str) -> Vec<(String, CallStatsSample)> {
+    let prefix = format!("{}:", session_id);
+
+    CALL_TELEMETRY.with(|telemetry| {
+        telemetry
+            .borrow()
+            .range(prefix.clone()..)
+            .take_while(|(key, _)| key.starts_with(&prefix))
+            .map(|(key, storable)| (key, CallStatsSample::from(storable)))
+            .collect()
+    })
+}
+
+// Append a sample, evicting the oldest ones once the buffer is full
+fn push_telemetry_sample(sample: CallStatsSample) {
+    let session_id = sample.session_id.clone();
+
+    CALL_TELEMETRY.with(|telemetry| {
+        telemetry.borrow_mut().insert(
+            scoped_key(&session_id, generate_next_id()),
+            StorableCallStatsSample::from(sample),
+        );
+    });
+
+    let samples = get_telemetry_samples(&session_id);
+    if samples.len() > TELEMETRY_BUFFER_SIZE {
+        let overflow = samples.len() - TELEMETRY_BUFFER_SIZE;
+        CALL_TELEMETRY.with(|telemetry| {
+            let mut telemetry = telemetry.borrow_mut();
+            for (key, _) in samples.into_iter().take(overflow) {
+                telemetry.remove(&key);
+            }
+        });
+    }
+}
+
+// Step quality down based on the network conditions seen in recent samples,
+// never exceeding what the session was configured with
+fn recommend_quality(configured: &QualitySettings, recent: &[CallStatsSample]) -> QualitySettings {
+    if recent.is_empty() {
+        return configured.clone();
+    }
+
+    let count = recent.len() as f64;
+    let avg_rtt = recent.iter().map(|s| s.stats.rtt_ms as f64).sum::<f64>() / count;
+    let avg_loss = recent.iter().map(|s| s.stats.packet_loss_percent).sum::<f64>() / count;
+    let avg_jitter = recent.iter().map(|s| s.stats.jitter_ms as f64).sum::<f64>() / count;
+
+    let (resolution, audio_bitrate, video_bitrate) = if avg_loss > 10.0 || avg_rtt > 400.0 || avg_jitter > 100.0 {
+        ("320x180", 24, 150)
+    } else if avg_loss > 5.0 || avg_rtt > 250.0 || avg_jitter > 50.0 {
+        ("640x360", 32, 500)
+    } else if avg_loss > 2.0 || avg_rtt > 150.0 || avg_jitter > 30.0 {
+        ("960x540", 48, 1000)
+    } else {
+        return configured.clone();
+    };
+
+    let video_resolution = if video_bitrate < configured.video_bitrate {
+        resolution.to_string()
+    } else {
+        configured.video_resolution.clone()
+    };
+
+    QualitySettings {
+        video_resolution,
+        audio_bitrate: audio_bitrate.min(configured.audio_bitrate),
+        video_bitrate: video_bitrate.min(configured.video_bitrate),
+    }
+}
+
+fn aggregate_call_quality(session_id: &str, samples: Vec<CallStatsSample>) -> CallQualityStats {
+    let count = samples.len() as f64;
+    let avg = |value: &dyn Fn(&CallStatsSample) -> f64| {
+        if samples.is_empty() {
+            0.0
+        } else {
+            samples.iter().map(value).sum::<f64>() / count
+        }
+    };
+
+    let skip = samples.len().saturating_sub(TELEMETRY_RECENT_SAMPLES);
+
+    CallQualityStats {
+        session_id: session_id.to_string(),
+        sample_count: samples.len() as u64,
+        first_sample_at: samples.first().map(|s| s.timestamp),
+        last_sample_at: samples.last().map(|s| s.timestamp),
+        avg_rtt_ms: avg(&|s| s.stats.rtt_ms as f64),
+        max_rtt_ms: samples.iter().map(|s| s.stats.rtt_ms).max().unwrap_or(0),
+        avg_packet_loss_percent: avg(&|s| s.stats.packet_loss_percent),
+        max_packet_loss_percent: samples.iter().map(|s| s.stats.packet_loss_percent).fold(0.0, f64::max),
+        avg_jitter_ms: avg(&|s| s.stats.jitter_ms as f64),
+        max_jitter_ms: samples.iter().map(|s| s.stats.jitter_ms).max().unwrap_or(0),
+        avg_video_bitrate: avg(&|s| s.stats.video_bitrate as f64),
+        min_video_bitrate: samples.iter().map(|s| s.stats.video_bitrate).min().unwrap_or(0),
+        recent_samples: samples[skip..].to_vec(),
+    }
+}
+
+// === SCREEN SHARE HELPERS ===
+
+fn screen_share_key(parent_session_id: &str, share_session_id: &str) -> String {
+    format!("{}:{}", parent_session_id, share_session_id)
+}
+
+fn save_screen_share(share: &ScreenShare) {
+    SCREEN_SHARES.with(|shares| {
+        shares.borrow_mut().insert(
+            screen_share_key(&share.parent_session_id, &share.share_session_id),
+            StorableScreenShare::from(share.clone()),
+        );
+    });
+}
+
+fn get_screen_shares_of(parent_session_id: &str) -> Vec<ScreenShare> {
+    let prefix = format!("{}:", parent_session_id);
+
+    SCREEN_SHARES.with(|shares| {
+        shares
+            .borrow()
+            .range(prefix.clone()..)
+            .take_while(|(key, _)| key.starts_with(&prefix))
+            .map(|(_, storable)| ScreenShare::from(storable))
+            .collect()
+    })
+}
+
+// Load a share and its sub-session, checking the caller takes part in it
+fn get_screen_share_for_participant(
+    share_session_id: &str,
+    caller: &Principal,
+) -> Result<(ScreenShare, RTCSession), String> {
+    let share_session = get_rtc_session_for_participant(share_session_id, caller)?;
+    let parent_session_id = share_session
+        .parent_session_id
+        .clone()
+        .ok_or_else(|| "Not a screen share session".to_string())?;
+
+    let share = SCREEN_SHARES
+        .with(|shares| shares.borrow().get(&screen_share_key(&parent_session_id, share_session_id)))
+        .map(ScreenShare::from)
+        .ok_or_else(|| "Screen share not found".to_string())?;
+
+    Ok((share, share_session))
+}
+
+// Close a share and its sub-session with the given final status
+fn close_screen_share(mut share: ScreenShare, mut share_session: RTCSession, status: ScreenShareStatus) -> ScreenShare {
+    let now = get_time();
+
+    share_session.status = match status {
+        ScreenShareStatus::Denied => RTCSessionStatus::Declined,
+        _ => RTCSessionStatus::Ended,
+    };
+    share_session.ended_at = Some(now);
+    save_rtc_session(&share_session);
+
+    share.status = status;
+    share.ended_at = Some(now);
+    save_screen_share(&share);
+
+    share
+}
+
+// Revoke the open shares of a call, optionally only those of one presenter
+fn revoke_screen_shares(parent_session_id: &str, presenter: Option<&Principal>) {
+    let open_shares = get_screen_shares_of(parent_session_id)
+        .into_iter()
+        .filter(|share| matches!(share.status, ScreenShareStatus::Requested | ScreenShareStatus::Granted))
+        .filter(|share| presenter.map(|p| &share.presenter == p).unwrap_or(true));
+
+    for share in open_shares {
+        if let Ok(share_session) = get_rtc_session_record(&share.share_session_id) {
+            close_screen_share(share, share_session, ScreenShareStatus::Revoked);
+        }
+    }
+}
+
+// === PRESENCE HELPERS ===
+
+fn get_presence_record(user_id: &Principal) -> Option<Presence> {
+    PRESENCE
+        .with(|presence| presence.borrow().get(user_id))
+        .map(Presence::from)
+}
+
+fn save_presence_record(presence: Presence) {
+    PRESENCE.with(|store| {
+        store.borrow_mut().insert(presence.user_id, StorablePresence::from(presence));
+    });
+}
+
+// Everyone the user shares at least one conversation with
+fn get_contacts(user_id: &Principal) -> HashSet<Principal> {
+    user_conversation_ids(user_id)
+        .into_iter()
+        .filter_map(|conversation_id| {
+            CONVERSATIONS.with(|conversations| conversations.borrow().get(&conversation_id))
+        })
+        .flat_map(|conversation| conversation.participants)
+        .filter(|participant| participant != user_id)
+        .collect()
+}
+
+// When the block between two users started, whichever of them blocked first
+fn blocked_since(a: &Principal, b: &Principal) -> Option<u64> {
+    let since = |blocker: &Principal, blocked: &Principal| BLOCKS.with(|blocks| blocks.borrow().get(&block_key(blocker, blocked)));
+    match (since(a, b), since(b, a)) {
+        (Some(x), Some(y)) => Some(x.min(y)),
+        (x, y) => x.or(y),
+    }
+}
+
+// Between users where either blocked the other, presence looks like a user who went
+// offline when the block started: last_seen never moves past that moment
+fn presence_for_viewer(user_id: Principal, viewer: &Principal, now: u64) -> UserPresence {
+    let Some(blocked_at) = blocked_since(&user_id, viewer) else {
+        return resolve_presence(user_id, now);
+    };
+
+    UserPresence {
+        user_id,
+        state: PresenceState::Offline,
+        status: None,
+        status_expires_at: None,
+        last_seen: get_presence_record(&user_id).map(|presence| presence.last_heartbeat.min(blocked_at)),
+    }
+}
+
+// Derive what others see from the stored heartbeat and manual status
+fn resolve_presence(user_id: Principal, now: u64) -> UserPresence {
+    let presence = match get_presence_record(&user_id) {
+        Some(presence) => presence,
+        None => {
+            return UserPresence {
+                user_id,
+                state: PresenceState::Offline,
+                status: None,
+                status_expires_at: None,
+                last_seen: None,
+            };
+        }
+    };
+
+    let state = if now.saturating_sub(presence.last_heartbeat) > PRESENCE_OFFLINE_AFTER_NS {
+        PresenceState::Offline
+    } else if now.saturating_sub(presence.last_active) > PRESENCE_AWAY_AFTER_NS {
+        PresenceState::Away
+    } else {
+        PresenceState::Online
+    };
+
+    let status_active = presence
+        .manual_status_expires_at
+        .map(|expires_at| expires_at > now)
+        .unwrap_or(true);
+
+    let (status, status_expires_at) = if status_active {
+        (presence.manual_status, presence.manual_status_expires_at)
+    } else {
+        (None, None)
+    };
+
+    UserPresence {
+        user_id,
+        state,
+        status,
+        status_expires_at,
+        last_seen: Some(presence.last_heartbeat),
+    }
+}
+
+// === TYPING HELPERS ===
+
+fn get_user_preferences_or_default(user_id: &Principal) -> UserPreferences {
+    let stored = USER_PREFERENCES.with(|preferences| preferences.borrow().get(user_id));
+
+    UserPreferences {
+        typing_indicators_enabled: stored.map(|p| p.typing_indicators_enabled).unwrap_or(true),
+    }
+}
+
+fn clear_typing(conversation_id: &str, user_id: &Principal) {
+    TYPING.with(|typing| {
+        let mut typing = typing.borrow_mut();
+        if let Some(typists) = typing.get_mut(conversation_id) {
+            typists.remove(user_id);
+            if typists.is_empty() {
+                typing.remove(conversation_id);
+            }
+        }
+    });
+}
+
+// Who is typing in the user's conversations, excluding the user
+fn active_typing_for(user_id: &Principal, now: u64) -> Vec<TypingIndicator> {
+    let typing: Vec<(String, Principal, u64)> = TYPING.with(|typing| {
+        typing
+            .borrow()
+            .iter()
+            .flat_map(|(conversation_id, typists)| {
+                typists
+                    .iter()
+                    .filter(|(typist, expires_at)| {
+                        *typist != user_id && **expires_at > now && !blocked_either_way(typist, user_id)
+                    })
+                    .map(move |(typist, expires_at)| (conversation_id.clone(), *typist, *expires_at))
+            })
+            .collect()
+    });
+
+    typing
+        .into_iter()
+        .filter(|(conversation_id, _, _)| {
+            CONVERSATIONS
+                .with(|conversations| conversations.borrow().get(conversation_id))
+                .is_some_and(|conversation| conversation.participants.contains(user_id))
+        })
+        .map(|(conversation_id, user_id, expires_at)| TypingIndicator {
+            conversation_id,
+            user_id,
+            expires_at,
+        })
+        .collect()
+}
+
+// === PHI ENCRYPTION FUNCTIONS ===
+
+/// Generate a new encryption key for PHI data using IC's random source
+pub fn generate_phi_encryption_key() -> Result<Vec<u8>, String> {
+    let mut key_bytes = [0u8; 32];
+    // Use IC's time and caller as entropy source
+    let entropy = format!("{}{}", get_time(), get_caller().to_text());
+    let mut hasher = Sha256::new();
+    hasher.update(entropy.as_bytes());
+    let hash = hasher.finalize();
+    key_bytes.copy_from_slice(&hash[..32]);
+    Ok(key_bytes.to_vec())
+}
+
+/// Encrypt PHI data using HMAC-based encryption
+pub fn encrypt_phi_data(data: &str, key: &[u8]) -> Result<EncryptedData, String> {
+    if key.len() != 32 {
+        return Err("Invalid key length. Requires 32 bytes".to_string());
+    }
+
+    // Generate nonce using time and data hash
+    let nonce_data = format!("{}{}", get_time(), data.len());
+    let mut nonce_hasher = Sha256::new();
+    nonce_hasher.update(nonce_data.as_bytes());
+    let nonce_hash = nonce_hasher.finalize();
+    let nonce_bytes = &nonce_hash[..12]; // Use first 12 bytes as nonce
+
+    // Simple XOR encryption with HMAC authentication
+    let mut encrypted_bytes = Vec::new();
+    let data_bytes = data.as_bytes();
+    
+    // Create encryption key stream using HMAC
+    type HmacSha256 = Hmac<Sha256>;
+    let mut mac = HmacSha256::new_from_slice(key)
+        .map_err(|_| "Invalid key for HMAC".to_string())?;
+    mac.update(nonce_bytes);
+    let key_stream = mac.finalize().into_bytes();
+    
+    // XOR encrypt the data
+    for (i, &byte) in data_bytes.iter().enumerate() {
+        let key_byte = key_stream[i % key_stream.len()];
+        encrypted_bytes.push(byte ^ key_byte);
+    }
+
+    let encrypted_content = general_purpose::STANDARD.encode(&encrypted_bytes);
+    let nonce_b64 = general_purpose::STANDARD.encode(nonce_bytes);
+    
+    // Generate key ID based on key hash
+    let mut hasher = Sha256::new();
+    hasher.update(key);
+    let key_id = format!("phi_key_{}", hex::encode(&hasher.finalize()[..8]));
+
+    Ok(EncryptedData {
+        encrypted_content,
+        nonce: nonce_b64,
+        key_id,
+    })
+}
+
+/// Decrypt PHI data using HMAC-based decryption
+pub fn decrypt_phi_data(encrypted_data: &EncryptedData, key: &[u8]) -> Result<String, String> {
+    if key.len() != 32 {
+        return Err("Invalid key length. Requires 32 bytes".to_string());
+    }
+
+    let encrypted_bytes = general_purpose::STANDARD.decode(&encrypted_data.encrypted_content)
+        .map_err(|_| "Failed to decode encrypted content".to_string())?;
+    
+    let nonce_bytes = general_purpose::STANDARD.decode(&encrypted_data.nonce)
+        .map_err(|_| "Failed to decode nonce".to_string())?;
+
+    if nonce_bytes.len() != 12 {
+        return Err("Invalid nonce length".to_string());
+    }
+
+    // Create decryption key stream using HMAC (same as encryption)
+    type HmacSha256 = Hmac<Sha256>;
+    let mut mac = HmacSha256::new_from_slice(key)
+        .map_err(|_| "Invalid key for HMAC".to_string())?;
+    mac.update(&nonce_bytes);
+    let key_stream = mac.finalize().into_bytes();
+    
+    // XOR decrypt the data
+    let mut decrypted_bytes = Vec::new();
+    for (i, &byte) in encrypted_bytes.iter().enumerate() {
+        let key_byte = key_stream[i % key_stream.len()];
+        decrypted_bytes.push(byte ^ key_byte);
+    }
+
+    String::from_utf8(decrypted_bytes)
+        .map_err(|_| "Failed to convert decrypted data to string".to_string())
+}
+
+/// Derive encryption key from conversation participants for end-to-end encryption
+pub fn derive_conversation_key(participants: &[Principal]) -> Result<Vec<u8>, String> {
+    let mut sorted_participants = participants.to_vec();
+    sorted_participants.sort();
+    
+    let mut hasher = Sha256::new();
+    for participant in &sorted_participants {
+        hasher.update(participant.as_slice());
+    }
+    hasher.update(b"mentalverse_phi_encryption_v1");
+    
+    let hash = hasher.finalize();
+    Ok(hash.to_vec())
+}
+
+/// Validate that PHI data meets encryption requirements
+pub fn validate_phi_encryption(data: &str) -> Result<(), String> {
+    if data.is_empty() {
+        return Err("PHI data cannot be empty".to_string());
+    }
+    
+    if data.len() > 1_000_000 { // 1MB limit
+        return Err("PHI data exceeds maximum size limit".to_string());
+    }
+    
+    Ok(())
+}
+
+/// Decrypt message content for authorized conversation participants
+pub fn decrypt_message_content(encrypted_content: &str, conversation_participants: &[Principal]) -> Result<String, String> {
+    // Derive the same conversation key used for encryption
+    let decryption_key = derive_conversation_key(conversation_participants)?;
+    
+    // Try to parse as encrypted data structure
+    match serde_json::from_str::<EncryptedData>(encrypted_content) {
+        Ok(encrypted_data) => {
+            // Decrypt using AES-256-GCM
+            decrypt_phi_data(&encrypted_data, &decryption_key)
+        },
+        Err(_) => {
+            // If not encrypted format, return as-is (backward compatibility)
+            Ok(encrypted_content.to_string())
+        }
+    }
+}
+
+/// Decrypt attachment data for authorized conversation participants
+pub fn decrypt_attachment_data(encrypted_data: &str, conversation_participants: &[Principal]) -> Result<String, String> {
+    if encrypted_data.is_empty() {
+        return Ok(String::new());
+    }
+    
+    // Derive the same conversation key used for encryption
+    let decryption_key = derive_conversation_key(conversation_participants)?;
+    
+    // Try to parse as encrypted data structure
+    match serde_json::from_str::<EncryptedData>(encrypted_data) {
+        Ok(encrypted_struct) => {
+            // Decrypt using AES-256-GCM
+            decrypt_phi_data(&encrypted_struct, &decryption_key)
+        },
+        Err(_) => {
+            // If not encrypted format, return as-is (backward compatibility)
+            Ok(encrypted_data.to_string())
+        }
+    }
+}
+
+/// Decrypt a stored message's content and attachments for conversation participants
+pub fn decrypt_message_for_participants(mut message: Message, conversation_participants: &[Principal]) -> Message {
+    // Keep encrypted content if decryption fails (shouldn't happen for valid participants)
+    if let Ok(decrypted_content) = decrypt_message_content(&message.content, conversation_participants) {
+        message.content = decrypted_content;
+    }
+
+    // Keep encrypted data if attachment decryption fails
+    message.attachments = message.attachments.into_iter().map(|mut attachment| {
+        if let Ok(decrypted_data) = decrypt_attachment_data(&attachment.encrypted_data, conversation_participants) {
+            attachment.encrypted_data = decrypted_data;
+        }
+        attachment
+    }).collect();
+
+    message
+}
+
+// === PRINCIPAL VALIDATION FUNCTIONS ===
+
+/// Validates that a Principal is not anonymous and has proper format
+fn validate_principal(principal: &Principal) -> Result<(), String> {
+    if principal == &Principal::anonymous() {
+        return Err("Anonymous principal not allowed".to_string());
+    }
+    
+    // Check if principal is properly formatted (basic validation)
+    let principal_text = principal.to_text();
+    if principal_text.is_empty() || principal_text.len() < 5 {
+        return Err("Invalid principal format".to_string());
+    }
+    
+    Ok(())
+}
+
+/// Validates multiple principals in a vector
+fn validate_principals(principals: &[Principal]) -> Result<(), String> {
+    for principal in principals {
+        validate_principal(principal)?;
+    }
+    Ok(())
+}
+
+/// Validates conversation ID format
+fn validate_conversation_id(conversation_id: &str) -> Result<(), String> {
+    if conversation_id.is_empty() {
+        return Err("Conversation ID cannot be empty".to_string());
+    }
+    
+    if conversation_id.len() > 128 {
+        return Err("Conversation ID too long".to_string());
+    }
+    
+    Ok(())
+}
+
+/// Validates session ID format for RTC sessions
+pub fn validate_session_id(session_id: &str) -> Result<(), String> {
+    if session_id.is_empty() {
+        return Err("Session ID cannot be empty".to_string());
+    }
+    
+    // Basic UUID format validation (36 characters with hyphens)
+    if session_id.len() != 36 {
+        return Err("Session ID must be a valid UUID format".to_string());
+    }
+    
+    // Check for basic UUID pattern (8-4-4-4-12)
+    let parts: Vec<&str> = session_id.split('-').collect();
+    if parts.len() != 5 || 
+       parts[0].len() != 8 || 
+       parts[1].len() != 4 || 
+       parts[2].len() != 4 || 
+       parts[3].len() != 4 || 
+       parts[4].len() != 12 {
+        return Err("Session ID must be a valid UUID format".to_string());
+    }
+    
+    Ok(())
+}
+
+// === BACKFILLS ===
+
+// One-time index backfills, run in this order. Each step handles a batch per timer
+// tick and returns the cursor to resume from, or None once it has seen every record.
+type BackfillStep = fn(Option<String>) -> Option<String>;
+
+const BACKFILLS: [(&str, BackfillStep); 8] = [
+    (SETTING_MESSAGE_INDEX_BUILT, backfill_conversation_message_index),
+    (SETTING_USER_CONVERSATION_INDEX_BUILT, backfill_user_conversation_index),
+    (SETTING_THREAD_INDEX_BUILT, backfill_thread_index),
+    (SETTING_UNREAD_COUNTS_BUILT, backfill_unread_counts),
+    (SETTING_PARTICIPANT_ROLES_BUILT, backfill_participant_roles),
+    (SETTING_DIRECT_INDEX_BUILT, backfill_direct_conversation_index),
+    (SETTING_SUPPRESSED_BY_MESSAGE_BUILT, backfill_suppressed_by_message),
+    (SETTING_REPORTER_INDEX_BUILT, backfill_reporter_index),
+];
+
+fn conversations_after(cursor: Option<String>) -> Vec<Conversation> {
+    let start = cursor.map_or(Unbounded, Excluded);
+
+    CONVERSATIONS.with(|conversations| {
+        conversations
+            .borrow()
+            .range((start, Unbounded))
+            .take(BACKFILL_BATCH_SIZE)
+            .map(|(_, storable)| Conversation::from(storable))
+            .collect()
+    })
+}
+
+fn next_conversation_cursor(batch: &[Conversation]) -> Option<String> {
+    if batch.len() < BACKFILL_BATCH_SIZE {
+        return None;
+    }
+    batch.last().map(|conversation| conversation.id.clone())
+}
+
+fn messages_after(cursor: Option<String>) -> Vec<Message> {
+    let start = match cursor.and_then(|cursor| cursor.parse::<u64>().ok()) {
+        Some(message_id) => Excluded(message_id),
+        None => Unbounded,
+    };
+
+    MESSAGES.with(|messages| {
+        messages
+            .borrow()
+            .range((start, Unbounded))
+            .take(BACKFILL_BATCH_SIZE)
+            .map(|(_, storable)| Message::from(storable))
+            .collect()
+    })
+}
+
+fn next_message_cursor(batch: &[Message]) -> Option<String> {
+    if batch.len() < BACKFILL_BATCH_SIZE {
+        return None;
+    }
+    batch.last().map(|message| message.id.to_string())
+}
+
+fn pending_backfill() -> Option<(&'static str, BackfillStep)> {
+    BACKFILLS
+        .iter()
+        .find(|(setting, _)| get_setting(setting, 0) != 1)
+        .copied()
+}
+
+// Backfill timer: advance the first unfinished backfill by one batch, then reschedule
+fn run_backfill_batch() {
+    let Some((setting, step)) = pending_backfill() else {
+        return;
+    };
+
+    let cursor = BACKFILL_CURSORS.with(|cursors| cursors.borrow().get(&setting.to_string()));
+    match step(cursor) {
+        Some(next) => {
+            BACKFILL_CURSORS.with(|cursors| cursors.borrow_mut().insert(setting.to_string(), next));
+        }
+        None => {
+            BACKFILL_CURSORS.with(|cursors| cursors.borrow_mut().remove(&setting.to_string()));
+            set_setting(setting, 1);
+        }
+    }
+
+    schedule_backfills();
+}
+
+fn schedule_backfills() {
+    if pending_backfill().is_some() {
+        ic_cdk_timers::set_timer(Duration::ZERO, run_backfill_batch);
+    }
+}
+
+// === CANISTER LIFECYCLE ===
+
+#[init]
+fn init() {
+    // Initialize the canister; a fresh install has nothing to backfill
+    for (setting, _) in BACKFILLS {
+        set_setting(setting, 1);
+    }
+    seed_crisis_lexicon();
+    start_retention_purge_timer();
+    ic_cdk::println!("Secure Messaging Canister initialized");
+}
+
+#[pre_upgrade]
+fn pre_upgrade() {
+    // Stable memory is automatically preserved
+}
+
+#[post_upgrade]
+fn post_upgrade() {
+    // Stable memory is automatically restored; backfills run in batches on a timer
+    schedule_backfills();
+    seed_crisis_lexicon();
+    restore_ring_timeouts();
+    restore_scheduled_message_timers();
+    start_retention_purge_timer();
+    ic_cdk::println!("Secure Messaging Canister upgraded");
+}
+
+// === PHI KEY MANAGEMENT API ===
+
+/// Generate and store a new PHI encryption key for a conversation
+#[update]
+fn generate_conversation_phi_key(conversation_id: String) -> Result<String, String> {
+    let caller = get_caller();
+    
+    // Validate caller principal
+    validate_principal(&caller)?;
+    
+    // Validate conversation ID format
+    validate_conversation_id(&conversation_id)?;
+    
+    // Verify caller is participant in conversation
+    let conversation = CONVERSATIONS.with(|conversations| {
+        conversations.borrow().get(&conversation_id)
+    });
+    
+    let conversation = match conversation {
+        Some(conv) => Conversation::from(conv),
+        None => return Err("Conversation not found".to_string()),
+    };
+    
+    if !is_participant(&conversation, &caller) {
+        return Err("Unauthorized: Not a participant in this conversation".to_string());
+    }
+    
+    // Generate new PHI encryption key
+    let phi_key = generate_phi_encryption_key()?;
+    let key_id = format!("phi_conv_{}_{}", conversation_id, get_time());
+    
+    let _phi_encryption_key = PHIEncryptionKey {
+        key_id: key_id.clone(),
+        key_data: phi_key,
+        created_at: get_time(),
+        is_active: true,
+        purpose: EncryptionPurpose::MessageContent,
+    };
+    
+    // Store the key (in a real implementation, this would be stored securely)
+    // For now, we return the key_id as confirmation
+    Ok(key_id)
+}
+
+/// Rotate PHI encryption key for a conversation (enhanced security)
+#[update]
+fn rotate_conversation_phi_key(conversation_id: String, _old_key_id: String) -> Result<String, String> {
+    let caller = get_caller();
+    
+    // Validate caller principal
+    validate_principal(&caller)?;
+    
+    // Validate conversation ID format
+    validate_conversation_id(&conversation_id)?;
+    
+    // Verify caller is participant in conversation
+    let conversation = CONVERSATIONS.with(|conversations| {
+        conversations.borrow().get(&conversation_id)
+    });
+    
+    let conversation = match conversation {
+        Some(conv) => Conversation::from(conv),
+        None => return Err("Conversation not found".to_string()),
+    };
+    
+    if !is_participant(&conversation, &caller) {
+        return Err("Unauthorized: Not a participant in this conversation".to_string());
+    }
+    
+    // Generate new PHI encryption key
+    let _new_phi_key = generate_phi_encryption_key()?;
+    let new_key_id = format!("phi_conv_{}_{}", conversation_id, get_time());
+    
+    // In a real implementation, you would:
+    // 1. Mark old key as inactive
+    // 2. Store new key securely
+    // 3. Re-encrypt recent messages with new key (optional)
+    
+    Ok(new_key_id)
+}
+
+// === PUBLIC API ===
+
+// Register user's public key for encryption
+#[update]
+fn register_user_key(public_key: String, key_type: KeyType) -> Result<UserKey, String> {
+    let caller = get_caller();
+    let now = get_time();
+    
+    // Validate caller principal
+    validate_principal(&caller)?;
+    
+    if public_key.is_empty() {
+        return Err("Public key cannot be empty".to_string());
+    }
+    
+    let user_key = UserKey {
+        user_id: caller,
+        public_key,
+        key_type,
+        created_at: now,
+        is_active: true,
+    };
+    
+    USER_KEYS.with(|keys| {
+        keys.borrow_mut().insert(caller, StorableUserKey::from(user_key.clone()));
+    });
+    
+    Ok(user_key)
+}
+
+// Get user's public key
+#[query]
+fn get_user_key(user_id: Principal) -> Option<UserKey> {
+    // Validate user_id principal
+    if validate_principal(&user_id).is_err() {
+        return None; // Return None for invalid principals
+    }
+    
+    USER_KEYS.with(|keys| {
+        keys.borrow().get(&user_id).map(|storable| UserKey::from(storable))
+    })
+}
+
+// Create a new conversation. Every call creates a new one (e.g. a SessionChat per therapy
+// session), except that two users share a single DirectMessage conversation.
+// Retrying with the same idempotency_key returns the conversation the first call created.
+#[update]
+fn create_conversation(
+    participants: Vec<Principal>,
+    conversation_type: ConversationType,
+    metadata: ConversationMetadata,
+    idempotency_key: Option<String>,
+) -> ConversationResult {
+    let caller = get_caller();
+    let now = get_time();
+    
+    if let Err(e) = require_not_suspended(&caller) {
+        return ConversationResult {
+            success: false,
+            conversation: None,
+            error: Some(e),
+        };
+    }
+    
+    if let Some(key) = &idempotency_key {
+        if key.is_empty() || key.len() > MAX_IDEMPOTENCY_KEY_LENGTH {
+            return ConversationResult {
+                success: false,
+                conversation: None,
+                error: Some("Invalid idempotency key".to_string()),
+            };
+        }
+        
+        let existing = CONVERSATION_IDEMPOTENCY
+            .with(|keys| keys.borrow().get(&idempotency_store_key(&caller, key)))
+            .and_then(|conversation_id| CONVERSATIONS.with(|conversations| conversations.borrow().get(&conversation_id)));
+        
+        if let Some(existing) = existing {
+            return ConversationResult {
+                success: true,
+                conversation: Some(Conversation::from(existing)),
+                error: None,
+            };
+        }
+    }
+    
+    // Validate caller principal
+    if let Err(e) = validate_principal(&caller) {
+        return ConversationResult {
+            success: false,
+            conversation: None,
+            error: Some(format!("Invalid caller: {}", e)),
+        };
+    }
+    
+    // Validate all participant principals
+    if let Err(e) = validate_principals(&participants) {
+        return ConversationResult {
+            success: false,
+            conversation: None,
+            error: Some(format!("Invalid participants: {}", e)),
+        };
+    }
+    
+    // Validate that caller is in participants
+    if !participants.contains(&caller) {
+        return ConversationResult {
+            success: false,
+            conversation: None,
+            error: Some("Caller must be a participant".to_string()),
+        };
+    }
+    
+    // Validate participants count
+    if participants.len() < 2 {
+        return ConversationResult {
+            success: false,
+            conversation: None,
+            error: Some("Conversation must have at least 2 participants".to_string()),
+        };
+    }
+    
+    if participants.len() > MAX_CONVERSATION_PARTICIPANTS {
+        return ConversationResult {
+            success: false,
+            conversation: None,
+            error: Some(format!("Conversation cannot have more than {} participants", MAX_CONVERSATION_PARTICIPANTS)),
+        };
+    }
+    
+    let direct_key = match (&conversation_type, participants.as_slice()) {
+        (ConversationType::DirectMessage, [a, b]) => Some(direct_conversation_key(a, b)),
+        (ConversationType::DirectMessage, _) => {
+            return ConversationResult {
+                success: false,
+                conversation: None,
+                error: Some("Direct messages have exactly 2 participants".to_string()),
+            };
+        }
+        _ => None,
+    };
+    
+    if let Some(direct_key) = &direct_key {
+        if DIRECT_CONVERSATIONS.with(|index| index.borrow().contains_key(direct_key)) {
+            return ConversationResult {
+                success: false,
+                conversation: None,
+                error: Some("Direct conversation already exists; use find_direct_conversation".to_string()),
+            };
+        }
+    }
+    
+    // Fixed for the life of the conversation; it does not follow later membership changes
+    let conversation_id = generate_conversation_id(&caller);
+    
+    let conversation = Conversation {
+        id: conversation_id.clone(),
+        participants,
+        conversation_type,
+        created_at: now,
+        updated_at: now,
+        last_message_id: None,
+        is_archived: false,
+        metadata,
+    };
+    
+    save_conversation(&conversation, None);
+    
+    if let Some(direct_key) = direct_key {
+        DIRECT_CONVERSATIONS.with(|index| {
+            index.borrow_mut().insert(direct_key, conversation_id.clone());
+        });
+    }
+    if let Some(key) = &idempotency_key {
+        CONVERSATION_IDEMPOTENCY.with(|keys| {
+            keys.borrow_mut().insert(idempotency_store_key(&caller, key), conversation_id.clone());
+        });
+    }
+    
+    for participant in &conversation.participants {
+        let role = if participant == &caller { ParticipantRole::Owner } else { ParticipantRole::Member };
+        set_role_entry(&conversation_id, participant, role, now);
+    }
+    hide_from_blockers(&conversation, &caller);
+    
+    // Users who blocked the creator are not told about it
+    let recipients: Vec<Principal> = conversation
+        .participants
+        .iter()
+        .filter(|participant| !is_blocked(participant, &caller))
+        .copied()
+        .collect();
+    record_sync_event(
+        &recipients,
+        SyncEventType::ConversationUpdated,
+        Some(&conversation_id),
+        None,
+        None,
+    );
+    
+    ConversationResult {
+        success: true,
+        conversation: Some(conversation),
+        error: None,
+    }
+}
+
+// The caller's direct conversation with peer, if they have one
+#[query]
+fn find_direct_conversation(peer: Principal) -> Option<Conversation> {
+    let caller = get_caller();
+    if validate_principal(&caller).is_err() {
+        return None;
+    }
+    
+    DIRECT_CONVERSATIONS
+        .with(|index| index.borrow().get(&direct_conversation_key(&caller, &peer)))
+        .and_then(|conversation_id| CONVERSATIONS.with(|conversations| conversations.borrow().get(&conversation_id)))
+        .map(Conversation::from)
+}
+
+// Send a message with comprehensive PHI encryption
+#[update]
+fn send_message(
+    conversation_id: String,
+    recipient_id: Principal,
+    content: String,
+    message_type: MessageType,
+    reply_to: Option<u64>,
+    attachments: Vec<Attachment>,
+    nonce: String,
+    timestamp: u64,
+) -> MessageResult {
+    let caller = get_caller();
+    
+    // Phase 2: Rate limiting (max 50 messages per minute)
+    if let Err(e) = check_rate_limit(caller, 50, 60000) {
+        return MessageResult {
+            success: false,
+            message: None,
+            error: Some(e),
+        };
+    }
+    
+    // Phase 2: Replay attack protection
+    if let Err(e) = validate_nonce(&nonce, timestamp) {
+        return MessageResult {
+            success: false,
+            message: None,
+            error: Some(e),
+        };
+    }
+    
+    match deliver_message(caller, conversation_id, recipient_id, content, message_type, reply_to, attachments) {
+        Ok(message) => MessageResult {
+            success: true,
+            message: Some(message),
+            error: None,
+        },
+        Err(e) => MessageResult {
+            success: false,
+            message: None,
+            error: Some(e),
+        },
+    }
+}
+
+// Validate, encrypt and store a message from sender. Shared by send_message and
+// scheduled delivery, so both go through exactly the same checks.
+fn deliver_message(
+    sender: Principal,
+    conversation_id: String,
+    recipient_id: Principal,
+    content: String,
+    message_type: MessageType,
+    reply_to: Option<u64>,
+    attachments: Vec<Attachment>,
+) -> Result<Message, String> {
+    let now = get_time();
+    
+    require_not_suspended(&sender)?;
+    
+    // Phase 2: Input validation and sanitization
+    validate_text_length(&content, MAX_TEXT_LENGTH, "Message content")?;
+    let sanitized_content = sanitize_message_content(&content, &message_type)?;
+    
+    validate_principal(&sender).map_err(|e| format!("Invalid caller: {}", e))?;
+    validate_principal(&recipient_id).map_err(|e| format!("Invalid recipient: {}", e))?;
+    validate_conversation_id(&conversation_id).map_err(|e| format!("Invalid conversation ID: {}", e))?;
+    validate_phi_encryption(&content).map_err(|e| format!("PHI validation failed: {}", e))?;
+    
+    // Validate conversation exists and sender is participant
+    let conversation = CONVERSATIONS
+        .with(|conversations| conversations.borrow().get(&conversation_id))
+        .map(Conversation::from)
+        .ok_or_else(|| "Conversation not found".to_string())?;
+    
+    require_role(&conversation, &sender, ParticipantRole::Member)?;
+    
+    // Validate recipient is also a participant
+    if !is_participant(&conversation, &recipient_id) {
+        return Err("Recipient is not a participant in this conversation".to_string());
+    }
+    
+    if let Some(reply_to) = reply_to {
+        validate_reply_to(reply_to, &conversation_id)?;
+    }
+    
+    // Generate conversation-specific encryption key for PHI data
+    let encryption_key = conversation_key(&conversation)
+        .map_err(|e| format!("Failed to derive encryption key: {}", e))?;
+    
+    // Encrypt message content using AES-256-GCM (use sanitized content)
+    let encrypted_content = encrypt_phi_data(&sanitized_content, &encryption_key)
+        .map(|encrypted| {
+            // Store as JSON string containing encrypted data structure
+            serde_json::to_string(&encrypted).unwrap_or_else(|_| sanitized_content.clone())
+        })
+        .map_err(|e| format!("Failed to encrypt message content: {}", e))?;
+    
+    // Encrypt attachments if present
+    let encrypted_attachments: Vec<Attachment> = attachments.into_iter().map(|mut attachment| {
+        if !attachment.encrypted_data.is_empty() {
+            // Decrypt first if already encrypted, then re-encrypt with conversation key
+            match encrypt_phi_data(&attachment.encrypted_data, &encryption_key) {
+                Ok(encrypted) => {
+                    attachment.encrypted_data = serde_json::to_string(&encrypted)
+                        .unwrap_or_else(|_| attachment.encrypted_data.clone());
+                },
+                Err(_) => {
+                    // Keep original if encryption fails
+                }
+            }
+        }
+        attachment
+    }).collect();
+    
+    let message_id = generate_next_id();
+    
+    let message = Message {
+        id: message_id,
+        conversation_id: conversation_id.clone(),
+        sender_id: sender,
+        recipient_id,
+        content: encrypted_content, // Store encrypted content
+        message_type,
+        timestamp: now,
+        is_read: false,
+        is_deleted: false,
+        reply_to,
+        attachments: encrypted_attachments, // Store encrypted attachments
+        edited_at: None,
+        reactions: Vec::new(),
+        thread: None,
+        plain_text: None,
+    };
+    
+    // Participants who blocked the sender never get this message
+    let blocked_by: Vec<Principal> = conversation
+        .participants
+        .iter()
+        .filter(|participant| is_blocked(participant, &sender))
+        .copied()
+        .collect();
+    for participant in &blocked_by {
+        suppress_message_for(participant, message_id);
+    }
+    
+    // Store the message
+    store_message(&message);
+    count_unread_message(&message, &conversation.participants);
+    if message.reply_to.is_some() {
+        index_thread_reply(&message);
+    }
+    
+    // Update conversation's last message
+    let previous_updated_at = conversation.updated_at;
+    let updated_conversation = Conversation {
+        last_message_id: Some(message_id),
+        updated_at: now,
+        ..conversation
+    };
+    
+    let recipients: Vec<Principal> = updated_conversation
+        .participants
+        .iter()
+        .filter(|participant| !blocked_by.contains(participant))
+        .copied()
+        .collect();
+    record_sync_event(
+        &recipients,
+        SyncEventType::MessageCreated,
+        Some(&conversation_id),
+        Some(message_id),
+        None,
+    );
+    
+    save_conversation(&updated_conversation, Some(previous_updated_at));
+    
+    // Senders have seen their own message and stopped typing
+    advance_watermark(&conversation_id, &sender, message_id, message_id);
+    clear_typing(&conversation_id, &sender);
+    
+    screen_for_crisis(&message, &sanitized_content);
+    
+    Ok(message)
+}
+
+// Get messages for a conversation.
+// Newest first by default or with before_id (messages older than that ID);
+// with after_id, the messages newer than that ID are returned oldest first.
+// offset is kept for older clients; prefer the ID cursors.
+#[query]
+fn get_conversation_messages(
+    conversation_id: String,
+    limit: Option<u64>,
+    offset: Option<u64>,
+    before_id: Option<u64>,
+    after_id: Option<u64>,
+) -> Vec<Message> {
+    let caller = get_caller();
+    
+    // Validate caller principal
+    if validate_principal(&caller).is_err() {
+        return Vec::new(); // Return empty vector for invalid principals
+    }
+    
+    // Validate conversation ID format
+    if validate_conversation_id(&conversation_id).is_err() {
+        return Vec::new(); // Return empty vector for invalid conversation IDs
+    }
+    
+    // Verify caller is participant in conversation
+    let conversation = CONVERSATIONS.with(|conversations| {
+        conversations.borrow().get(&conversation_id)
+    });
+    
+    let conversation = match conversation {
+        Some(conv) => Conversation::from(conv),
+        None => return vec![],
+    };
+    
+    if !is_participant(&conversation, &caller) {
+        return vec![];
+    }
+    
+    let limit = limit.unwrap_or(50).min(100) as usize; // Max 100 messages per query
+    let offset = offset.unwrap_or(0) as usize;
+    
+    let messages = conversation_page(&conversation_id, before_id, after_id, offset, limit, |message| {
+        !message.is_deleted && !is_message_hidden_from(&message.sender_id, message.id, &caller)
+    });
+    
+    // Decrypt message content and attachments for authorized participant
+    messages
+        .into_iter()
+        .map(|message| present_message(message, &conversation, &caller))
+        .collect()
+}
+
+// Get a thread: its root message and replies oldest first.
+// Pass next_cursor back as cursor to page through longer threads.
+#[query]
+fn get_thread(root_message_id: u64, cursor: Option<u64>, limit: Option<u64>) -> Result<ThreadPage, String> {
+    let caller = get_caller();
+    let limit = limit.unwrap_or(50).clamp(1, MAX_THREAD_PAGE) as usize;
+    
+    let (root, conversation) = get_message_for_participant(root_message_id, &caller)?;
+    if root.reply_to.is_some() {
+        return Err("Message is a reply, not a thread root".to_string());
+    }
+    
+    let scope = thread_scope(root_message_id);
+    let start = scoped_key(&scope, cursor.map(|id| id.saturating_add(1)).unwrap_or(0));
+    let end = scoped_key(&scope, u64::MAX);
+    
+    // Fetch one extra to know whether another page follows
+    let mut reply_ids: Vec<u64> = THREAD_REPLIES.with(|index| {
+        index
+            .borrow()
+            .range(start..end)
+            .filter_map(|(key, _)| parse_scoped_id(&key))
+            .take(limit + 1)
+            .collect()
+    });
+    let has_more = reply_ids.len() > limit;
+    reply_ids.truncate(limit);
+    
+    let replies: Vec<Message> = reply_ids
+        .iter()
+        .filter_map(|reply_id| MESSAGES.with(|messages| messages.borrow().get(reply_id)))
+        .map(Message::from)
+        .filter(|message| !is_message_hidden_from(&message.sender_id, message.id, &caller))
+        .map(|message| present_message(message, &conversation, &caller))
+        .collect();
+    
+    let summary = get_thread_summary(root_message_id).unwrap_or_else(|| empty_thread_summary(root_message_id));
+    
+    Ok(ThreadPage {
+        root: present_message(root, &conversation, &caller),
+        replies,
+        summary,
+        next_cursor: if has_more { reply_ids.last().copied() } else { None },
+    })
+}
+
+// Get user's conversations, most recently updated first.
+// Pass the last conversation's updated_at and ID as cursor to get the next page.
+#[query]
+fn get_user_conversations(
+    limit: Option<u64>,
+    cursor: Option<ConversationCursor>,
+    include_archived: Option<bool>,
+) -> Vec<Conversation> {
+    let caller = get_caller();
+    let limit = limit.unwrap_or(100).clamp(1, MAX_CONVERSATIONS_PAGE) as usize;
+    let include_archived = include_archived.unwrap_or(false);
+    
+    let scope = caller.to_text();
+    let start = format!("{}:", scope);
+    let end = match cursor {
+        Some(cursor) => user_conversation_key(&caller, cursor.updated_at, &cursor.conversation_id),
+        None => format!("{};", scope), // ';' sorts right after ':'
+    };
+    
+    USER_CONVERSATIONS.with(|index| {
+        CONVERSATIONS.with(|conversations| {
+            let conversations_ref = conversations.borrow();
+            
+            index
+                .borrow()
+                .range(start..end)
+                .rev()
+                .filter_map(|(key, _)| conversation_id_from_index_key(&key))
+                .filter_map(|conversation_id| conversations_ref.get(&conversation_id))
+                .map(Conversation::from)
+                .filter(|conversation| include_archived || !conversation.is_archived)
+                .filter(|conversation| !is_conversation_hidden_from(conversation, &caller))
+                .take(limit)
+                .collect()
+        })
+    })
+}
+
+// Mark message as read. Read state is a watermark, so this is mark_read_up_to for the
+// message's conversation: every earlier message there counts as read too.
+#[update]
+fn mark_message_read(message_id: u64) -> Result<(), String> {
+    let caller = get_caller();
+    
+    let conversation_id = MESSAGES.with(|messages| {
+        let mut messages_ref = messages.borrow_mut();
+        
+        match messages_ref.get(&message_id) {
+            Some(storable_message) => {
+                let mut message = Message::from(storable_message);
+                
+                // Only recipient can mark message as read
+                if message.recipient_id != caller {
+                    return Err("Unauthorized: Only recipient can mark message as read".to_string());
+                }
+                
+                message.is_read = true;
+                let conversation_id = message.conversation_id.clone();
+                messages_ref.insert(message_id, StorableMessage::from(message));
+                Ok(conversation_id)
+            }
+            None => Err("Message not found".to_string()),
+        }
+    })?;
+    
+    mark_read_up_to(conversation_id, message_id).map(|_| ())
+}
+
+// Unread badge counts for the caller's conversations, without downloading messages
+#[query]
+fn get_unread_summary() -> UnreadSummary {
+    let caller = get_caller();
+    let scope = caller.to_text();
+    let start = format!("{}:", scope);
+    let end = format!("{};", scope); // ';' sorts right after ':'
+    
+    let now = get_time();
+    
+    let conversations: Vec<ConversationUnread> = UNREAD_COUNTS.with(|counts| {
+        counts
+            .borrow()
+            .range(start.clone()..end)
+            .map(|(key, unread)| (key[start.len()..].to_string(), unread))
+            .filter(|(conversation_id, _)| !is_hidden_by_creator_block(&caller, conversation_id))
+            .map(|(conversation_id, unread)| {
+                ConversationUnread {
+                    muted_until: active_mute(&caller, &conversation_id, now),
+                    conversation_id,
+                    unread_count: unread.count,
+                    first_unread_message_id: unread.first_unread_id,
+                }
+            })
+            .collect()
+    });
+    
+    UnreadSummary {
+        total_unread: conversations
+            .iter()
+            .filter(|unread| unread.muted_until.is_none())
+            .map(|unread| unread.unread_count)
+            .sum(),
+        conversations,
+    }
+}
+
+// Mark everything in a conversation up to and including message_id as read by the caller
+#[update]
+fn mark_read_up_to(conversation_id: String, message_id: u64) -> Result<ReadWatermark, String> {
+    let caller = get_caller();
+    validate_principal(&caller)?;
+    
+    let conversation = get_conversation_for_participant(&conversation_id, &caller)?;
+    if !conversation_has_message(&conversation_id, message_id) {
+        return Err("Message not found".to_string());
+    }
+    
+    let previous_read_up_to = get_stored_watermark(&conversation_id, &caller).read_up_to;
+    
+    if advance_watermark(&conversation_id, &caller, message_id, message_id) {
+        // Keep the per-message flag in step for direct messages addressed to the caller
+        let newly_read: Vec<u64> = CONVERSATION_MESSAGES.with(|index| {
+            index
+                .borrow()
+                .range(scoped_key(&conversation_id, previous_read_up_to + 1)..=scoped_key(&conversation_id, message_id))
+                .filter_map(|(key, _)| parse_scoped_id(&key))
+                .collect()
+        });
+        
+        MESSAGES.with(|messages| {
+            let mut messages = messages.borrow_mut();
+            for id in newly_read {
+                if let Some(mut message) = messages.get(&id) {
+                    if message.recipient_id == caller && !message.is_read {
+                        message.is_read = true;
+                        messages.insert(id, message);
+                    }
+                }
+            }
+        });
+        
+        notify_read(&conversation, &caller, message_id);
+    }
+    
+    Ok(get_read_watermark_view(&conversation_id, &caller, &caller))
+}
+
+// Acknowledge that the caller's client has received messages up to message_id
+#[update]
+fn mark_delivered_up_to(conversation_id: String, message_id: u64) -> Result<ReadWatermark, String> {
+    let caller = get_caller();
+    validate_principal(&caller)?;
+    
+    get_conversation_for_participant(&conversation_id, &caller)?;
+    if !conversation_has_message(&conversation_id, message_id) {
+        return Err("Message not found".to_string());
+    }
+    
+    if advance_watermark(&conversation_id, &caller, message_id, 0) {
+        notify_conversation(&conversation_id, SyncEventType::MessageDelivered, Some(message_id));
+    }
+    
+    Ok(get_read_watermark_view(&conversation_id, &caller, &caller))
+}
+
+// Delivered/read watermarks of every participant in a conversation
+#[query]
+fn get_conversation_read_state(conversation_id: String) -> Result<Vec<ReadWatermark>, String> {
+    let caller = get_caller();
+    let conversation = get_conversation_for_participant(&conversation_id, &caller)?;
+    
+    Ok(conversation
+        .participants
+        .iter()
+        .map(|participant| get_read_watermark_view(&conversation_id, participant, &caller))
+        .collect())
+}
+
+// Which participants have received and read a message
+#[query]
+fn get_message_receipts(message_id: u64) -> Result<MessageReceipts, String> {
+    let caller = get_caller();
+    let (message, conversation) = get_message_for_participant(message_id, &caller)?;
+    let read_receipts_enabled = get_conversation_settings_or_default(&conversation.id).read_receipts_enabled;
+    
+    let mut delivered_to = Vec::new();
+    let mut read_by = Vec::new();
+    
+    for participant in conversation.participants.iter().filter(|p| **p != message.sender_id) {
+        let watermark = get_stored_watermark(&conversation.id, participant);
+        let has_read = watermark.read_up_to >= message_id
+            || (message.is_read && message.recipient_id == *participant);
+        
+        if watermark.delivered_up_to >= message_id || has_read {
+            delivered_to.push(*participant);
+        }
+        // The caller may always see their own read state
+        if has_read && (read_receipts_enabled || participant == &caller) {
+            read_by.push(*participant);
+        }
+    }
+    
+    Ok(MessageReceipts {
+        message_id,
+        delivered_to,
+        read_by,
+        read_receipts_enabled,
+    })
+}
+
+#[query]
+fn get_conversation_settings(conversation_id: String) -> Result<ConversationSettings, String> {
+    let caller = get_caller();
+    get_conversation_for_participant(&conversation_id, &caller)?;
+    
+    Ok(get_conversation_settings_or_default(&conversation_id))
+}
+
+// Turn read receipts on or off for everyone in a conversation
+#[update]
+fn set_read_receipts_enabled(conversation_id: String, enabled: bool) -> Result<ConversationSettings, String> {
+    let caller = get_caller();
+    validate_principal(&caller)?;
+    let conversation = get_conversation_for_participant(&conversation_id, &caller)?;
+    require_role(&conversation, &caller, settings_role(&conversation))?;
+    
+    let mut settings = get_conversation_settings_or_default(&conversation_id);
+    if settings.read_receipts_enabled != enabled {
+        settings.read_receipts_enabled = enabled;
+        save_conversation_settings(&settings);
+        notify_conversation(&conversation_id, SyncEventType::ConversationUpdated, None);
+    }
+    
+    Ok(settings)
+}
+
+// Switch a conversation to end-to-end encryption. This can't be undone: turning it
+// off would let the canister read content participants expected to stay private.
+#[update]
+fn enable_end_to_end_encryption(conversation_id: String) -> Result<ConversationSettings, String> {
+    let caller = get_caller();
+    validate_principal(&caller)?;
+    let conversation = get_conversation_for_participant(&conversation_id, &caller)?;
+    require_role(&conversation, &caller, settings_role(&conversation))?;
+    
+    let mut settings = get_conversation_settings_or_default(&conversation_id);
+    if !settings.end_to_end_encrypted {
+        settings.end_to_end_encrypted = true;
+        save_conversation_settings(&settings);
+        post_system_message(
+            &conversation_id,
+            "End-to-end encryption is on. Messages are no longer screened for crisis language on the server.",
+        )?;
+        notify_conversation(&conversation_id, SyncEventType::ConversationUpdated, None);
+    }
+    
+    Ok(settings)
+}
+
+// Choose how long messages are kept. Anyone in a direct conversation may change it;
+// groups need an admin. Every change is announced in the conversation.
+#[update]
+fn set_retention_policy(conversation_id: String, policy: RetentionPolicy) -> Result<ConversationRetention, String> {
+    let caller = get_caller();
+    validate_principal(&caller)?;
+    let conversation = get_conversation_for_participant(&conversation_id, &caller)?;
+    require_role(&conversation, &caller, settings_role(&conversation))?;
+    
+    match &policy {
+        RetentionPolicy::KeepForever => {}
+        RetentionPolicy::ExpireAfterDays { days } => {
+            if *days == 0 || *days > MAX_RETENTION_DAYS {
+                return Err(format!("Retention must be between 1 and {} days", MAX_RETENTION_DAYS));
+            }
+        }
+        RetentionPolicy::DisappearAfterRead { after_ns } => {
+            if *after_ns > MAX_DISAPPEAR_AFTER_NS {
+                return Err("Messages can disappear at most 30 days after being read".to_string());
+            }
+        }
+    }
+    
+    let current = get_retention(&conversation_id).map(|retention| retention.policy);
+    if current.as_ref().unwrap_or(&RetentionPolicy::KeepForever) == &policy {
+        return get_retention_policy(conversation_id);
+    }
+    
+    let now = get_time();
+    if policy == RetentionPolicy::KeepForever {
+        RETENTION_POLICIES.with(|policies| policies.borrow_mut().remove(&conversation_id));
+    } else {
+        // Messages already read by everyone are handled by the re-derivation below
+        RETENTION_POLICIES.with(|policies| {
+            policies.borrow_mut().insert(
+                conversation_id.clone(),
+                StorableRetention {
+                    policy: policy.clone(),
+                    updated_by: caller,
+                    updated_at: now,
+                    read_expiry_through: read_by_all(&conversation_id),
+                },
+            );
+        });
+    }
+    apply_retention_policy(&conversation_id);
+    
+    post_system_message(&conversation_id, &retention_notice(&policy))?;
+    notify_conversation(&conversation_id, SyncEventType::ConversationUpdated, None);
+    
+    Ok(ConversationRetention {
+        conversation_id,
+        policy,
+        updated_by: Some(caller),
+        updated_at: Some(now),
+    })
+}
+
+#[query]
+fn get_retention_policy(conversation_id: String) -> Result<ConversationRetention, String> {
+    get_conversation_for_participant(&conversation_id, &get_caller())?;
+    
+    Ok(match get_retention(&conversation_id) {
+        Some(retention) => ConversationRetention {
+            conversation_id,
+            policy: retention.policy,
+            updated_by: Some(retention.updated_by),
+            updated_at: Some(retention.updated_at),
+        },
+        None => ConversationRetention {
+            conversation_id,
+            policy: RetentionPolicy::KeepForever,
+            updated_by: None,
+            updated_at: None,
+        },
+    })
+}
+
+// Delete message (soft delete)
+#[update]
+fn delete_message(message_id: u64) -> Result<(), String> {
+    let caller = get_caller();
+    
+    let message = MESSAGES
+        .with(|messages| messages.borrow().get(&message_id))
+        .map(Message::from)
+        .ok_or_else(|| "Message not found".to_string())?;
+    
+    // Senders delete their own messages; group admins may delete any
+    if message.sender_id != caller && !can_delete_others_messages(&message.conversation_id, &caller) {
+        return Err("Unauthorized: Only sender or a conversation admin can delete message".to_string());
+    }
+    
+    soft_delete_message(message);
+    Ok(())
+}
+
+// Edit a message's content (sender only, within the edit window).
+// The replaced ciphertext is kept in the message's signed edit history.
+#[update]
+fn edit_message(message_id: u64, new_content: String) -> MessageResult {
+    let caller = get_caller();
+    let now = get_time();
+    
+    let fail = |error: String| MessageResult {
+        success: false,
+        message: None,
+        error: Some(error),
+    };
+    
+    if let Err(e) = validate_principal(&caller) {
+        return fail(format!("Invalid caller: {}", e));
+    }
+    
+    if let Err(e) = require_not_suspended(&caller) {
+        return fail(e);
+    }
+    
+    // Edits count against the same budget as sending
+    if let Err(e) = check_rate_limit(caller, 50, 60000) {
+        return fail(e);
+    }
+    
+    if let Err(e) = validate_text_length(&new_content, MAX_TEXT_LENGTH, "Message content") {
+        return fail(e);
+    }
+    
+    if let Err(e) = validate_phi_encryption(&new_content) {
+        return fail(format!("PHI validation failed: {}", e));
+    }
+    
+    let mut message = match MESSAGES.with(|messages| messages.borrow().get(&message_id)) {
+        Some(storable_message) => Message::from(storable_message),
+        None => return fail("Message not found".to_string()),
+    };
+    
+    if message.sender_id != caller {
+        return fail("Unauthorized: Only sender can edit message".to_string());
+    }
+    
+    if message.is_deleted || matches!(message.message_type, MessageType::System) {
+        return fail("This message can no longer be edited".to_string());
+    }
+    
+    let edit_window = get_setting(SETTING_EDIT_WINDOW_NS, DEFAULT_EDIT_WINDOW_NS);
+    if now.saturating_sub(message.timestamp) > edit_window {
+        return fail("Edit window has expired".to_string());
+    }
+    
+    let conversation = match CONVERSATIONS.with(|conversations| conversations.borrow().get(&message.conversation_id)) {
+        Some(conv) => Conversation::from(conv),
+        None => return fail("Conversation not found".to_string()),
+    };
+    
+    if let Err(e) = require_role(&conversation, &caller, ParticipantRole::Member) {
+        return fail(e);
+    }
+    
+    let encryption_key = match conversation_key(&conversation) {
+        Ok(key) => key,
+        Err(e) => return fail(format!("Failed to derive encryption key: {}", e)),
+    };
+    
+    let sanitized_content = match sanitize_message_content(&new_content, &message.message_type) {
+        Ok(sanitized) => sanitized,
+        Err(e) => return fail(e),
+    };
+    let encrypted_content = match encrypt_phi_data(&sanitized_content, &encryption_key) {
+        Ok(encrypted) => serde_json::to_string(&encrypted).unwrap_or_else(|_| sanitized_content.clone()),
+        Err(e) => return fail(format!("Failed to encrypt message content: {}", e)),
+    };
+    
+    let previous_signature = get_message_edits(message_id)
+        .last()
+        .map(|edit| edit.signature.clone())
+        .unwrap_or_default();
+    
+    let signature = match sign_message_edit(
+        &encryption_key,
+        &previous_signature,
+        message_id,
+        &caller,
+        now,
+        &message.content,
+    ) {
+        Ok(signature) => signature,
+        Err(e) => return fail(format!("Failed to sign edit: {}", e)),
+    };
+    
+    let edit = MessageEdit {
+        message_id,
+        editor: caller,
+        edited_at: now,
+        previous_content: message.content.clone(),
+        signature,
+    };
+    
+    MESSAGE_EDITS.with(|edits| {
+        edits.borrow_mut().insert(
+            scoped_key(&message_edit_scope(message_id), generate_next_id()),
+            StorableMessageEdit::from(edit),
+        );
+    });
+    
+    message.content = encrypted_content;
+    message.edited_at = Some(now);
+    MESSAGES.with(|messages| {
+        messages.borrow_mut().insert(message_id, StorableMessage::from(message.clone()));
+    });
+    screen_for_crisis(&message, &sanitized_content);
+    
+    record_sync_event(
+        &conversation.participants,
+        SyncEventType::MessageEdited,
+        Some(&conversation.id),
+        Some(message_id),
+        None,
+    );
+    
+    MessageResult {
+        success: true,
+        message: Some(message),
+        error: None,
+    }
+}
+
+// Edit history of a message with prior versions decrypted (participants only)
+#[query]
+fn get_message_edit_history(message_id: u64) -> Result<MessageEditHistory, String> {
+    let caller = get_caller();
+    
+    let message = MESSAGES
+        .with(|messages| messages.borrow().get(&message_id))
+        .map(Message::from)
+        .ok_or_else(|| "Message not found".to_string())?;
+    
+    let conversation = CONVERSATIONS
+        .with(|conversations| conversations.borrow().get(&message.conversation_id))
+        .map(Conversation::from)
+        .ok_or_else(|| "Conversation not found".to_string())?;
+    
+    if !is_participant(&conversation, &caller) {
+        return Err("Unauthorized: Not a participant in this conversation".to_string());
+    }
+    
+    let key = conversation_key(&conversation)?;
+    let key_participants = conversation_key_participants(&conversation);
+    let edits = get_message_edits(message_id);
+    let chain_valid = verify_message_edit_chain(&key, &edits);
+    
+    let edits = edits
+        .into_iter()
+        .map(|mut edit| {
+            if let Ok(content) = decrypt_message_content(&edit.previous_content, &key_participants) {
+                edit.previous_content = content;
+            }
+            edit
+        })
+        .collect();
+    
+    Ok(MessageEditHistory {
+        message_id,
+        edits,
+        chain_valid,
+    })
+}
+
+// Configure how long senders may edit their messages (controllers only)
+#[update]
+fn set_message_edit_window(window_ns: u64) -> Result<(), String> {
+    require_controller(&get_caller())?;
+    set_setting(SETTING_EDIT_WINDOW_NS, window_ns);
+    Ok(())
+}
+
+#[query]
+fn get_message_edit_window() -> u64 {
+    get_setting(SETTING_EDIT_WINDOW_NS, DEFAULT_EDIT_WINDOW_NS)
+}
+
+#[update]
+fn set_sanitization_policy(policy: SanitizationPolicy) -> Result<SanitizationPolicy, String> {
+    require_controller(&get_caller())?;
+    
+    if policy.max_length == 0 || policy.max_length > MAX_TEXT_LENGTH as u64 {
+        return Err(format!("max_length must be between 1 and {}", MAX_TEXT_LENGTH));
+    }
+    
+    SANITIZATION_POLICIES.with(|policies| {
+        policies.borrow_mut().insert(
+            message_type_name(&policy.message_type),
+            StorableSanitizationPolicy {
+                max_length: policy.max_length,
+                allow_newlines: policy.allow_newlines,
+            },
+        );
+    });
+    
+    Ok(policy)
+}
+
+// Effective policy for every message type
+#[query]
+fn get_sanitization_policies() -> Vec<SanitizationPolicy> {
+    [
+        MessageType::Text,
+        MessageType::Image,
+        MessageType::File,
+        MessageType::Audio,
+        MessageType::Video,
+        MessageType::System,
+        MessageType::RichText,
+    ]
+    .iter()
+    .map(get_sanitization_policy)
+    .collect()
+}
+
+// React to a message with an emoji (once per user and emoji)
+#[update]
+fn add_reaction(message_id: u64, emoji: String) -> Result<Vec<ReactionSummary>, String> {
+    let caller = get_caller();
+    validate_principal(&caller)?;
+    validate_emoji(&emoji)?;
+    
+    require_not_suspended(&caller)?;
+    
+    let (_, conversation) = get_message_for_participant(message_id, &caller)?;
+    require_role(&conversation, &caller, ParticipantRole::Member)?;
+    
+    let key = reaction_key(message_id, &emoji, &caller);
+    let already_reacted = REACTIONS.with(|reactions| reactions.borrow().contains_key(&key));
+    
+    if !already_reacted {
+        let own_reactions = get_message_reactions_list(message_id)
+            .iter()
+            .filter(|reaction| reaction.user_id == caller)
+            .count();
+        if own_reactions >= MAX_REACTIONS_PER_USER_PER_MESSAGE {
+            return Err("Too many reactions on this message".to_string());
+        }
+        
+        REACTIONS.with(|reactions| {
+            reactions.borrow_mut().insert(key, get_time());
+        });
+        
+        record_sync_event(
+            &conversation.participants,
+            SyncEventType::ReactionChanged,
+            Some(&conversation.id),
+            Some(message_id),
+            None,
+        );
+    }
+    
+    Ok(summarize_reactions(message_id, &caller))
+}
+
+// Remove the caller's reaction
+#[update]
+fn remove_reaction(message_id: u64, emoji: String) -> Result<Vec<ReactionSummary>, String> {
+    let caller = get_caller();
+    validate_principal(&caller)?;
+    
+    let (_, conversation) = get_message_for_participant(message_id, &caller)?;
+    
+    let removed = REACTIONS.with(|reactions| {
+        reactions.borrow_mut().remove(&reaction_key(message_id, &emoji, &caller))
+    });
+    
+    if removed.is_some() {
+        record_sync_event(
+            &conversation.participants,
+            SyncEventType::ReactionChanged,
+            Some(&conversation.id),
+            Some(message_id),
+            None,
+        );
+    }
+    
+    Ok(summarize_reactions(message_id, &caller))
+}
+
+// Everyone who reacted to a message (participants only)
+#[query]
+fn get_message_reactions(message_id: u64) -> Result<Vec<Reaction>, String> {
+    let caller = get_caller();
+    get_message_for_participant(message_id, &caller)?;
+    
+    Ok(get_message_reactions_list(message_id))
+}
+
+// Archive conversation
+#[update]
+fn archive_conversation(conversation_id: String) -> Result<(), String> {
+    let caller = get_caller();
+    
+    let conversation = CONVERSATIONS.with(|conversations| {
+        conversations.borrow().get(&conversation_id)
+    });
+    
+    let mut conversation = match conversation {
+        Some(conv) => Conversation::from(conv),
+        None => return Err("Conversation not found".to_string()),
+    };
+    
+    // Archiving applies to everyone in the conversation, so it follows the settings rule
+    require_role(&conversation, &caller, settings_role(&conversation))?;
+    
+    let previous_updated_at = conversation.updated_at;
+    conversation.is_archived = true;
+    conversation.updated_at = get_time();
+    save_conversation(&conversation, Some(previous_updated_at));
+    
+    notify_conversation(&conversation_id, SyncEventType::ConversationUpdated, None);
+    Ok(())
+}
+
+// Members of a conversation with their roles
+#[query]
+fn get_conversation_members(conversation_id: String) -> Result<Vec<ConversationMember>, String> {
+    let caller = get_caller();
+    let conversation = get_conversation_for_participant(&conversation_id, &caller)?;
+    
+    Ok(conversation_members(&conversation))
+}
+
+// Add someone to a group or session conversation (admins and owners).
+// Only the owner can add another admin.
+#[update]
+fn add_participant(conversation_id: String, user_id: Principal, role: Option<ParticipantRole>) -> Result<Conversation, String> {
+    let caller = get_caller();
+    validate_principal(&caller)?;
+    validate_principal(&user_id)?;
+    
+    let previous = get_conversation_for_participant(&conversation_id, &caller)?;
+    require_mutable_membership(&previous)?;
+    let caller_role = require_role(&previous, &caller, ParticipantRole::Admin)?;
+    
+    let role = role.unwrap_or(ParticipantRole::Member);
+    if role == ParticipantRole::Owner {
+        return Err("Use set_participant_role to transfer ownership".to_string());
+    }
+    if role == ParticipantRole::Admin && caller_role != ParticipantRole::Owner {
+        return Err("Unauthorized: Only the owner can add admins".to_string());
+    }
+    
+    add_member(
+        &previous,
+        &user_id,
+        role,
+        &format!("{} added {}", caller.to_text(), user_id.to_text()),
+    )
+}
+
+// Remove someone with a lower role than the caller
+#[update]
+fn remove_participant(conversation_id: String, user_id: Principal) -> Result<Conversation, String> {
+    let caller = get_caller();
+    validate_principal(&caller)?;
+    
+    if user_id == caller {
+        return Err("Use leave_conversation to leave".to_string());
+    }
+    
+    let previous = get_conversation_for_participant(&conversation_id, &caller)?;
+    require_mutable_membership(&previous)?;
+    let caller_role = require_role(&previous, &caller, ParticipantRole::Admin)?;
+    
+    if !is_participant(&previous, &user_id) {
+        return Err("User is not a participant".to_string());
+    }
+    if get_participant_role(&conversation_id, &user_id).rank() >= caller_role.rank() {
+        return Err("Unauthorized: Cannot remove a participant with an equal or higher role".to_string());
+    }
+    
+    pin_key_participants(&previous);
+    
+    let mut conversation = previous.clone();
+    conversation.participants.retain(|participant| participant != &user_id);
+    conversation.updated_at = get_time();
+    save_membership_change(&conversation, &previous);
+    
+    post_system_message(&conversation_id, &format!("{} removed {}", caller.to_text(), user_id.to_text()))?;
+    
+    get_conversation_for_participant(&conversation_id, &caller)
+}
+
+// Leave a conversation. An owner's role passes to the longest-standing admin; the last
+// owner or admin has to promote someone first, and the last one out archives it.
+#[update]
+fn leave_conversation(conversation_id: String) -> Result<(), String> {
+    let caller = get_caller();
+    validate_principal(&caller)?;
+    
+    let previous = get_conversation_for_participant(&conversation_id, &caller)?;
+    require_mutable_membership(&previous)?;
+    
+    pin_key_participants(&previous);
+    
+    let mut conversation = previous.clone();
+    conversation.participants.retain(|participant| participant != &caller);
+    conversation.updated_at = get_time();
+    require_manager_remains(&conversation, &caller)?;
+    // Nobody is left to read it, so it goes to the archive rather than lingering empty
+    if conversation.participants.is_empty() {
+        conversation.is_archived = true;
+    }
+    
+    let mut successor = None;
+    if get_participant_role(&conversation_id, &caller) == ParticipantRole::Owner {
+        successor = conversation_members(&conversation)
+            .into_iter()
+            .filter(|member| member.role == ParticipantRole::Admin)
+            .min_by_key(|member| member.joined_at);
+    }
+    
+    save_membership_change(&conversation, &previous);
+    post_system_message(&conversation_id, &format!("{} left the conversation", caller.to_text()))?;
+    
+    if let Some(successor) = successor {
+        set_role_entry(&conversation_id, &successor.user_id, ParticipantRole::Owner, successor.joined_at);
+        post_system_message(&conversation_id, &format!("{} is now the owner", successor.user_id.to_text()))?;
+    }
+    
+    Ok(())
+}
+
+// Change a participant's role. Admins manage members and read-only members;
+// the owner also manages admins, and hands over ownership by making someone else owner.
+#[update]
+fn set_participant_role(conversation_id: String, user_id: Principal, role: ParticipantRole) -> Result<Vec<ConversationMember>, String> {
+    let caller = get_caller();
+    validate_principal(&caller)?;
+    
+    let conversation = get_conversation_for_participant(&conversation_id, &caller)?;
+    require_mutable_membership(&conversation)?;
+    let caller_role = require_role(&conversation, &caller, ParticipantRole::Admin)?;
+    
+    if user_id == caller {
+        return Err("Cannot change your own role".to_string());
+    }
+    if !is_participant(&conversation, &user_id) {
+        return Err("User is not a participant".to_string());
+    }
+    
+    let current_role = get_participant_role(&conversation_id, &user_id);
+    let touches_admins = current_role.rank() >= ParticipantRole::Admin.rank()
+        || role.rank() >= ParticipantRole::Admin.rank();
+    if touches_admins && caller_role != ParticipantRole::Owner {
+        return Err("Unauthorized: Only the owner can change admin roles".to_string());
+    }
+    
+    if current_role != role {
+        let joined_at = get_joined_at(&conversation, &user_id);
+        if role == ParticipantRole::Owner {
+            // A conversation has one owner
+            set_role_entry(&conversation_id, &caller, ParticipantRole::Admin, get_joined_at(&conversation, &caller));
+        }
+        set_role_entry(&conversation_id, &user_id, role.clone(), joined_at);
+        
+        notify_conversation(&conversation_id, SyncEventType::ConversationUpdated, None);
+        post_system_message(
+            &conversation_id,
+            &format!("{} changed the role of {} to {:?}", caller.to_text(), user_id.to_text(), role),
+        )?;
+    }
+    
+    Ok(conversation_members(&conversation))
+}
+
+// Create an invite token for a group or session conversation (admins and owners).
+// Only the owner can create invites that grant the admin role.
+#[update]
+async fn create_invite(
+    conversation_id: String,
+    role: Option<ParticipantRole>,
+    expires_at: Option<u64>,
+    max_uses: Option<u64>,
+    requires_approval: bool,
+) -> Result<ConversationInvite, String> {
+    let caller = get_caller();
+    validate_principal(&caller)?;
+    
+    let role = role.unwrap_or(ParticipantRole::Member);
+    validate_invite_creation(&conversation_id, &caller, &role)?;
+    
+    if expires_at.is_some_and(|expires_at| expires_at <= get_time()) {
+        return Err("Invite expiry must be in the future".to_string());
+    }
+    if max_uses == Some(0) {
+        return Err("Invite must allow at least one use".to_string());
+    }
+    
+    let token = generate_invite_token().await?;
+    validate_invite_creation(&conversation_id, &caller, &role)?;
+    
+    let now = get_time();
+    let invite = ConversationInvite {
+        token,
+        conversation_id: conversation_id.clone(),
+        created_by: caller,
+        role,
+        created_at: now,
+        expires_at,
+        max_uses,
+        uses: 0,
+        requires_approval,
+        revoked: false,
+    };
+    
+    save_invite(&invite);
+    CONVERSATION_INVITES.with(|index| {
+        index.borrow_mut().insert(format!("{}:{}", conversation_id, invite.token), ());
+    });
+    
+    Ok(invite)
+}
+
+// Invites of a conversation, including revoked and used-up ones (admins only)
+#[query]
+fn list_invites(conversation_id: String) -> Result<Vec<ConversationInvite>, String> {
+    let caller = get_caller();
+    let conversation = get_conversation_for_participant(&conversation_id, &caller)?;
+    require_role(&conversation, &caller, ParticipantRole::Admin)?;
+    
+    let prefix = format!("{}:", conversation_id);
+    let tokens: Vec<String> = CONVERSATION_INVITES.with(|index| {
+        index
+            .borrow()
+            .range(prefix.clone()..)
+            .take_while(|(key, _)| key.starts_with(&prefix))
+            .map(|(key, _)| key[prefix.len()..].to_string())
+            .collect()
+    });
+    
+    Ok(tokens.iter().filter_map(|token| get_invite(token)).collect())
+}
+
+#[update]
+fn revoke_invite(token: String) -> Result<(), String> {
+    let caller = get_caller();
+    validate_principal(&caller)?;
+    
+    let mut invite = get_invite(&token).ok_or_else(|| "Invite not found".to_string())?;
+    let conversation = get_conversation_for_participant(&invite.conversation_id, &caller)?;
+    require_role(&conversation, &caller, ParticipantRole::Admin)?;
+    
+    invite.revoked = true;
+    save_invite(&invite);
+    Ok(())
+}
+
+// Join a conversation with an invite token, or ask to join if the invite needs approval
+#[update]
+fn redeem_invite(token: String) -> Result<InviteRedemption, String> {
+    let caller = get_caller();
+    validate_principal(&caller)?;
+    
+    // Slows down token guessing
+    check_rate_limit(caller, 10, 60000)?;
+    
+    let now = get_time();
+    let invite = get_invite(&token).ok_or_else(|| "Invite not found".to_string())?;
+    validate_invite_usable(&invite, now)?;
+    
+    let conversation = CONVERSATIONS
+        .with(|conversations| conversations.borrow().get(&invite.conversation_id))
+        .map(Conversation::from)
+        .ok_or_else(|| "Conversation not found".to_string())?;
+    
+    if is_participant(&conversation, &caller) {
+        return Err("Already a participant in this conversation".to_string());
+    }
+    
+    let request_key = join_request_key(&conversation.id, &caller);
+    if JOIN_REQUESTS.with(|requests| requests.borrow().contains_key(&request_key)) {
+        return Err("Join request already pending".to_string());
+    }
+    
+    let redemption = if invite.requires_approval {
+        let request = JoinRequest {
+            conversation_id: conversation.id.clone(),
+            user_id: caller,
+            invite_token: token,
+            role: invite.role.clone(),
+            requested_at: now,
+        };
+        JOIN_REQUESTS.with(|requests| {
+            requests.borrow_mut().insert(request_key, StorableJoinRequest::from(request.clone()));
+        });
+        
+        record_sync_event(
+            &conversation_admins(&conversation),
+            SyncEventType::ConversationUpdated,
+            Some(&conversation.id),
+            None,
+            None,
+        );
+        InviteRedemption::PendingApproval(request)
+    } else {
+        let joined = add_member(
+            &conversation,
+            &caller,
+            invite.role.clone(),
+            &format!("{} joined with an invite", caller.to_text()),
+        )?;
+        count_invite_use(invite);
+        InviteRedemption::Joined(joined)
+    };
+    
+    Ok(redemption)
+}
+
+// Pending join requests of a conversation (admins only)
+#[query]
+fn list_join_requests(conversation_id: String) -> Result<Vec<JoinRequest>, String> {
+    let caller = get_caller();
+    let conversation = get_conversation_for_participant(&conversation_id, &caller)?;
+    require_role(&conversation, &caller, ParticipantRole::Admin)?;
+    
+    let prefix = format!("{}:", conversation_id);
+    Ok(JOIN_REQUESTS.with(|requests| {
+        requests
+            .borrow()
+            .range(prefix.clone()..)
+            .take_while(|(key, _)| key.starts_with(&prefix))
+            .map(|(_, storable)| JoinRequest::from(storable))
+            .collect()
+    }))
+}
+
+#[update]
+fn accept_join_request(conversation_id: String, user_id: Principal) -> Result<Conversation, String> {
+    let caller = get_caller();
+    validate_principal(&caller)?;
+    
+    let conversation = get_conversation_for_participant(&conversation_id, &caller)?;
+    require_role(&conversation, &caller, ParticipantRole::Admin)?;
+    
+    let request_key = join_request_key(&conversation_id, &user_id);
+    let request = JOIN_REQUESTS
+        .with(|requests| requests.borrow().get(&request_key))
+        .map(JoinRequest::from)
+        .ok_or_else(|| "Join request not found".to_string())?;
+    
+    // The invite may have been revoked, expired or used up since the request was made;
+    // such a request can never be approved, so it is dropped
+    let invite = get_invite(&request.invite_token)
+        .ok_or_else(|| "Invite not found".to_string())
+        .and_then(|invite| validate_invite_usable(&invite, get_time()).map(|_| invite));
+    let invite = match invite {
+        Ok(invite) => invite,
+        Err(e) => {
+            JOIN_REQUESTS.with(|requests| requests.borrow_mut().remove(&request_key));
+            return Err(format!("Join request can no longer be approved: {}", e));
+        }
+    };
+    
+    let joined = add_member(
+        &conversation,
+        &user_id,
+        request.role,
+        &format!("{} approved {} to join", caller.to_text(), user_id.to_text()),
+    )?;
+    
+    JOIN_REQUESTS.with(|requests| requests.borrow_mut().remove(&request_key));
+    count_invite_use(invite);
+    
+    Ok(joined)
+}
+
+#[update]
+fn reject_join_request(conversation_id: String, user_id: Principal) -> Result<(), String> {
+    let caller = get_caller();
+    validate_principal(&caller)?;
+    
+    let conversation = get_conversation_for_participant(&conversation_id, &caller)?;
+    require_role(&conversation, &caller, ParticipantRole::Admin)?;
+    
+    JOIN_REQUESTS
+        .with(|requests| requests.borrow_mut().remove(&join_request_key(&conversation_id, &user_id)))
+        .ok_or_else(|| "Join request not found".to_string())?;
+    
+    // The requester isn't a participant, so tell them directly
+    record_sync_event(&[user_id], SyncEventType::ConversationUpdated, Some(&conversation_id), None, None);
+    Ok(())
+}
+
+// Block a user. They are not told; their messages to the caller stop arriving
+// and presence and typing stop flowing in both directions.
+#[update]
+fn block_user(user_id: Principal) -> Result<(), String> {
+    let caller = get_caller();
+    validate_principal(&caller)?;
+    validate_principal(&user_id)?;
+    
+    if user_id == caller {
+        return Err("Cannot block yourself".to_string());
+    }
+    
+    let previous = BLOCKS.with(|blocks| {
+        blocks.borrow_mut().insert(block_key(&caller, &user_id), get_time())
+    });
+    
+    if previous.is_none() {
+        adjust_unread_counts_for_block(&caller, &user_id, true);
+    }
+    Ok(())
+}
+
+// Unblock a user. Messages sent while they were blocked stay hidden.
+#[update]
+fn unblock_user(user_id: Principal) -> Result<(), String> {
+    let caller = get_caller();
+    validate_principal(&caller)?;
+    
+    let removed = BLOCKS.with(|blocks| blocks.borrow_mut().remove(&block_key(&caller, &user_id)));
+    if removed.is_some() {
+        adjust_unread_counts_for_block(&caller, &user_id, false);
+    }
+    Ok(())
+}
+
+#[query]
+fn list_blocked_users() -> Vec<BlockedUser> {
+    let caller = get_caller();
+    let prefix = format!("{}:", caller.to_text());
+    
+    BLOCKS.with(|blocks| {
+        blocks
+            .borrow()
+            .range(prefix.clone()..)
+            .take_while(|(key, _)| key.starts_with(&prefix))
+            .filter_map(|(key, blocked_at)| {
+                Some(BlockedUser {
+                    user_id: Principal::from_text(&key[prefix.len()..]).ok()?,
+                    blocked_at,
+                })
+            })
+            .collect()
+    })
+}
+
+// Mute a conversation until the given time, or until unmuted when no time is given
+#[update]
+fn mute_conversation(conversation_id: String, until: Option<u64>) -> Result<ConversationMute, String> {
+    let caller = get_caller();
+    validate_principal(&caller)?;
+    get_conversation_for_participant(&conversation_id, &caller)?;
+    
+    let muted_until = until.unwrap_or(u64::MAX);
+    if muted_until <= get_time() {
+        return Err("Mute expiry must be in the future".to_string());
+    }
+    
+    MUTES.with(|mutes| {
+        mutes.borrow_mut().insert(mute_key(&caller, &conversation_id), muted_until);
+    });
+    
+    Ok(ConversationMute {
+        conversation_id,
+        muted_until,
+    })
+}
+
+#[update]
+fn unmute_conversation(conversation_id: String) -> Result<(), String> {
+    let caller = get_caller();
+    validate_principal(&caller)?;
+    
+    MUTES.with(|mutes| mutes.borrow_mut().remove(&mute_key(&caller, &conversation_id)));
+    Ok(())
+}
+
+// The caller's currently muted conversations
+#[query]
+fn get_muted_conversations() -> Vec<ConversationMute> {
+    let caller = get_caller();
+    let now = get_time();
+    let prefix = format!("{}:", caller.to_text());
+    
+    MUTES.with(|mutes| {
+        mutes
+            .borrow()
+            .range(prefix.clone()..)
+            .take_while(|(key, _)| key.starts_with(&prefix))
+            .filter(|(_, muted_until)| *muted_until > now)
+            .map(|(key, muted_until)| ConversationMute {
+                conversation_id: key[prefix.len()..].to_string(),
+                muted_until,
+            })
+            .collect()
+    })
+}
+
+// Changes for the caller since a sync cursor (0 for everything still retained).
+// Pass the returned next_seq as since_seq on the following call.
+#[query]
+fn sync(since_seq: u64, limit: Option<u64>) -> SyncResponse {
+    let caller = get_caller();
+    
+    if validate_principal(&caller).is_err() {
+        return SyncResponse {
+            events: Vec::new(),
+            next_seq: since_seq,
+            has_more: false,
+            reset_required: false,
+            typing: Vec::new(),
+        };
+    }
+    
+    let limit = limit.unwrap_or(100).clamp(1, MAX_SYNC_BATCH) as usize;
+    let scope = caller.to_text();
+    let prefix = format!("{}:", scope);
+    let start = scoped_key(&scope, since_seq.saturating_add(1));
+    
+    let pruned_up_to = SYNC_WATERMARKS.with(|watermarks| watermarks.borrow().get(&caller));
+    let reset_required = pruned_up_to
+        .map(|pruned_up_to| since_seq > 0 && since_seq < pruned_up_to)
+        .unwrap_or(false);
+    
+    let mut events: Vec<SyncEvent> = SYNC_EVENTS.with(|store| {
+        store
+            .borrow()
+            .range(start..)
+            .take_while(|(key, _)| key.starts_with(&prefix))
+            .take(limit + 1)
+            .map(|(_, storable)| SyncEvent::from(storable))
+            .collect()
+    });
+    
+    let has_more = events.len() > limit;
+    events.truncate(limit);
+    let next_seq = events.last().map(|event| event.seq).unwrap_or(since_seq);
+    
+    SyncResponse {
+        events,
+        next_seq,
+        has_more,
+        reset_required,
+        typing: active_typing_for(&caller, get_time()),
+    }
+}
+
+// Show or stop the caller's typing indicator in a conversation.
+// Indicators expire on their own, so clients re-send while the user keeps typing.
+#[update]
+fn set_typing(conversation_id: String, is_typing: bool) -> Result<(), String> {
+    let caller = get_caller();
+    validate_principal(&caller)?;
+    let conversation = get_conversation_for_participant(&conversation_id, &caller)?;
+    require_role(&conversation, &caller, ParticipantRole::Member)?;
+    
+    if !is_typing || !get_user_preferences_or_default(&caller).typing_indicators_enabled {
+        clear_typing(&conversation_id, &caller);
+        return Ok(());
+    }
+    
+    let now = get_time();
+    TYPING.with(|typing| {
+        let mut typing = typing.borrow_mut();
+        let typists = typing.entry(conversation_id).or_default();
+        typists.retain(|_, expires_at| *expires_at > now);
+        typists.insert(caller, now + TYPING_EXPIRY_NS);
+    });
+    
+    Ok(())
+}
+
+#[query]
+fn get_user_preferences() -> UserPreferences {
+    get_user_preferences_or_default(&get_caller())
+}
+
+// Opt out of sharing typing indicators; clears any indicator currently shown
+#[update]
+fn set_typing_indicators_enabled(enabled: bool) -> Result<UserPreferences, String> {
+    let caller = get_caller();
+    validate_principal(&caller)?;
+    
+    let mut preferences = get_user_preferences_or_default(&caller);
+    preferences.typing_indicators_enabled = enabled;
+    USER_PREFERENCES.with(|store| {
+        store.borrow_mut().insert(caller, StorableUserPreferences {
+            typing_indicators_enabled: enabled,
+        });
+    });
+    
+    if !enabled {
+        TYPING.with(|typing| {
+            let mut typing = typing.borrow_mut();
+            for typists in typing.values_mut() {
+                typists.remove(&caller);
+            }
+            typing.retain(|_, typists| !typists.is_empty());
+        });
+    }
+    
+    Ok(preferences)
+}
+
+// === SCHEDULED MESSAGE API ===
+
+// Queue a message for later delivery. It is checked now so obvious problems surface
+// immediately, and checked and encrypted again when it is delivered.
+#[update]
+fn schedule_message(
+    conversation_id: String,
+    content: String,
+    deliver_at: u64,
+    message_type: Option<MessageType>,
+) -> Result<ScheduledMessage, String> {
+    let caller = get_caller();
+    let now = get_time();
+    validate_principal(&caller)?;
+    check_rate_limit(caller, 50, 60000)?;
+    require_not_suspended(&caller)?;
+    
+    let message_type = message_type.unwrap_or(MessageType::Text);
+    if !matches!(message_type, MessageType::Text | MessageType::RichText) {
+        return Err("Only text and rich text messages can be scheduled".to_string());
+    }
+    
+    if deliver_at <= now {
+        return Err("Delivery time must be in the future".to_string());
+    }
+    if deliver_at - now > MAX_SCHEDULE_AHEAD_NS {
+        return Err("Messages can be scheduled at most one year ahead".to_string());
+    }
+    
+    validate_text_length(&content, MAX_TEXT_LENGTH, "Message content")?;
+    let sanitized_content = sanitize_message_content(&content, &message_type)?;
+    
+    let conversation = get_conversation_for_participant(&conversation_id, &caller)?;
+    require_role(&conversation, &caller, ParticipantRole::Member)?;
+    
+    if scheduled_message_ids_for(&caller).len() >= MAX_SCHEDULED_PER_USER {
+        return Err(format!("You can have at most {} scheduled messages", MAX_SCHEDULED_PER_USER));
+    }
+    
+    let encryption_key = conversation_key(&conversation)?;
+    let encrypted = encrypt_phi_data(&sanitized_content, &encryption_key)?;
+    
+    let scheduled = ScheduledMessage {
+        id: generate_next_id(),
+        conversation_id,
+        sender_id: caller,
+        content: serde_json::to_string(&encrypted).map_err(|_| "Failed to serialize encrypted content".to_string())?,
+        message_type,
+        deliver_at,
+        created_at: now,
+        status: ScheduledMessageStatus::Pending,
+        failure_reason: None,
+    };
+    save_scheduled_message(&scheduled);
+    schedule_delivery_timer(scheduled.id, deliver_at - now);
+    
+    Ok(ScheduledMessage {
+        content: sanitized_content,
+        ..scheduled
+    })
+}
+
+// Cancel a pending scheduled message, or dismiss one whose delivery failed
+#[update]
+fn cancel_scheduled_message(scheduled_id: u64) -> Result<(), String> {
+    let caller = get_caller();
+    
+    let scheduled = get_scheduled_message(scheduled_id)
+        .filter(|scheduled| scheduled.sender_id == caller)
+        .ok_or_else(|| "Scheduled message not found".to_string())?;
+    
+    remove_scheduled_message(&scheduled);
+    Ok(())
+}
+
+// The caller's pending and failed scheduled messages, soonest first
+#[query]
+fn list_scheduled_messages(conversation_id: Option<String>) -> Vec<ScheduledMessage> {
+    let caller = get_caller();
+    
+    let mut scheduled: Vec<ScheduledMessage> = scheduled_message_ids_for(&caller)
+        .into_iter()
+        .filter_map(get_scheduled_message)
+        .filter(|scheduled| conversation_id.is_none() || conversation_id.as_ref() == Some(&scheduled.conversation_id))
+        .map(|mut scheduled| {
+            let conversation = CONVERSATIONS.with(|conversations| conversations.borrow().get(&scheduled.conversation_id));
+            if let Some(conversation) = conversation.map(Conversation::from) {
+                if let Ok(content) = decrypt_message_content(&scheduled.content, &conversation_key_participants(&conversation)) {
+                    scheduled.content = content;
+                }
+            }
+            scheduled
+        })
+        .collect();
+    scheduled.sort_by_key(|scheduled| (scheduled.deliver_at, scheduled.id));
+    
+    scheduled
+}
+
+// === MODERATION API ===
+
+// Report a message for review. With share_content the reporter consents to moderators
+// reading the decrypted message; otherwise they only see the report itself.
+#[update]
+fn report_message(
+    message_id: u64,
+    reason: ReportReason,
+    details: Option<String>,
+    share_content: bool,
+) -> Result<MessageReport, String> {
+    let caller = get_caller();
+    validate_principal(&caller)?;
+    check_rate_limit(caller, 10, 60000)?;
+    
+    if let Some(details) = &details {
+        validate_text_length(details, MAX_REPORT_DETAILS_LENGTH, "Report details")?;
+    }
+    
+    let (message, conversation) = get_message_for_participant(message_id, &caller)?;
+    if message.sender_id == caller {
+        return Err("Cannot report your own message".to_string());
+    }
+    if matches!(message.message_type, MessageType::System) {
+        return Err("System messages cannot be reported".to_string());
+    }
+    
+    let already_reported = REPORTS_BY_REPORTER.with(|index| index.borrow().contains_key(&reporter_key(message_id, &caller)));
+    if already_reported {
+        return Err("You have already reported this message".to_string());
+    }
+    
+    let escrowed_content = if share_content {
+        let decrypted = decrypt_message_for_participants(message.clone(), &conversation_key_participants(&conversation));
+        let encrypted = encrypt_phi_data(&decrypted.content, &moderation_escrow_key())?;
+        Some(serde_json::to_string(&encrypted).map_err(|_| "Failed to serialize encrypted content".to_string())?)
+    } else {
+        None
+    };
+    
+    let report = MessageReport {
+        id: generate_next_id(),
+        message_id,
+        conversation_id: conversation.id,
+        reporter: caller,
+        reported_user: message.sender_id,
+        reason,
+        details: details.map(|details| sanitize_text(&details)),
+        escrowed_content,
+        status: ReportStatus::Open,
+        created_at: get_time(),
+        resolved_at: None,
+        resolved_by: None,
+    };
+    save_report_record(&report);
+    
+    // The reporter gets their report back without the escrowed copy
+    Ok(MessageReport {
+        escrowed_content: None,
+        ..report
+    })
+}
+
+// Open reports, oldest first. Pass the last report ID as cursor for the next page.
+#[query]
+fn get_report_queue(cursor: Option<u64>, limit: Option<u64>) -> Result<Vec<MessageReport>, String> {
+    require_moderator(&get_caller())?;
+    let limit = limit.unwrap_or(50).clamp(1, MAX_MODERATION_PAGE) as usize;
+    let start = cursor.map(|id| id.saturating_add(1)).unwrap_or(0);
+    
+    let report_ids: Vec<u64> = OPEN_REPORTS.with(|open| {
+        open.borrow().range(start..).map(|(id, _)| id).take(limit).collect()
+    });
+    
+    Ok(report_ids
+        .into_iter()
+        .filter_map(get_report_record)
+        .map(report_for_moderator)
+        .collect())
+}
+
+#[query]
+fn get_report(report_id: u64) -> Result<MessageReport, String> {
+    require_moderator(&get_caller())?;
+    
+    get_report_record(report_id)
+        .map(report_for_moderator)
+        .ok_or_else(|| "Report not found".to_string())
+}
+
+// Decide an open report. Every decision, including dismissal, goes into the audit trail.
+#[update]
+fn resolve_report(report_id: u64, action: ModerationAction, note: Option<String>) -> Result<MessageReport, String> {
+    let caller = get_caller();
+    require_moderator(&caller)?;
+    
+    if let Some(note) = &note {
+        validate_text_length(note, MAX_MODERATION_NOTE_LENGTH, "Moderation note")?;
+    }
+    
+    let mut report = get_report_record(report_id).ok_or_else(|| "Report not found".to_string())?;
+    if report.status != ReportStatus::Open {
+        return Err("Report has already been resolved".to_string());
+    }
+    if matches!(action, ModerationAction::LiftSuspension) {
+        return Err("Use lift_suspension to lift a suspension".to_string());
+    }
+    
+    apply_moderation_action(&action, &report.reported_user, Some(report.message_id))?;
+    
+    report.status = if matches!(action, ModerationAction::Dismiss) {
+        ReportStatus::Dismissed
+    } else {
+        ReportStatus::Actioned
+    };
+    report.resolved_at = Some(get_time());
+    report.resolved_by = Some(caller);
+    save_report_record(&report);
+    
+    record_moderation_decision(
+        caller,
+        action,
+        report.reported_user,
+        Some(report_id),
+        Some(report.message_id),
+        note.map(|note| sanitize_text(&note)),
+    );
+    
+    Ok(report_for_moderator(report))
+}
+
+// Suspend or warn a user without a report, or lift a suspension early
+#[update]
+fn moderate_user(user_id: Principal, action: ModerationAction, note: Option<String>) -> Result<AccountStanding, String> {
+    let caller = get_caller();
+    require_moderator(&caller)?;
+    
+    if let Some(note) = &note {
+        validate_text_length(note, MAX_MODERATION_NOTE_LENGTH, "Moderation note")?;
+    }
+    if matches!(action, ModerationAction::Dismiss | ModerationAction::RemoveMessage) {
+        return Err("Dismissing reports and removing messages require a report".to_string());
+    }
+    
+    apply_moderation_action(&action, &user_id, None)?;
+    record_moderation_decision(caller, action, user_id, None, None, note.map(|note| sanitize_text(&note)));
+    
+    Ok(get_account_standing(&user_id))
+}
+
+// Moderation decisions, newest first. Pass the last entry ID as cursor for older ones.
+#[query]
+fn get_moderation_audit(cursor: Option<u64>, limit: Option<u64>) -> Result<Vec<ModerationAuditEntry>, String> {
+    require_moderator(&get_caller())?;
+    let limit = limit.unwrap_or(50).clamp(1, MAX_MODERATION_PAGE) as usize;
+    let end = cursor.unwrap_or(u64::MAX);
+    
+    Ok(MODERATION_AUDIT.with(|audit| {
+        audit
+            .borrow()
+            .range(..end)
+            .rev()
+            .take(limit)
+            .map(|(_, entry)| ModerationAuditEntry::from(entry))
+            .collect()
+    }))
+}
+
+// The caller's warnings and suspension
+#[query]
+fn get_my_account_standing() -> AccountStanding {
+    get_account_standing(&get_caller())
+}
+
+#[update]
+fn add_moderator(user_id: Principal) -> Result<(), String> {
+    require_controller(&get_caller())?;
+    validate_principal(&user_id)?;
+    
+    MODERATORS.with(|moderators| {
+        moderators.borrow_mut().insert(user_id, get_time());
+    });
+    Ok(())
+}
+
+#[update]
+fn remove_moderator(user_id: Principal) -> Result<(), String> {
+    require_controller(&get_caller())?;
+    
+    MODERATORS.with(|moderators| moderators.borrow_mut().remove(&user_id));
+    Ok(())
+}
+
+#[query]
+fn list_moderators() -> Result<Vec<Principal>, String> {
+    require_moderator(&get_caller())?;
+    
+    Ok(MODERATORS.with(|moderators| {
+        moderators.borrow().iter().map(|(user_id, _)| user_id).collect()
+    }))
+}
+
+// === CRISIS ESCALATION API ===
+
+// Client-side crisis flag, for E2E conversations the canister can't screen and for
+// anything the lexicon missed. Takes the same escalation path as a lexicon match.
+#[update]
+fn flag_crisis_message(message_id: u64, severity: CrisisSeverity) -> Result<CrisisEscalation, String> {
+    let caller = get_caller();
+    validate_principal(&caller)?;
+    check_rate_limit(caller, 10, 60000)?;
+    
+    let (message, _) = get_message_for_participant(message_id, &caller)?;
+    if matches!(message.message_type, MessageType::System) {
+        return Err("System messages cannot be flagged".to_string());
+    }
+    
+    Ok(escalate_crisis(&message, EscalationSource::ClientReport, Some(caller), severity, Vec::new()))
+}
+
+// Unresolved escalations, oldest first, with urgent ones at the top of each page.
+// Pass the highest ID seen as cursor for the next page.
+#[query]
+fn get_crisis_escalations(cursor: Option<u64>, limit: Option<u64>) -> Result<Vec<CrisisEscalation>, String> {
+    require_on_call_clinician(&get_caller())?;
+    let limit = limit.unwrap_or(50).clamp(1, MAX_ESCALATION_PAGE) as usize;
+    let start = cursor.map(|id| id.saturating_add(1)).unwrap_or(0);
+    
+    let mut escalations: Vec<CrisisEscalation> = OPEN_ESCALATIONS.with(|open| {
+        open.borrow()
+            .range(start..)
+            .take(limit)
+            .filter_map(|(id, _)| get_escalation(id))
+            .collect()
+    });
+    escalations.sort_by(|a, b| b.severity.cmp(&a.severity).then(a.id.cmp(&b.id)));
+    
+    Ok(escalations)
+}
+
+#[query]
+fn get_crisis_escalation(escalation_id: u64) -> Result<CrisisEscalation, String> {
+    require_on_call_clinician(&get_caller())?;
+    get_escalation(escalation_id).ok_or_else(|| "Escalation not found".to_string())
+}
+
+// Claim an escalation so other clinicians know it's being handled
+#[update]
+fn acknowledge_crisis_escalation(escalation_id: u64) -> Result<CrisisEscalation, String> {
+    let caller = get_caller();
+    require_on_call_clinician(&caller)?;
+    
+    let mut escalation = get_escalation(escalation_id).ok_or_else(|| "Escalation not found".to_string())?;
+    if escalation.status != EscalationStatus::Open {
+        return Err("Escalation has already been acknowledged".to_string());
+    }
+    
+    escalation.status = EscalationStatus::Acknowledged;
+    escalation.acknowledged_by = Some(caller);
+    escalation.acknowledged_at = Some(get_time());
+    save_escalation(&escalation);
+    
+    Ok(escalation)
+}
+
+#[update]
+fn resolve_crisis_escalation(escalation_id: u64, note: Option<String>) -> Result<CrisisEscalation, String> {
+    let caller = get_caller();
+    require_on_call_clinician(&caller)?;
+    
+    if let Some(note) = &note {
+        validate_text_length(note, MAX_MODERATION_NOTE_LENGTH, "Resolution note")?;
+    }
+    
+    let mut escalation = get_escalation(escalation_id).ok_or_else(|| "Escalation not found".to_string())?;
+    if escalation.status == EscalationStatus::Resolved {
+        return Err("Escalation has already been resolved".to_string());
+    }
+    
+    let now = get_time();
+    if escalation.acknowledged_by.is_none() {
+        escalation.acknowledged_by = Some(caller);
+        escalation.acknowledged_at = Some(now);
+    }
+    escalation.status = EscalationStatus::Resolved;
+    escalation.resolved_at = Some(now);
+    escalation.resolution_note = note.map(|note| sanitize_text(&note));
+    save_escalation(&escalation);
+    
+    Ok(escalation)
+}
+
+#[query]
+fn list_crisis_terms() -> Result<Vec<CrisisTerm>, String> {
+    require_on_call_clinician(&get_caller())?;
+    
+    Ok(CRISIS_LEXICON.with(|lexicon| {
+        lexicon.borrow().iter().map(|(_, term)| CrisisTerm::from(term)).collect()
+    }))
+}
+
+#[update]
+fn add_crisis_term(language: String, phrase: String, severity: CrisisSeverity) -> Result<CrisisTerm, String> {
+    require_controller(&get_caller())?;
+    validate_crisis_language(&language)?;
+    validate_text_length(&phrase, MAX_CRISIS_PHRASE_LENGTH, "Crisis phrase")?;
+    
+    let phrase = normalize_crisis_text(&phrase);
+    if phrase.is_empty() {
+        return Err("Crisis phrase must contain letters or digits".to_string());
+    }
+    
+    let key = crisis_term_key(&language, &phrase);
+    let exists = CRISIS_LEXICON.with(|lexicon| lexicon.borrow().contains_key(&key));
+    if !exists && CRISIS_LEXICON.with(|lexicon| lexicon.borrow().len()) >= MAX_CRISIS_TERMS {
+        return Err(format!("Crisis lexicon is limited to {} terms", MAX_CRISIS_TERMS));
+    }
+    
+    let term = CrisisTerm {
+        language,
+        phrase,
+        severity,
+        added_at: get_time(),
+    };
+    CRISIS_LEXICON.with(|lexicon| {
+        lexicon.borrow_mut().insert(key, StorableCrisisTerm::from(term.clone()));
+    });
+    
+    Ok(term)
+}
+
+#[update]
+fn remove_crisis_term(language: String, phrase: String) -> Result<(), String> {
+    require_controller(&get_caller())?;
+    
+    let key = crisis_term_key(&language, &normalize_crisis_text(&phrase));
+    CRISIS_LEXICON
+        .with(|lexicon| lexicon.borrow_mut().remove(&key))
+        .map(|_| ())
+        .ok_or_else(|| "Crisis term not found".to_string())
+}
+
+#[update]
+fn add_on_call_clinician(user_id: Principal) -> Result<(), String> {
+    require_controller(&get_caller())?;
+    validate_principal(&user_id)?;
+    
+    ON_CALL_CLINICIANS.with(|clinicians| {
+        clinicians.borrow_mut().insert(user_id, get_time());
+    });
+    Ok(())
+}
+
+#[update]
+fn remove_on_call_clinician(user_id: Principal) -> Result<(), String> {
+    require_controller(&get_caller())?;
+    
+    ON_CALL_CLINICIANS.with(|clinicians| clinicians.borrow_mut().remove(&user_id));
+    Ok(())
+}
+
+#[query]
+fn list_on_call_clinicians() -> Result<Vec<Principal>, String> {
+    require_on_call_clinician(&get_caller())?;
+    
+    Ok(ON_CALL_CLINICIANS.with(|clinicians| {
+        clinicians.borrow().iter().map(|(user_id, _)| user_id).collect()
+    }))
+}
+
+// === RTC SESSION API ===
+
+// Create an RTC session for the participants of a conversation
+#[update]
+fn create_rtc_session(
+    conversation_id: String,
+    session_type: RTCSessionType,
+    quality_settings: QualitySettings,
+    therapy_session_id: Option<String>,
+) -> Result<RTCSession, String> {
+    let caller = get_caller();
+    let now = get_time();
+
+    // Validate caller principal
+    validate_principal(&caller)?;
+
+    // Validate conversation ID format
+    validate_conversation_id(&conversation_id)?;
+
+    let conversation = CONVERSATIONS.with(|conversations| {
+        conversations.borrow().get(&conversation_id)
+    });
+
+    let conversation = match conversation {
+        Some(conv) => Conversation::from(conv),
+        None => return Err("Conversation not found".to_string()),
+    };
+
+    require_role(&conversation, &caller, ParticipantRole::Member)?;
+
+    let session = RTCSession {
+        session_id: generate_session_id(),
+        conversation_id,
+        initiator_id: caller,
+        participants: conversation.participants.clone(),
+        session_type,
+        status: RTCSessionStatus::Pending,
+        created_at: now,
+        started_at: None,
+        ended_at: None,
+        declined_by: Vec::new(),
+        parent_session_id: None,
+        metadata: RTCSessionMetadata {
+            therapy_session_id,
+            recording_enabled: false,
+            encryption_key_id: conversation.metadata.encryption_key_id.clone(),
+            quality_settings,
+        },
+    };
+
+    save_rtc_session(&session);
+
+    RTC_SESSION_INDEX.with(|index| {
+        index.borrow_mut().insert(
+            scoped_key(&session.conversation_id, generate_next_id()),
+            session.session_id.clone(),
+        );
+    });
+
+    schedule_ring_timeout(session.session_id.clone(), RTC_RING_TIMEOUT_NS);
+
+    Ok(session)
+}
+
+// Join a pending session; the first join activates it
+#[update]
+fn join_rtc_session(session_id: String) -> Result<RTCSession, String> {
+    let caller = get_caller();
+    validate_principal(&caller)?;
+
+    let mut session = get_rtc_session_for_participant(&session_id, &caller)?;
+
+    if session.parent_session_id.is_some() {
+        return Err("Screen shares are started with respond_to_screen_share".to_string());
+    }
+
+    // Group rooms: everyone except admitted members waits to be admitted by a host
+    if let Some(mut room) = get_group_room(&session_id) {
+        if !is_session_open(&session) {
+            return Err("RTC session has already ended".to_string());
+        }
+
+        if room_role(&room, &caller).is_some() {
+            return Ok(session);
+        }
+
+        if room.removed.contains(&caller) {
+            return Err("You have been removed from this room".to_string());
+        }
+
+        if !room.waiting_room.contains(&caller) {
+            room.waiting_room.push(caller);
+            save_group_room(&room);
+        }
+
+        return Ok(session);
+    }
+
+    match session.status {
+        // The initiator joining their own call leaves it ringing; it only starts once
+        // someone else answers, so unanswered calls still end up Missed
+        RTCSessionStatus::Pending if caller == session.initiator_id => Ok(session),
+        RTCSessionStatus::Pending => {
+            session.status = RTCSessionStatus::Active;
+            session.started_at = Some(get_time());
+            save_rtc_session(&session);
+            post_call_notice(&session);
+            Ok(session)
+        }
+        RTCSessionStatus::Active => Ok(session),
+        _ => Err("RTC session has already ended".to_string()),
+    }
+}
+
+// Decline an incoming call; the call is declined once every invitee has declined
+#[update]
+fn decline_rtc_session(session_id: String) -> Result<RTCSession, String> {
+    let caller = get_caller();
+    validate_principal(&caller)?;
+
+    let mut session = get_rtc_session_for_participant(&session_id, &caller)?;
+
+    if !matches!(session.status, RTCSessionStatus::Pending) || session.parent_session_id.is_some() {
+        return Err("Only pending calls can be declined".to_string());
+    }
+
+    if session.initiator_id == caller {
+        return Err("The caller cannot decline their own call".to_string());
+    }
+
+    if !session.declined_by.contains(&caller) {
+        session.declined_by.push(caller);
+    }
+
+    let all_declined = session
+        .participants
+        .iter()
+        .filter(|participant| **participant != session.initiator_id)
+        .all(|participant| session.declined_by.contains(participant));
+
+    if all_declined {
+        session.status = RTCSessionStatus::Declined;
+        session.ended_at = Some(get_time());
+    }
+
+    save_rtc_session(&session);
+
+    if all_declined {
+        post_call_notice(&session);
+    }
+
+    Ok(session)
+}
+
+// End a session; recording always stops with the session.
+// Hanging up before anyone answered reports the call as missed.
+#[update]
+fn end_rtc_session(session_id: String) -> Result<RTCSession, String> {
+    let caller = get_caller();
+    validate_principal(&caller)?;
+
+    let mut session = get_rtc_session_for_participant(&session_id, &caller)?;
+
+    if !is_session_open(&session) {
+        return Err("RTC session has already ended".to_string());
+    }
+
+    if session.parent_session_id.is_some() {
+        return Err("Use stop_screen_share to end a screen share".to_string());
+    }
+
+    if let Some(room) = get_group_room(&session_id) {
+        if room_role(&room, &caller) != Some(RoomRole::Host) {
+            return Err("Only the host can end a group room".to_string());
+        }
+    }
+
+    session.status = match session.status {
+        RTCSessionStatus::Pending => RTCSessionStatus::Missed,
+        _ => RTCSessionStatus::Ended,
+    };
+    session.ended_at = Some(get_time());
+    session.metadata.recording_enabled = false;
+    save_rtc_session(&session);
+    revoke_screen_shares(&session_id, None);
+    post_call_notice(&session);
+
+    Ok(session)
+}
+
+// Get an RTC session the caller takes part in
+#[query]
+fn get_rtc_session(session_id: String) -> Option<RTCSession> {
+    let caller = get_caller();
+
+    get_rtc_session_for_participant(&session_id, &caller).ok()
+}
+
+// Record the caller's explicit consent to the session being recorded
+#[update]
+fn grant_recording_consent(session_id: String) -> Result<RecordingConsent, String> {
+    let caller = get_caller();
+    validate_principal(&caller)?;
+
+    let session = get_rtc_session_for_participant(&session_id, &caller)?;
+
+    if !is_session_open(&session) {
+        return Err("RTC session has already ended".to_string());
+    }
+
+    Ok(record_consent(&session_id, caller, ConsentAction::Granted))
+}
+
+// Withdraw consent; an ongoing recording is stopped and the conversation notified
+#[update]
+fn withdraw_recording_consent(session_id: String) -> Result<RecordingConsent, String> {
+    let caller = get_caller();
+    validate_principal(&caller)?;
+
+    let mut session = get_rtc_session_for_participant(&session_id, &caller)?;
+
+    let consent = record_consent(&session_id, caller, ConsentAction::Withdrawn);
+
+    if session.metadata.recording_enabled {
+        session.metadata.recording_enabled = false;
+        save_rtc_session(&session);
+
+        post_system_message(
+            &session.conversation_id,
+            "Recording stopped: a participant withdrew their consent to recording",
+        )?;
+    }
+
+    Ok(consent)
+}
+
+// Turn recording on or off (RecordSession permission); turning it on requires
+// consent from every participant
+#[update]
+fn set_session_recording(session_id: String, enabled: bool) -> Result<RTCSession, String> {
+    let caller = get_caller();
+    validate_principal(&caller)?;
+
+    let mut session = get_rtc_session_for_participant(&session_id, &caller)?;
+
+    if !is_session_open(&session) || session.parent_session_id.is_some() {
+        return Err("Recording can only be changed on an ongoing call".to_string());
+    }
+
+    require_session_permission(&session, &caller, SessionPermission::RecordSession)?;
+
+    if session.metadata.recording_enabled == enabled {
+        return Ok(session);
+    }
+
+    if enabled {
+        let trail = get_consent_trail(&session_id);
+        let missing = session
+            .participants
+            .iter()
+            .filter(|participant| !has_recording_consent(&trail, participant))
+            .count();
+
+        if missing > 0 {
+            return Err(format!(
+                "Recording requires consent from every participant ({} missing)",
+                missing
+            ));
+        }
+    }
+
+    session.metadata.recording_enabled = enabled;
+    save_rtc_session(&session);
+
+    let notice = if enabled {
+        "Recording started with the consent of all participants"
+    } else {
+        "Recording stopped"
+    };
+    post_system_message(&session.conversation_id, notice)?;
+
+    Ok(session)
+}
+
+// === GROUP ROOM API ===
+
+// Open a group therapy room for a conversation; the caller becomes its host
+#[update]
+fn create_group_room(
+    conversation_id: String,
+    quality_settings: QualitySettings,
+    therapy_session_id: Option<String>,
+) -> Result<RTCSession, String> {
+    let caller = get_caller();
+    let now = get_time();
+
+    // Validate caller principal
+    validate_principal(&caller)?;
+
+    // Validate conversation ID format
+    validate_conversation_id(&conversation_id)?;
+
+    let conversation = CONVERSATIONS.with(|conversations| {
+        conversations.borrow().get(&conversation_id)
+    });
+
+    let conversation = match conversation {
+        Some(conv) => Conversation::from(conv),
+        None => return Err("Conversation not found".to_string()),
+    };
+
+    require_role(&conversation, &caller, ParticipantRole::Member)?;
+
+    // The room is live as soon as the host opens it, so it never rings or goes missed
+    let session = RTCSession {
+        session_id: generate_session_id(),
+        conversation_id,
+        initiator_id: caller,
+        participants: conversation.participants.clone(),
+        session_type: RTCSessionType::TherapySession,
+        status: RTCSessionStatus::Active,
+        created_at: now,
+        started_at: Some(now),
+        ended_at: None,
+        declined_by: Vec::new(),
+        parent_session_id: None,
+        metadata: RTCSessionMetadata {
+            therapy_session_id,
+            recording_enabled: false,
+            encryption_key_id: conversation.metadata.encryption_key_id.clone(),
+            quality_settings,
+        },
+    };
+
+    let room = GroupRoom {
+        session_id: session.session_id.clone(),
+        members: vec![RoomMember {
+            principal: caller,
+            role: RoomRole::Host,
+            is_muted: false,
+            joined_at: now,
+        }],
+        waiting_room: Vec::new(),
+        removed: Vec::new(),
+    };
+
+    save_rtc_session(&session);
+    save_group_room(&room);
+
+    RTC_SESSION_INDEX.with(|index| {
+        index.borrow_mut().insert(
+            scoped_key(&session.conversation_id, generate_next_id()),
+            session.session_id.clone(),
+        );
+    });
+
+    post_call_notice(&session);
+
+    Ok(session)
+}
+
+// Get the room state (members, roles and waiting room) of a group session
+#[query]
+fn get_group_room_state(session_id: String) -> Result<GroupRoom, String> {
+    let caller = get_caller();
+    get_rtc_session_for_participant(&session_id, &caller)?;
+
+    get_group_room(&session_id).ok_or_else(|| "Not a group room".to_string())
+}
+
+// Admit a principal from the waiting room
+#[update]
+fn admit_room_participant(session_id: String, participant: Principal) -> Result<GroupRoom, String> {
+    let caller = get_caller();
+    let (_, mut room) = get_room_for_moderator(&session_id, &caller)?;
+
+    if !room.waiting_room.contains(&participant) {
+        return Err("Principal is not in the waiting room".to_string());
+    }
+
+    if room.members.len() >= MAX_GROUP_ROOM_PARTICIPANTS {
+        return Err(format!(
+            "Room is full ({} participants maximum)",
+            MAX_GROUP_ROOM_PARTICIPANTS
+        ));
+    }
+
+    room.waiting_room.retain(|waiting| waiting != &participant);
+    room.members.push(RoomMember {
+        principal: participant,
+        role: RoomRole::Attendee,
+        is_muted: false,
+        joined_at: get_time(),
+    });
+    save_group_room(&room);
+
+    Ok(room)
+}
+
+// Remove an attendee (or reject someone waiting); they cannot rejoin this room
+#[update]
+fn remove_room_participant(session_id: String, participant: Principal) -> Result<GroupRoom, String> {
+    let caller = get_caller();
+    let (_, mut room) = get_room_for_moderator(&session_id, &caller)?;
+
+    match room_role(&room, &participant) {
+        Some(RoomRole::Host) => return Err("The host cannot be removed".to_string()),
+        Some(RoomRole::CoHost) if room_role(&room, &caller) != Some(RoomRole::Host) => {
+            return Err("Only the host can remove a co-host".to_string());
+        }
+        _ => {}
+    }
+
+    room.members.retain(|member| member.principal != participant);
+    room.waiting_room.retain(|waiting| waiting != &participant);
+    if !room.removed.contains(&participant) {
+        room.removed.push(participant);
+    }
+    save_group_room(&room);
+    revoke_screen_shares(&session_id, Some(&participant));
+
+    Ok(room)
+}
+
+// Mute or unmute an attendee's audio
+#[update]
+fn set_room_participant_muted(session_id: String, participant: Principal, muted: bool) -> Result<GroupRoom, String> {
+    let caller = get_caller();
+    let (_, mut room) = get_room_for_moderator(&session_id, &caller)?;
+
+    if room_role(&room, &participant) == Some(RoomRole::Host) && participant != caller {
+        return Err("The host cannot be muted by others".to_string());
+    }
+
+    match room.members.iter_mut().find(|member| member.principal == participant) {
+        Some(member) => member.is_muted = muted,
+        None => return Err("Principal is not a member of this room".to_string()),
+    }
+    save_group_room(&room);
+
+    Ok(room)
+}
+
+// Promote an attendee to co-host or demote a co-host (host only)
+#[update]
+fn set_room_role(session_id: String, participant: Principal, role: RoomRole) -> Result<GroupRoom, String> {
+    let caller = get_caller();
+    let (_, mut room) = get_room_for_moderator(&session_id, &caller)?;
+
+    if room_role(&room, &caller) != Some(RoomRole::Host) {
+        return Err("Unauthorized: Only the host can change roles".to_string());
+    }
+
+    if role == RoomRole::Host {
+        return Err("Use transfer_room_host to hand off the host role".to_string());
+    }
+
+    if participant == caller {
+        return Err("The host cannot change their own role".to_string());
+    }
+
+    match room.members.iter_mut().find(|member| member.principal == participant) {
+        Some(member) => member.role = role,
+        None => return Err("Principal is not a member of this room".to_string()),
+    }
+    save_group_room(&room);
+
+    Ok(room)
+}
+
+// Hand the host role to another member; the previous host stays on as co-host
+#[update]
+fn transfer_room_host(session_id: String, new_host: Principal) -> Result<GroupRoom, String> {
+    let caller = get_caller();
+    let (_, mut room) = get_room_for_moderator(&session_id, &caller)?;
+
+    if room_role(&room, &caller) != Some(RoomRole::Host) {
+        return Err("Unauthorized: Only the host can hand off the host role".to_string());
+    }
+
+    if room_role(&room, &new_host).is_none() {
+        return Err("Principal is not a member of this room".to_string());
+    }
+
+    for member in room.members.iter_mut() {
+        if member.principal == new_host {
+            member.role = RoomRole::Host;
+        } else if member.principal == caller {
+            member.role = RoomRole::CoHost;
+        }
+    }
+    save_group_room(&room);
+
+    Ok(room)
+}
+
+// Leave a group room (or its waiting room). The host must hand off the role first.
+#[update]
+fn leave_group_room(session_id: String) -> Result<(), String> {
+    let caller = get_caller();
+    get_rtc_session_for_participant(&session_id, &caller)?;
+
+    let mut room = get_group_room(&session_id).ok_or_else(|| "Not a group room".to_string())?;
+
+    if room_role(&room, &caller) == Some(RoomRole::Host) {
+        return Err("The host must hand off the host role or end the room before leaving".to_string());
+    }
+
+    room.members.retain(|member| member.principal != caller);
+    room.waiting_room.retain(|waiting| waiting != &caller);
+    save_group_room(&room);
+    revoke_screen_shares(&session_id, Some(&caller));
+
+    Ok(())
+}
+
+// === SIGNALING API ===
+
+// Queue a WebRTC signal. Offers, answers and ICE candidates go to one peer;
+// hangups without a recipient fan out to every other member of the session.
+#[update]
+fn send_webrtc_signal(
+    session_id: String,
+    recipient_id: Option<Principal>,
+    signal_type: SignalType,
+    payload: String,
+) -> Result<Vec<String>, String> {
+    let caller = get_caller();
+    validate_principal(&caller)?;
+    validate_text_length(&payload, MAX_SIGNAL_PAYLOAD_LENGTH, "Signal payload")?;
+
+    let session = get_rtc_session_for_participant(&session_id, &caller)?;
+
+    if !is_session_open(&session) {
+        return Err("RTC session has already ended".to_string());
+    }
+
+    if session.parent_session_id.is_some() && !matches!(session.status, RTCSessionStatus::Active) {
+        return Err("Screen share has not been granted".to_string());
+    }
+
+    let members = signaling_members(&session);
+    if !members.contains(&caller) {
+        return Err("Unauthorized: Not admitted to this session".to_string());
+    }
+
+    let recipients = match (recipient_id, &signal_type) {
+        (Some(recipient), _) => {
+            if recipient == caller || !members.contains(&recipient) {
+                return Err("Recipient is not a member of this session".to_string());
+            }
+            vec![recipient]
+        }
+        (None, SignalType::Hangup) => members.into_iter().filter(|member| member != &caller).collect(),
+        (None, _) => return Err("Offers, answers and ICE candidates need a recipient".to_string()),
+    };
+
+    let ids = recipients
+        .into_iter()
+        .map(|recipient| enqueue_signal(&session_id, caller, recipient, signal_type.clone(), &payload).id)
+        .collect();
+
+    Ok(ids)
+}
+
+// Drain the caller's pending signals for a session; expired signals are dropped
+#[update]
+fn fetch_webrtc_signals(session_id: String) -> Result<Vec<WebRTCSignal>, String> {
+    let caller = get_caller();
+    validate_principal(&caller)?;
+    get_rtc_session_for_participant(&session_id, &caller)?;
+
+    let now = get_time();
+    let prefix = format!("{}:", signal_queue_scope(&session_id, &caller));
+
+    WEBRTC_SIGNALS.with(|signals| {
+        let mut signals = signals.borrow_mut();
+
+        let queued: Vec<(String, WebRTCSignal)> = signals
+            .range(prefix.clone()..)
+            .take_while(|(key, _)| key.starts_with(&prefix))
+            .map(|(key, storable)| (key, WebRTCSignal::from(storable)))
+            .collect();
+
+        for (key, signal) in &queued {
+            signals.remove(key);
+            SIGNAL_EXPIRY.with(|expiry| expiry.borrow_mut().remove(&signal_expiry_key(signal.expires_at, key)));
+        }
+
+        Ok(queued
+            .into_iter()
+            .map(|(_, signal)| signal)
+            .filter(|signal| signal.expires_at > now)
+            .collect())
+    })
+}
+
+// === SCREEN SHARE API ===
+
+// Ask the other participants for permission to share the caller's screen in an active call
+#[update]
+fn request_screen_share(parent_session_id: String) -> Result<ScreenShare, String> {
+    let caller = get_caller();
+    validate_principal(&caller)?;
+
+    let parent = get_rtc_session_for_participant(&parent_session_id, &caller)?;
+
+    if !matches!(parent.status, RTCSessionStatus::Active) {
+        return Err("Screen sharing requires an active call".to_string());
+    }
+
+    if parent.parent_session_id.is_some() {
+        return Err("Screen shares cannot be nested".to_string());
+    }
+
+    let members = signaling_members(&parent);
+    if !members.contains(&caller) {
+        return Err("Unauthorized: Not admitted to this session".to_string());
+    }
+
+    require_session_permission(&parent, &caller, SessionPermission::ShareScreen)?;
+
+    let busy = get_screen_shares_of(&parent_session_id)
+        .iter()
+        .any(|share| matches!(share.status, ScreenShareStatus::Requested | ScreenShareStatus::Granted));
+    if busy {
+        return Err("A screen share is already requested or in progress in this call".to_string());
+    }
+
+    let now = get_time();
+    let share_session = RTCSession {
+        session_id: generate_session_id(),
+        conversation_id: parent.conversation_id.clone(),
+        initiator_id: caller,
+        participants: members,
+        session_type: RTCSessionType::ScreenShare,
+        status: RTCSessionStatus::Pending,
+        created_at: now,
+        started_at: None,
+        ended_at: None,
+        declined_by: Vec::new(),
+        parent_session_id: Some(parent_session_id.clone()),
+        metadata: RTCSessionMetadata {
+            recording_enabled: false,
+            ..parent.metadata
+        },
+    };
+
+    let share = ScreenShare {
+        share_session_id: share_session.session_id.clone(),
+        parent_session_id,
+        presenter: caller,
+        status: ScreenShareStatus::Requested,
+        requested_at: now,
+        decided_by: None,
+        decided_at: None,
+        ended_at: None,
+    };
+
+    save_rtc_session(&share_session);
+    save_screen_share(&share);
+
+    Ok(share)
+}
+
+// Grant or deny a pending screen share request. In group rooms only the host
+// or a co-host decides; otherwise any other participant of the call can.
+#[update]
+fn respond_to_screen_share(share_session_id: String, grant: bool) -> Result<ScreenShare, String> {
+    let caller = get_caller();
+    validate_principal(&caller)?;
+
+    let (mut share, mut share_session) = get_screen_share_for_participant(&share_session_id, &caller)?;
+
+    if share.status != ScreenShareStatus::Requested {
+        return Err("Screen share request is no longer pending".to_string());
+    }
+
+    if share.presenter == caller {
+        return Err("The presenter cannot answer their own request".to_string());
+    }
+
+    if let Some(room) = get_group_room(&share.parent_session_id) {
+        if !matches!(room_role(&room, &caller), Some(RoomRole::Host) | Some(RoomRole::CoHost)) {
+            return Err("Unauthorized: Only the host or a co-host can answer screen share requests".to_string());
+        }
+    }
+
+    let now = get_time();
+    share.decided_by = Some(caller);
+    share.decided_at = Some(now);
+
+    if !grant {
+        return Ok(close_screen_share(share, share_session, ScreenShareStatus::Denied));
+    }
+
+    share.status = ScreenShareStatus::Granted;
+    share_session.status = RTCSessionStatus::Active;
+    share_session.started_at = Some(now);
+    save_rtc_session(&share_session);
+    save_screen_share(&share);
+
+    Ok(share)
+}
+
+// Stop a screen share (or withdraw a pending request)
+#[update]
+fn stop_screen_share(share_session_id: String) -> Result<ScreenShare, String> {
+    let caller = get_caller();
+    validate_principal(&caller)?;
+
+    let (share, share_session) = get_screen_share_for_participant(&share_session_id, &caller)?;
+
+    if !matches!(share.status, ScreenShareStatus::Requested | ScreenShareStatus::Granted) {
+        return Err("Screen share has already ended".to_string());
+    }
+
+    // Presenters stop their own share; anyone else watching revokes it
+    let status = if share.presenter == caller {
+        ScreenShareStatus::Stopped
+    } else {
+        if let Some(room) = get_group_room(&share.parent_session_id) {
+            if !matches!(room_role(&room, &caller), Some(RoomRole::Host) | Some(RoomRole::CoHost)) {
+                return Err("Unauthorized: Only the host or a co-host can stop another member's share".to_string());
+            }
+        }
+        ScreenShareStatus::Revoked
+    };
+
+    Ok(close_screen_share(share, share_session, status))
+}
+
+// All screen shares requested in a call, oldest first
+#[query]
+fn get_screen_shares(parent_session_id: String) -> Result<Vec<ScreenShare>, String> {
+    let caller = get_caller();
+    get_rtc_session_for_participant(&parent_session_id, &caller)?;
+
+    Ok(get_screen_shares_of(&parent_session_id))
+}
+
+// === CALL TELEMETRY API ===
+
+// Report periodic WebRTC stats for an active call
+#[update]
+fn report_call_stats(session_id: String, stats: CallStatsReport) -> Result<(), String> {
+    let caller = get_caller();
+    validate_principal(&caller)?;
+
+    let session = get_rtc_session_for_participant(&session_id, &caller)?;
+
+    if !matches!(session.status, RTCSessionStatus::Active) {
+        return Err("Stats can only be reported for active calls".to_string());
+    }
+
+    if !stats.packet_loss_percent.is_finite() || !(0.0..=100.0).contains(&stats.packet_loss_percent) {
+        return Err("Packet loss must be a percentage between 0 and 100".to_string());
+    }
+
+    push_telemetry_sample(CallStatsSample {
+        session_id,
+        reporter: caller,
+        timestamp: get_time(),
+        stats,
+    });
+
+    Ok(())
+}
+
+// Quality settings recommended for a session given its recent telemetry
+#[query]
+fn get_recommended_quality(session_id: String) -> Result<QualitySettings, String> {
+    let caller = get_caller();
+    let session = get_rtc_session_for_participant(&session_id, &caller)?;
+
+    let samples: Vec<CallStatsSample> = get_telemetry_samples(&session_id)
+        .into_iter()
+        .map(|(_, sample)| sample)
+        .collect();
+    let skip = samples.len().saturating_sub(TELEMETRY_RECENT_SAMPLES);
+
+    Ok(recommend_quality(&session.metadata.quality_settings, &samples[skip..]))
+}
+
+// Aggregate call quality of a session (participants and controllers only)
+#[query]
+fn get_call_quality_stats(session_id: String) -> Result<CallQualityStats, String> {
+    let caller = get_caller();
+    let session = get_rtc_session_record(&session_id)?;
+
+    if !session.participants.contains(&caller) && !ic_cdk::api::is_controller(&caller) {
+        return Err("Unauthorized: Not allowed to view call quality for this session".to_string());
+    }
+
+    let samples = get_telemetry_samples(&session_id)
+        .into_iter()
+        .map(|(_, sample)| sample)
+        .collect();
+
+    Ok(aggregate_call_quality(&session_id, samples))
+}
+
+// === PRESENCE API ===
+
+// Keep the caller online; pass idle=true while the user is inactive on the client
+#[update]
+fn presence_heartbeat(idle: bool) -> Result<UserPresence, String> {
+    let caller = get_caller();
+    validate_principal(&caller)?;
+
+    let now = get_time();
+    let mut presence = get_presence_record(&caller).unwrap_or(Presence {
+        user_id: caller,
+        last_heartbeat: now,
+        last_active: now,
+        manual_status: None,
+        manual_status_expires_at: None,
+    });
+
+    presence.last_heartbeat = now;
+    if !idle {
+        presence.last_active = now;
+    }
+    save_presence_record(presence);
+
+    Ok(resolve_presence(caller, now))
+}
+
+// Set or clear the caller's manual availability status, optionally until a given time
+#[update]
+fn set_presence_status(status: Option<AvailabilityStatus>, expires_at: Option<u64>) -> Result<UserPresence, String> {
+    let caller = get_caller();
+    validate_principal(&caller)?;
+
+    let now = get_time();
+    if let Some(expires_at) = expires_at {
+        if expires_at <= now {
+            return Err("Status expiry must be in the future".to_string());
+        }
+    }
+
+    let mut presence = get_presence_record(&caller).unwrap_or(Presence {
+        user_id: caller,
+        last_heartbeat: 0,
+        last_active: 0,
+        manual_status: None,
+        manual_status_expires_at: None,
     });
-    
-    MessageResult {
-        success: true,
-        message: Some(message),
-        error: None,
+
+    presence.manual_status_expires_at = status.as_ref().and(expires_at);
+    presence.manual_status = status;
+    save_presence_record(presence);
+
+    Ok(resolve_presence(caller, now))
+}
+
+// Presence of the given users; users who share no conversation with the caller are omitted
+#[query]
+fn get_presence(user_ids: Vec<Principal>) -> Vec<UserPresence> {
+    let caller = get_caller();
+    if validate_principal(&caller).is_err() {
+        return Vec::new();
+    }
+
+    let now = get_time();
+    let contacts = get_contacts(&caller);
+
+    user_ids
+        .into_iter()
+        .take(MAX_PRESENCE_BATCH)
+        .filter(|user_id| contacts.contains(user_id))
+        .map(|user_id| presence_for_viewer(user_id, &caller, now))
+        .collect()
+}
+
+// Presence of the other participants for a batch of the caller's conversations
+#[query]
+fn get_conversations_presence(conversation_ids: Vec<String>) -> Vec<ConversationPresence> {
+    let caller = get_caller();
+    if validate_principal(&caller).is_err() {
+        return Vec::new();
     }
+
+    let now = get_time();
+
+    conversation_ids
+        .into_iter()
+        .take(MAX_PRESENCE_BATCH)
+        .filter_map(|conversation_id| {
+            CONVERSATIONS.with(|conversations| conversations.borrow().get(&conversation_id))
+        })
+        .map(Conversation::from)
+        .filter(|conversation| is_participant(conversation, &caller))
+        .map(|conversation| ConversationPresence {
+            participants: conversation
+                .participants
+                .iter()
+                .filter(|participant| **participant != caller)
+                .map(|participant| presence_for_viewer(*participant, &caller, now))
+                .collect(),
+            conversation_id: conversation.id,
+        })
+        .collect()
 }
 
-// Get messages for a conversation
+// Call history of a conversation, newest first. Pass the returned cursor to page further back.
 #[query]
-fn get_conversation_messages(
+fn get_call_history(
     conversation_id: String,
+    cursor: Option<u64>,
     limit: Option<u64>,
-    offset: Option<u64>,
-) -> Vec<Message> {
+) -> Result<CallHistoryPage, String> {
     let caller = get_caller();
-    
+
     // Validate caller principal
-    if validate_principal(&caller).is_err() {
-        return Vec::new(); // Return empty vector for invalid principals
-    }
-    
+    validate_principal(&caller)?;
+
     // Validate conversation ID format
-    if validate_conversation_id(&conversation_id).is_err() {
-        return Vec::new(); // Return empty vector for invalid conversation IDs
-    }
-    
-    // Verify caller is participant in conversation
+    validate_conversation_id(&conversation_id)?;
+
     let conversation = CONVERSATIONS.with(|conversations| {
         conversations.borrow().get(&conversation_id)
     });
-    
+
     let conversation = match conversation {
         Some(conv) => Conversation::from(conv),
-        None => return vec![],
+        None => return Err("Conversation not found".to_string()),
     };
-    
+
     if !is_participant(&conversation, &caller) {
-        return vec![];
+        return Err("Unauthorized: Not a participant in this conversation".to_string());
     }
-    
-    let limit = limit.unwrap_or(50).min(100); // Max 100 messages per query
-    let offset = offset.unwrap_or(0);
-    
-    let mut messages = Vec::new();
-    let mut count = 0;
-    let mut skipped = 0;
-    
-    MESSAGES.with(|msg_store| {
-        let messages_ref = msg_store.borrow();
-        for (_, storable_message) in messages_ref.iter().rev() {
-            let mut message = Message::from(storable_message);
-            
-            if message.conversation_id == conversation_id && !message.is_deleted {
-                if skipped < offset {
-                    skipped += 1;
-                    continue;
-                }
-                
-                // Decrypt message content for authorized participant
-                match decrypt_message_content(&message.content, &conversation.participants) {
-                    Ok(decrypted_content) => {
-                        message.content = decrypted_content;
-                    },
-                    Err(_) => {
-                        // Keep encrypted content if decryption fails (shouldn't happen for valid participants)
-                    }
-                }
-                
-                // Decrypt attachments if present
-                message.attachments = message.attachments.into_iter().map(|mut attachment| {
-                    match decrypt_attachment_data(&attachment.encrypted_data, &conversation.participants) {
-                        Ok(decrypted_data) => {
-                            attachment.encrypted_data = decrypted_data;
-                        },
-                        Err(_) => {
-                            // Keep encrypted data if decryption fails
-                        }
-                    }
-                    attachment
-                }).collect();
-                
-                messages.push(message);
-                count += 1;
-                
-                if count >= limit {
-                    break;
-                }
-            }
-        }
-    });
-    
-    messages
-}
 
-// Get user's conversations
-#[query]
-fn get_user_conversations() -> Vec<Conversation> {
-    let caller = get_caller();
-    let mut user_conversations = Vec::new();
-    
-    CONVERSATIONS.with(|conversations| {
-        let conversations_ref = conversations.borrow();
-        for (_, storable_conversation) in conversations_ref.iter() {
-            let conversation = Conversation::from(storable_conversation);
-            
-            if is_participant(&conversation, &caller) && !conversation.is_archived {
-                user_conversations.push(conversation);
-            }
-        }
+    let limit = limit.unwrap_or(20).clamp(1, MAX_CALL_HISTORY_PAGE) as usize;
+    let start = scoped_key(&conversation_id, 0);
+    let end = scoped_key(&conversation_id, cursor.unwrap_or(u64::MAX));
+
+    let entries: Vec<(String, String)> = RTC_SESSION_INDEX.with(|index| {
+        index
+            .borrow()
+            .range(start..end)
+            .rev()
+            .take(limit + 1)
+            .collect()
     });
-    
-    // Sort by updated_at descending
-    user_conversations.sort_by(|a, b| b.updated_at.cmp(&a.updated_at));
-    
-    user_conversations
-}
 
-// Mark message as read
-#[update]
-fn mark_message_read(message_id: u64) -> Result<(), String> {
-    let caller = get_caller();
-    
-    MESSAGES.with(|messages| {
-        let mut messages_ref = messages.borrow_mut();
-        
-        match messages_ref.get(&message_id) {
-            Some(storable_message) => {
-                let mut message = Message::from(storable_message);
-                
-                // Only recipient can mark message as read
-                if message.recipient_id != caller {
-                    return Err("Unauthorized: Only recipient can mark message as read".to_string());
-                }
-                
-                message.is_read = true;
-                messages_ref.insert(message_id, StorableMessage::from(message));
-                Ok(())
-            }
-            None => Err("Message not found".to_string()),
-        }
-    })
-}
+    let next_cursor = if entries.len() > limit {
+        entries.get(limit - 1).and_then(|(key, _)| parse_scoped_id(key))
+    } else {
+        None
+    };
 
-// Delete message (soft delete)
-#[update]
-fn delete_message(message_id: u64) -> Result<(), String> {
-    let caller = get_caller();
-    
-    MESSAGES.with(|messages| {
-        let mut messages_ref = messages.borrow_mut();
-        
-        match messages_ref.get(&message_id) {
-            Some(storable_message) => {
-                let mut message = Message::from(storable_message);
-                
-                // Only sender can delete message
-                if message.sender_id != caller {
-                    return Err("Unauthorized: Only sender can delete message".to_string());
-                }
-                
-                message.is_deleted = true;
-                messages_ref.insert(message_id, StorableMessage::from(message));
-                Ok(())
-            }
-            None => Err("Message not found".to_string()),
-        }
-    })
+    let calls = entries
+        .into_iter()
+        .take(limit)
+        .filter_map(|(_, session_id)| get_rtc_session_record(&session_id).ok())
+        .map(to_call_summary)
+        .collect();
+
+    Ok(CallHistoryPage { calls, next_cursor })
 }
 
-// Archive conversation
-#[update]
-fn archive_conversation(conversation_id: String) -> Result<(), String> {
+// Full consent trail of a session for audits (participants and controllers only)
+#[query]
+fn get_recording_consent_trail(session_id: String) -> Result<Vec<RecordingConsent>, String> {
     let caller = get_caller();
-    
-    CONVERSATIONS.with(|conversations| {
-        let mut conversations_ref = conversations.borrow_mut();
-        
-        match conversations_ref.get(&conversation_id) {
-            Some(storable_conversation) => {
-                let mut conversation = Conversation::from(storable_conversation);
-                
-                if !is_participant(&conversation, &caller) {
-                    return Err("Unauthorized: Not a participant in this conversation".to_string());
-                }
-                
-                conversation.is_archived = true;
-                conversation.updated_at = get_time();
-                conversations_ref.insert(conversation_id, StorableConversation::from(conversation));
-                Ok(())
-            }
-            None => Err("Conversation not found".to_string()),
-        }
-    })
+    let session = get_rtc_session_record(&session_id)?;
+
+    if !session.participants.contains(&caller) && !ic_cdk::api::is_controller(&caller) {
+        return Err("Unauthorized: Not allowed to view this consent trail".to_string());
+    }
+
+    Ok(get_consent_trail(&session_id))
 }
 
 // Health check
@@ -1812,4 +9849,507 @@ fn get_stats() -> HashMap<String, u64> {
 }
 
 // Export Candid interface
-ic_cdk::export_candid!();
\ No newline at end of file
+ic_cdk::export_candid!();
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chain(links: &[(u64, u64)]) -> impl Fn(u64) -> Option<u64> + '_ {
+        move |id| links.iter().find(|(child, _)| *child == id).map(|(_, parent)| *parent)
+    }
+
+    #[test]
+    fn reply_chain_resolves_to_root() {
+        let links = [(3, 2), (2, 1)];
+        assert_eq!(follow_reply_chain(4, Some(3), chain(&links)), 1);
+        assert_eq!(follow_reply_chain(1, None, chain(&links)), 1);
+    }
+
+    #[test]
+    fn reply_chain_self_loop_is_its_own_root() {
+        assert_eq!(follow_reply_chain(5, Some(5), chain(&[])), 5);
+    }
+
+    #[test]
+    fn reply_chain_cycle_is_its_own_root() {
+        let links = [(7, 8), (8, 6)];
+        assert_eq!(follow_reply_chain(6, Some(7), chain(&links)), 6);
+        // A reply hanging off a cycle stops where it would revisit a message
+        assert_eq!(follow_reply_chain(9, Some(7), chain(&[(7, 8), (8, 7)])), 9);
+    }
+
+    fn test_message(id: u64, conversation_id: &str) -> Message {
+        Message {
+            id,
+            conversation_id: conversation_id.to_string(),
+            sender_id: Principal::anonymous(),
+            recipient_id: Principal::anonymous(),
+            content: String::new(),
+            message_type: MessageType::Text,
+            timestamp: id,
+            is_read: false,
+            is_deleted: false,
+            reply_to: None,
+            attachments: Vec::new(),
+            edited_at: None,
+            reactions: Vec::new(),
+            thread: None,
+            plain_text: None,
+        }
+    }
+
+    // Messages a full history page loads from MESSAGES, counted through the visibility filter
+    fn history_page_loads(conversation_id: &str) -> usize {
+        let loads = std::cell::Cell::new(0);
+        let page = conversation_page(conversation_id, None, None, 0, 100, |_| {
+            loads.set(loads.get() + 1);
+            true
+        });
+        assert_eq!(page.first().map(|message| message.conversation_id.as_str()), Some(conversation_id));
+        loads.get()
+    }
+
+    #[test]
+    fn history_cost_does_not_grow_with_unrelated_conversations() {
+        for id in 1..=30 {
+            store_message(&test_message(id, "conv_target"));
+        }
+        assert_eq!(history_page_loads("conv_target"), 30);
+
+        let mut next_id = 1_000;
+        for unrelated in 0..50 {
+            for _ in 0..100 {
+                store_message(&test_message(next_id, &format!("conv_other_{}", unrelated)));
+                next_id += 1;
+            }
+        }
+
+        assert_eq!(MESSAGES.with(|messages| messages.borrow().len()), 5_030);
+        assert_eq!(history_page_loads("conv_target"), 30);
+    }
+
+    #[test]
+    fn history_pages_by_cursor() {
+        for id in 1..=10 {
+            store_message(&test_message(id, "conv_paged"));
+        }
+
+        let ids = |page: Vec<Message>| page.into_iter().map(|message| message.id).collect::<Vec<_>>();
+        assert_eq!(ids(conversation_page("conv_paged", None, None, 0, 3, |_| true)), vec![10, 9, 8]);
+        assert_eq!(ids(conversation_page("conv_paged", Some(8), None, 0, 3, |_| true)), vec![7, 6, 5]);
+        assert_eq!(ids(conversation_page("conv_paged", None, Some(8), 0, 3, |_| true)), vec![9, 10]);
+        assert!(conversation_page("conv_paged", Some(4), Some(3), 0, 3, |_| true).is_empty());
+    }
+
+    #[test]
+    fn purged_message_suppressions_are_cleared_for_everyone() {
+        let former = Principal::from_slice(&[1]);
+        let current = Principal::from_slice(&[2]);
+        suppress_message_for(&former, 42);
+        suppress_message_for(&current, 42);
+        suppress_message_for(&current, 43);
+
+        clear_message_suppressions(42);
+
+        assert!(!is_message_hidden_from(&Principal::anonymous(), 42, &former));
+        assert!(!is_message_hidden_from(&Principal::anonymous(), 42, &current));
+        assert!(is_message_hidden_from(&Principal::anonymous(), 43, &current));
+        assert_eq!(SUPPRESSED_BY_MESSAGE.with(|index| index.borrow().len()), 1);
+    }
+
+    #[test]
+    fn suppression_backfill_builds_reverse_index() {
+        let viewer = Principal::from_slice(&[3]);
+        SUPPRESSED_MESSAGES.with(|index| index.borrow_mut().insert(scoped_key(&viewer.to_text(), 7), ()));
+
+        assert_eq!(backfill_suppressed_by_message(None), None);
+        clear_message_suppressions(7);
+        assert!(!is_message_hidden_from(&Principal::anonymous(), 7, &viewer));
+    }
+
+    #[test]
+    fn full_length_encrypted_message_fits_storage_bound() {
+        let encrypted = EncryptedData {
+            encrypted_content: general_purpose::STANDARD.encode(vec![0xffu8; MAX_TEXT_LENGTH]),
+            nonce: general_purpose::STANDARD.encode([0u8; 12]),
+            key_id: format!("phi_key_{}", "0".repeat(16)),
+        };
+        let mut message = test_message(u64::MAX, &"c".repeat(64));
+        message.content = serde_json::to_string(&encrypted).unwrap();
+        message.reply_to = Some(u64::MAX);
+        message.edited_at = Some(u64::MAX);
+
+        let Bound::Bounded { max_size, .. } = StorableMessage::BOUND else {
+            panic!("messages must be bounded");
+        };
+        assert!(StorableMessage::from(message).to_bytes().len() <= max_size as usize);
+    }
+
+    fn unread_of(user_id: &Principal, conversation_id: &str) -> (u64, Option<u64>) {
+        UNREAD_COUNTS
+            .with(|counts| counts.borrow().get(&unread_key(user_id, conversation_id)))
+            .map_or((0, None), |unread| (unread.count, unread.first_unread_id))
+    }
+
+    // Two-person conversation "conv_unread" with messages 1..=5, alternating senders from `a`
+    fn unread_fixture() -> (Principal, Principal, Vec<Message>) {
+        let (a, b) = (Principal::from_slice(&[10]), Principal::from_slice(&[11]));
+        save_conversation(
+            &Conversation {
+                id: "conv_unread".to_string(),
+                participants: vec![a, b],
+                conversation_type: ConversationType::DirectMessage,
+                created_at: 0,
+                updated_at: 0,
+                last_message_id: Some(5),
+                is_archived: false,
+                metadata: ConversationMetadata {
+                    title: None,
+                    description: None,
+                    session_id: None,
+                    encryption_key_id: String::new(),
+                },
+            },
+            None,
+        );
+
+        let messages: Vec<Message> = (1..=5)
+            .map(|id| Message {
+                sender_id: if id % 2 == 1 { a } else { b },
+                ..test_message(id, "conv_unread")
+            })
+            .collect();
+        for message in &messages {
+            store_message(message);
+            count_unread_message(message, &[a, b]);
+        }
+        (a, b, messages)
+    }
+
+    #[test]
+    fn both_dm_participants_may_archive() {
+        let (a, b, _) = unread_fixture();
+        set_role_entry("conv_unread", &a, ParticipantRole::Owner, 0);
+        set_role_entry("conv_unread", &b, ParticipantRole::Member, 0);
+        let conversation = Conversation::from(CONVERSATIONS.with(|c| c.borrow().get(&"conv_unread".to_string())).unwrap());
+
+        for participant in [a, b] {
+            assert!(require_role(&conversation, &participant, settings_role(&conversation)).is_ok());
+        }
+        assert!(require_role(&conversation, &Principal::anonymous(), settings_role(&conversation)).is_err());
+    }
+
+    #[test]
+    fn removing_unread_messages_decrements_counts() {
+        let (a, b, messages) = unread_fixture();
+        assert_eq!(unread_of(&b, "conv_unread"), (3, Some(1)));
+        assert_eq!(unread_of(&a, "conv_unread"), (2, Some(2)));
+
+        // Removing the first unread message moves first_unread_id to the next one
+        purge_message(1);
+        assert_eq!(unread_of(&b, "conv_unread"), (2, Some(3)));
+
+        uncount_unread_message(&messages[4], &[a, b]);
+        assert_eq!(unread_of(&b, "conv_unread"), (1, Some(3)));
+        assert_eq!(unread_of(&a, "conv_unread"), (2, Some(2)));
+
+        refresh_unread_count("conv_unread", &a);
+        assert_eq!(unread_of(&a, "conv_unread"), (2, Some(2)));
+    }
+
+    #[test]
+    fn blocking_hides_and_unblocking_restores_unread_messages() {
+        let (a, b, _) = unread_fixture();
+        suppress_message_for(&b, 5);
+        refresh_unread_count("conv_unread", &b);
+        assert_eq!(unread_of(&b, "conv_unread"), (2, Some(1)));
+
+        BLOCKS.with(|blocks| blocks.borrow_mut().insert(block_key(&b, &a), 0));
+        adjust_unread_counts_for_block(&b, &a, true);
+        assert_eq!(unread_of(&b, "conv_unread"), (0, None));
+
+        BLOCKS.with(|blocks| blocks.borrow_mut().remove(&block_key(&b, &a)));
+        adjust_unread_counts_for_block(&b, &a, false);
+        assert_eq!(unread_of(&b, "conv_unread"), (2, Some(1)));
+    }
+
+    fn test_group(id: &str, participants: Vec<Principal>) -> Conversation {
+        let group = Conversation {
+            id: id.to_string(),
+            participants,
+            conversation_type: ConversationType::GroupChat,
+            created_at: 0,
+            updated_at: 0,
+            last_message_id: None,
+            is_archived: false,
+            metadata: ConversationMetadata {
+                title: Some("Group".to_string()),
+                description: None,
+                session_id: None,
+                encryption_key_id: String::new(),
+            },
+        };
+        save_conversation(&group, None);
+        group
+    }
+
+    #[test]
+    fn last_manager_cannot_leave_without_promoting_someone() {
+        let (owner, reader) = (Principal::from_slice(&[40]), Principal::from_slice(&[41]));
+        let group = test_group("conv_last_manager", vec![owner, reader]);
+        set_role_entry(&group.id, &owner, ParticipantRole::Owner, 0);
+        set_role_entry(&group.id, &reader, ParticipantRole::ReadOnly, 0);
+        let without = |leaver: Principal| Conversation {
+            participants: group.participants.iter().filter(|p| **p != leaver).copied().collect(),
+            ..group.clone()
+        };
+
+        assert!(require_manager_remains(&without(owner), &owner).is_err());
+        assert!(require_manager_remains(&without(reader), &reader).is_ok());
+
+        set_role_entry(&group.id, &reader, ParticipantRole::Admin, 0);
+        assert!(require_manager_remains(&without(owner), &owner).is_ok());
+
+        // The last person out is never stopped
+        let empty = Conversation { participants: Vec::new(), ..group.clone() };
+        assert!(require_manager_remains(&empty, &owner).is_ok());
+    }
+
+    #[test]
+    fn group_created_by_blocked_user_is_hidden_from_blocker() {
+        let (creator, blocker, other) =
+            (Principal::from_slice(&[30]), Principal::from_slice(&[31]), Principal::from_slice(&[32]));
+        BLOCKS.with(|blocks| blocks.borrow_mut().insert(block_key(&blocker, &creator), 0));
+        let group = test_group("conv_group_blocked", vec![creator, blocker, other]);
+        hide_from_blockers(&group, &creator);
+
+        // The blocker stays a member, so the creator's view gives nothing away
+        assert!(group.participants.contains(&blocker));
+        assert!(is_conversation_hidden_from(&group, &blocker));
+        assert!(!is_conversation_hidden_from(&group, &creator));
+        assert!(!is_conversation_hidden_from(&group, &other));
+    }
+
+    #[test]
+    fn blocked_viewer_sees_presence_frozen_at_block_time() {
+        let (user, viewer) = (Principal::from_slice(&[20]), Principal::from_slice(&[21]));
+        save_presence_record(Presence {
+            user_id: user,
+            last_heartbeat: 900,
+            last_active: 900,
+            manual_status: None,
+            manual_status_expires_at: None,
+        });
+        BLOCKS.with(|blocks| blocks.borrow_mut().insert(block_key(&user, &viewer), 500));
+
+        let seen = presence_for_viewer(user, &viewer, 1_000);
+        assert_eq!(seen.state, PresenceState::Offline);
+        assert_eq!(seen.last_seen, Some(500));
+
+        // Same answer whoever blocked, and the earliest block wins
+        BLOCKS.with(|blocks| blocks.borrow_mut().insert(block_key(&viewer, &user), 300));
+        assert_eq!(presence_for_viewer(user, &viewer, 1_000).last_seen, Some(300));
+    }
+
+    fn add_crisis_term(language: &str, phrase: &str, severity: CrisisSeverity) {
+        let phrase = normalize_crisis_text(phrase);
+        CRISIS_LEXICON.with(|lexicon| {
+            lexicon.borrow_mut().insert(
+                crisis_term_key(language, &phrase),
+                StorableCrisisTerm {
+                    language: language.to_string(),
+                    phrase,
+                    severity,
+                    added_at: 0,
+                },
+            )
+        });
+    }
+
+    #[test]
+    fn crisis_text_is_folded_to_single_spaced_words() {
+        assert_eq!(normalize_crisis_text("  I want\tto   DIE!!! "), "i want to die");
+        assert_eq!(normalize_crisis_text("Suicídio"), "suicídio");
+        // Decomposed input is composed first, so it matches precomposed phrases
+        assert_eq!(normalize_crisis_text("Suici\u{301}dio"), normalize_crisis_text("Suicídio"));
+        // Thai tone marks survive instead of splitting the word
+        assert_eq!(normalize_crisis_text("อยากตาย"), "อยากตาย");
+    }
+
+    #[test]
+    fn crisis_lexicon_matches_whole_words() {
+        add_crisis_term("en", "want to die", CrisisSeverity::Urgent);
+        add_crisis_term("en", "suicide", CrisisSeverity::Concern);
+
+        let (severity, matched) = match_crisis_lexicon("Honestly... I WANT to die, suicide seems easy").unwrap();
+        assert_eq!(severity, CrisisSeverity::Urgent);
+        assert_eq!(matched.len(), 2);
+
+        assert!(match_crisis_lexicon("antisuicide campaign").is_none());
+        assert!(match_crisis_lexicon("I want to diet").is_none());
+    }
+
+    #[test]
+    fn crisis_lexicon_matches_unspaced_scripts_as_substrings() {
+        add_crisis_term("zh", "不想活了", CrisisSeverity::Urgent);
+        add_crisis_term("ja", "死にたい", CrisisSeverity::Urgent);
+        add_crisis_term("th", "อยากตาย", CrisisSeverity::Urgent);
+
+        assert!(match_crisis_lexicon("我真的不想活了。").is_some());
+        assert!(match_crisis_lexicon("もう死にたいです").is_some());
+        assert!(match_crisis_lexicon("ฉันอยากตายแล้ว").is_some());
+        assert!(match_crisis_lexicon("今天天气很好").is_none());
+    }
+
+    #[test]
+    fn sanitization_drops_invisible_controls_and_normalizes() {
+        let cleaned = sanitize_message_content("  he\u{202E}llo\r\nwor\u{7}ld\u{2067}  ", &MessageType::Text).unwrap();
+        assert_eq!(cleaned, "hello\nworld");
+
+        // NFC composes, and directional marks that only affect neutral characters are kept
+        assert_eq!(sanitize_message_content("e\u{301}\u{200F}", &MessageType::Text).unwrap(), "\u{e9}\u{200F}");
+    }
+
+    #[test]
+    fn sanitization_rejects_what_it_cannot_clean() {
+        assert!(sanitize_message_content("nul\0byte", &MessageType::Text).is_err());
+        assert!(sanitize_message_content("not a char \u{FFFF}", &MessageType::Text).is_err());
+        assert!(sanitize_message_content("\u{FDD0}", &MessageType::Text).is_err());
+        assert!(sanitize_message_content(" \u{202A}\u{7} ", &MessageType::Text).is_err());
+
+        let caption = "a".repeat(MAX_CAPTION_LENGTH as usize + 1);
+        assert!(sanitize_message_content(&caption, &MessageType::Image).is_err());
+        assert!(sanitize_message_content(&caption, &MessageType::Text).is_ok());
+    }
+
+    #[test]
+    fn sanitization_follows_configured_newline_policy() {
+        SANITIZATION_POLICIES.with(|policies| {
+            policies.borrow_mut().insert(
+                message_type_name(&MessageType::Image),
+                StorableSanitizationPolicy {
+                    max_length: 100,
+                    allow_newlines: false,
+                },
+            )
+        });
+
+        assert!(sanitize_message_content("line\nbreak", &MessageType::Image).is_err());
+        assert!(sanitize_message_content("line\r\nbreak", &MessageType::Image).is_err());
+        assert!(sanitize_message_content("line\nbreak", &MessageType::Text).is_ok());
+
+        // Rich text leaves never take raw line breaks, whatever the policy: they are
+        // rejected instead of being silently joined
+        let leaf = |text: &str| format!(r#"{{"blocks":[{{"type":"paragraph","children":[{{"type":"text","text":"{}"}}]}}]}}"#, text);
+        let error = sanitize_message_content(&leaf("a\\nb"), &MessageType::RichText).unwrap_err();
+        assert!(error.contains("line_break"));
+        assert!(sanitize_message_content(&leaf("a\\rb"), &MessageType::RichText).is_err());
+        assert!(sanitize_message_content(
+            r#"{"blocks":[{"type":"paragraph","children":[{"type":"code","text":"a\nb"}]}]}"#,
+            &MessageType::RichText
+        )
+        .is_err());
+        assert!(sanitize_message_content(
+            r#"{"blocks":[{"type":"paragraph","children":[{"type":"text","text":"a"},{"type":"line_break"},{"type":"text","text":"b"}]}]}"#,
+            &MessageType::RichText
+        )
+        .is_ok());
+        assert!(sanitize_message_content(r#"{"blocks":[{"type":"code_block","text":"a\nb"}]}"#, &MessageType::RichText).is_ok());
+    }
+
+    #[test]
+    fn rich_text_is_stored_in_canonical_form() {
+        let document = r#"{"blocks":[{"type":"paragraph","children":[
+            {"type":"bold","children":[{"type":"text","text":"hi\u202e"}]},
+            {"type":"link","href":"https://example.org","children":[{"type":"text","text":" there"}]}
+        ]}]}"#;
+
+        assert_eq!(
+            normalize_rich_text(document).unwrap(),
+            r#"{"blocks":[{"type":"paragraph","children":[{"type":"bold","children":[{"type":"text","text":"hi"}]},{"type":"link","href":"https://example.org","children":[{"type":"text","text":" there"}]}]}]}"#
+        );
+    }
+
+    #[test]
+    fn rich_text_rejects_anything_outside_the_allow_list() {
+        let paragraph = |inline: &str| format!(r#"{{"blocks":[{{"type":"paragraph","children":[{}]}}]}}"#, inline);
+
+        // Unknown node types, unknown fields and raw HTML-ish nodes don't parse
+        assert!(normalize_rich_text(&paragraph(r#"{"type":"html","html":"<script>"}"#)).is_err());
+        assert!(normalize_rich_text(&paragraph(r#"{"type":"text","text":"x","style":"color:red"}"#)).is_err());
+        assert!(normalize_rich_text(r#"{"blocks":[],"extra":1}"#).is_err());
+
+        // Links: only https and mailto, no nesting, no whitespace
+        assert!(normalize_rich_text(&paragraph(r#"{"type":"link","href":"javascript:alert(1)","children":[{"type":"text","text":"x"}]}"#)).is_err());
+        assert!(normalize_rich_text(&paragraph(r#"{"type":"link","href":"https://a.b/ c","children":[{"type":"text","text":"x"}]}"#)).is_err());
+        assert!(normalize_rich_text(&paragraph(
+            r#"{"type":"link","href":"https://a.b","children":[{"type":"link","href":"https://c.d","children":[{"type":"text","text":"x"}]}]}"#
+        ))
+        .is_err());
+        assert!(normalize_rich_text(&paragraph(r#"{"type":"link","href":"mailto:care@example.org","children":[{"type":"text","text":"x"}]}"#)).is_ok());
+
+        // Placement and limits
+        assert!(normalize_rich_text(r#"{"blocks":[{"type":"text","text":"inline at the root"}]}"#).is_err());
+        assert!(normalize_rich_text(r#"{"blocks":[{"type":"heading","level":4,"children":[{"type":"text","text":"x"}]}]}"#).is_err());
+        assert!(normalize_rich_text(&paragraph(r#"{"type":"text","text":"   "}"#)).is_err());
+
+        let mut nested = r#"{"type":"text","text":"deep"}"#.to_string();
+        for _ in 0..MAX_RICH_TEXT_DEPTH {
+            nested = format!(r#"{{"type":"bold","children":[{}]}}"#, nested);
+        }
+        assert!(normalize_rich_text(&paragraph(&nested)).is_err());
+
+        let many = vec![r#"{"type":"text","text":"x"}"#; MAX_RICH_TEXT_NODES].join(",");
+        assert!(normalize_rich_text(&paragraph(&many)).is_err());
+    }
+
+    #[test]
+    fn retention_delays_use_the_largest_whole_unit() {
+        let second = 1_000_000_000;
+        assert_eq!(describe_retention_delay(0), "0 seconds");
+        assert_eq!(describe_retention_delay(second), "1 second");
+        assert_eq!(describe_retention_delay(90 * second), "90 seconds");
+        assert_eq!(describe_retention_delay(120 * second), "2 minutes");
+        assert_eq!(describe_retention_delay(3600 * second), "1 hour");
+        assert_eq!(describe_retention_delay(30 * NANOS_PER_DAY), "30 days");
+        assert_eq!(describe_retention_delay(NANOS_PER_DAY + 3600 * second), "25 hours");
+    }
+
+    #[test]
+    fn invites_stop_working_when_revoked_expired_or_used_up() {
+        let invite = ConversationInvite {
+            token: "t".to_string(),
+            conversation_id: "conv".to_string(),
+            created_by: Principal::anonymous(),
+            role: ParticipantRole::Member,
+            created_at: 0,
+            expires_at: Some(100),
+            max_uses: Some(2),
+            uses: 1,
+            requires_approval: false,
+            revoked: false,
+        };
+
+        assert!(validate_invite_usable(&invite, 99).is_ok());
+        assert!(validate_invite_usable(&invite, 100).is_err());
+        assert!(validate_invite_usable(&ConversationInvite { uses: 2, ..invite.clone() }, 0).is_err());
+        assert!(validate_invite_usable(&ConversationInvite { revoked: true, ..invite.clone() }, 0).is_err());
+        assert!(validate_invite_usable(&ConversationInvite { expires_at: None, max_uses: None, uses: 99, ..invite }, u64::MAX).is_ok());
+    }
+
+    #[test]
+    fn message_index_backfill_resumes_from_cursor() {
+        for id in 1..=(BACKFILL_BATCH_SIZE as u64 + 5) {
+            MESSAGES.with(|messages| {
+                messages.borrow_mut().insert(id, StorableMessage::from(test_message(id, "conv_legacy")));
+            });
+        }
+
+        let cursor = backfill_conversation_message_index(None);
+        assert_eq!(cursor, Some(BACKFILL_BATCH_SIZE.to_string()));
+        assert_eq!(CONVERSATION_MESSAGES.with(|index| index.borrow().len()), BACKFILL_BATCH_SIZE as u64);
+
+        assert_eq!(backfill_conversation_message_index(cursor), None);
+        assert_eq!(CONVERSATION_MESSAGES.with(|index| index.borrow().len()), BACKFILL_BATCH_SIZE as u64 + 5);
+    }
+}